@@ -0,0 +1,111 @@
+use serde::Deserialize;
+
+use crate::Vec2d;
+
+/// The description of an ArcGIS MapServer or ImageServer, as returned by `?f=json`
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MapService {
+    pub map_name: Option<String>,
+    pub name: Option<String>,
+    pub document_info: Option<DocumentInfo>,
+    pub tile_info: Option<TileInfo>,
+    #[serde(alias = "extent")]
+    pub full_extent: Option<Extent>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct DocumentInfo {
+    #[serde(rename = "Title")]
+    pub title: Option<String>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct TileInfo {
+    pub rows: u32,
+    pub cols: u32,
+    pub origin: Point,
+    pub lods: Vec<Lod>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Clone, Copy)]
+pub struct Point {
+    pub x: f64,
+    pub y: f64,
+}
+
+/// A level of detail of the tile cache
+#[derive(Debug, Deserialize, PartialEq, Clone, Copy)]
+pub struct Lod {
+    pub level: u32,
+    /// Size of a pixel, in the units of the spatial reference
+    pub resolution: f64,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Clone, Copy)]
+pub struct Extent {
+    pub xmin: f64,
+    pub ymin: f64,
+    pub xmax: f64,
+    pub ymax: f64,
+}
+
+impl MapService {
+    pub fn title(&self) -> Option<String> {
+        self.document_info.as_ref().and_then(|d| d.title.clone())
+            .filter(|t| !t.is_empty())
+            .or_else(|| self.map_name.clone())
+            .or_else(|| self.name.clone())
+    }
+}
+
+impl TileInfo {
+    pub fn tile_size(&self) -> Vec2d {
+        Vec2d { x: self.cols, y: self.rows }
+    }
+
+    /// The first tile (column and row) and the number of tiles that cover the extent at a level of detail
+    pub fn tiles(&self, lod: &Lod, extent: Option<&Extent>) -> (Vec2d, Vec2d) {
+        let tile_width = lod.resolution * f64::from(self.cols);
+        let tile_height = lod.resolution * f64::from(self.rows);
+        let extent = match extent {
+            Some(e) if e.xmin < e.xmax && e.ymin < e.ymax => *e,
+            // Without an extent, the whole tiling scheme is downloaded, starting from its origin
+            _ => Extent {
+                xmin: self.origin.x,
+                ymax: self.origin.y,
+                xmax: self.origin.x - 2. * self.origin.x.min(0.),
+                ymin: self.origin.y - 2. * self.origin.y.max(0.),
+            },
+        };
+        // The tiles in which the corners of the extent are, avoiding an empty tile at the edge
+        let first_col = ((extent.xmin - self.origin.x) / tile_width).floor().max(0.);
+        let first_row = ((self.origin.y - extent.ymax) / tile_height).floor().max(0.);
+        let end_col = ((extent.xmax - self.origin.x) / tile_width).ceil().max(first_col + 1.);
+        let end_row = ((self.origin.y - extent.ymin) / tile_height).ceil().max(first_row + 1.);
+        let first = Vec2d { x: first_col as u32, y: first_row as u32 };
+        let end = Vec2d { x: end_col as u32, y: end_row as u32 };
+        (first, end - first)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tiles() {
+        let info = TileInfo {
+            rows: 256,
+            cols: 256,
+            origin: Point { x: -1000., y: 1000. },
+            lods: vec![],
+        };
+        let lod = Lod { level: 3, resolution: 1. };
+        let extent = Extent { xmin: -500., ymin: 0., xmax: 100., ymax: 500. };
+        // Columns from 500/256 to 1100/256, rows from 500/256 to 1000/256
+        assert_eq!(info.tiles(&lod, Some(&extent)), (Vec2d { x: 1, y: 1 }, Vec2d { x: 4, y: 3 }));
+        assert_eq!(info.tiles(&Lod { level: 0, resolution: 1000. }, Some(&extent)), (Vec2d { x: 0, y: 0 }, Vec2d { x: 1, y: 1 }));
+        assert_eq!(info.tiles(&Lod { level: 0, resolution: 1. }, None), (Vec2d { x: 0, y: 0 }, Vec2d { x: 8, y: 8 }));
+    }
+}
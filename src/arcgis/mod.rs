@@ -0,0 +1,159 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use custom_error::custom_error;
+use serde::export::Formatter;
+
+use map_service::{Lod, MapService};
+
+use crate::dezoomer::*;
+
+pub mod map_service;
+
+/// A dezoomer for the tile caches of ArcGIS MapServer and ImageServer services.
+/// Takes the URL of the service, such as `https://example.com/arcgis/rest/services/Maps/Old_map/MapServer`
+#[derive(Default)]
+pub struct ArcGisDezoomer;
+
+const SERVICE_TYPES: [&str; 2] = ["/MapServer", "/ImageServer"];
+
+impl Dezoomer for ArcGisDezoomer {
+    fn name(&self) -> &'static str {
+        "arcgis"
+    }
+
+    fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
+        let service = service_url(&data.uri).ok_or_else(|| self.wrong_dezoomer())?;
+        let (path, query) = split_query(&data.uri);
+        let mut params: Vec<&str> = query.split('&').filter(|p| !p.is_empty()).collect();
+        let is_json = params.iter().any(|&p| p == "f=json" || p == "f=pjson");
+        // Other parameters, such as access tokens, are kept in the tile urls
+        params.retain(|p| !p.starts_with("f="));
+        let query = params.join("&");
+        if !is_json || path != service {
+            let mut uri = format!("{}?f=json", service);
+            if !query.is_empty() { uri = uri + "&" + &query }
+            return Err(DezoomerError::NeedsData { uri });
+        }
+        let DezoomerInputWithContents { contents, .. } = data.with_contents()?;
+        let levels = load_from_json(service, &query, contents)?;
+        Ok(levels)
+    }
+}
+
+/// The address of the service, without the query string and the path of its sub-resources
+fn service_url(uri: &str) -> Option<&str> {
+    let (path, _) = split_query(uri);
+    SERVICE_TYPES.iter()
+        .filter_map(|t| path.find(t).map(|i| i + t.len()))
+        .filter(|&end| path[end..].is_empty() || path[end..].starts_with('/'))
+        .min()
+        .map(|end| &path[..end])
+}
+
+fn split_query(uri: &str) -> (&str, &str) {
+    let mut parts = uri.splitn(2, '?');
+    (parts.next().unwrap_or_default(), parts.next().unwrap_or_default())
+}
+
+custom_error! {pub ArcGisError
+    JsonError{source: serde_json::Error} = "Invalid ArcGIS service description: {source}",
+    NotCached = "The ArcGIS service does not have a tile cache",
+}
+
+impl From<ArcGisError> for DezoomerError {
+    fn from(err: ArcGisError) -> Self {
+        DezoomerError::Other { source: err.into() }
+    }
+}
+
+fn load_from_json(service: &str, query: &str, contents: &[u8]) -> Result<ZoomLevels, ArcGisError> {
+    let map_service: MapService = serde_json::from_slice(contents)?;
+    let title = map_service.title();
+    let MapService { tile_info, full_extent, .. } = map_service;
+    let tile_info = tile_info.ok_or(ArcGisError::NotCached)?;
+    if tile_info.lods.is_empty() { return Err(ArcGisError::NotCached); }
+    let tile_size = tile_info.tile_size();
+    let service = Arc::new(ServiceInfo {
+        service: service.to_string(),
+        query: if query.is_empty() { String::new() } else { format!("?{}", query) },
+        title,
+    });
+    let levels = tile_info.lods.iter().map(|lod| {
+        let (first_tile, tiles) = tile_info.tiles(lod, full_extent.as_ref());
+        ArcGisLevel { service: Arc::clone(&service), lod: *lod, tile_size, first_tile, tiles }
+    });
+    Ok(levels.into_zoom_levels())
+}
+
+struct ServiceInfo {
+    service: String,
+    query: String,
+    title: Option<String>,
+}
+
+struct ArcGisLevel {
+    service: Arc<ServiceInfo>,
+    lod: Lod,
+    tile_size: Vec2d,
+    first_tile: Vec2d,
+    tiles: Vec2d,
+}
+
+impl TilesRect for ArcGisLevel {
+    fn size(&self) -> Vec2d {
+        self.tiles * self.tile_size
+    }
+
+    fn tile_size(&self) -> Vec2d {
+        self.tile_size
+    }
+
+    fn tile_url(&self, pos: Vec2d) -> String {
+        let Vec2d { x: col, y: row } = self.first_tile + pos;
+        format!("{}/tile/{}/{}/{}{}", self.service.service, self.lod.level, row, col, self.service.query)
+    }
+
+    fn title(&self) -> Option<String> {
+        self.service.title.clone()
+    }
+}
+
+impl Debug for ArcGisLevel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ArcGIS level {}", self.lod.level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVICE: &str = "https://example.com/arcgis/rest/services/Maps/Old_map/MapServer";
+
+    #[test]
+    fn test_needs_json() {
+        for uri in &[SERVICE.to_string(), format!("{}/tile/3/1/2?token=abc", SERVICE), format!("{}?f=html", SERVICE)] {
+            let input = DezoomerInput { uri: uri.clone(), contents: None };
+            match ArcGisDezoomer.zoom_levels(&input) {
+                Err(DezoomerError::NeedsData { uri: json }) => assert!(json.starts_with(&format!("{}?f=json", SERVICE))),
+                _ => panic!("The service description should be requested for {}", uri),
+            }
+        }
+        let input = DezoomerInput { uri: "https://example.com/MapServerList".into(), contents: None };
+        assert!(ArcGisDezoomer.zoom_levels(&input).is_err());
+    }
+
+    #[test]
+    fn test_levels() {
+        let contents = include_bytes!("../../testdata/arcgis/MapServer.json");
+        let uri = format!("{}?f=json&token=abc", SERVICE);
+        let input = DezoomerInput { uri, contents: Some(contents.to_vec()) };
+        let mut levels = ArcGisDezoomer.zoom_levels(&input).unwrap();
+        assert_eq!(levels.len(), 3);
+        assert_eq!(levels[0].title().as_deref(), Some("Plan of the city, 1750"));
+        assert_eq!(levels[2].size_hint(), Some(Vec2d::square(2 * 512)));
+        let tile = levels[2].next_tiles(None).into_iter().next().unwrap();
+        assert_eq!(tile.url, format!("{}/tile/14/2817/4149?token=abc", SERVICE));
+    }
+}
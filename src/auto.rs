@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use crate::dezoomer::{Dezoomer, DezoomerError, DezoomerInput, ZoomLevels};
 use log::{info, debug};
 
@@ -12,6 +14,11 @@ pub fn all_dezoomers(include_generic: bool) -> Vec<Box<dyn Dezoomer>> {
         Box::new(crate::pff::PFF::default()),
         Box::new(crate::krpano::KrpanoDezoomer::default()),
         Box::new(crate::iipimage::IIPImage::default()),
+        Box::new(crate::har::HarDezoomer::default()),
+        Box::new(crate::xyz::XyzDezoomer),
+        Box::new(crate::wmts::WmtsDezoomer),
+        Box::new(crate::arcgis::ArcGisDezoomer),
+        Box::new(crate::page::PageDezoomer::default()),
     ];
     if include_generic {
         dezoomers.push(Box::new(AutoDezoomer::default()))
@@ -31,6 +38,16 @@ impl Default for AutoDezoomer {
     }
 }
 
+impl AutoDezoomer {
+    /// The dezoomers that read the image found in a web page, `depth` pages deep
+    pub fn nested(depth: usize) -> Self {
+        let mut dezoomers = all_dezoomers(false);
+        dezoomers.retain(|d| d.name() != "page");
+        dezoomers.push(Box::new(crate::page::PageDezoomer::nested(depth)));
+        AutoDezoomer { dezoomers }
+    }
+}
+
 impl Dezoomer for AutoDezoomer {
     fn name(&self) -> &'static str {
         "auto"
@@ -40,6 +57,7 @@ impl Dezoomer for AutoDezoomer {
         let mut errs = vec![];
         let mut successes = Vec::new();
         let mut needs_uri = None;
+        let mut multiple_images = None;
         // TO DO: Use drain_filter when it is stabilized
         let mut i = 0;
         while i != self.dezoomers.len() {
@@ -49,11 +67,20 @@ impl Dezoomer for AutoDezoomer {
                     successes.append(&mut levels);
                     true
                 }
-                Err(e @ DezoomerError::NeedsData { .. }) => {
+                Err(e @ DezoomerError::NeedsData { .. }) | Err(e @ DezoomerError::NeedsTile { .. }) => {
                     debug!("{} requested more data: {}", dezoomer.name(), e);
-                    needs_uri = Some(e);
+                    // Requests for another file are more specific than requests for the input itself
+                    let requests_input = matches!(&e, DezoomerError::NeedsData { uri } if uri == &data.uri);
+                    if needs_uri.is_none() || !requests_input {
+                        needs_uri = Some(e);
+                    }
                     true
                 }
+                Err(e @ DezoomerError::MultipleImages { .. }) => {
+                    debug!("{} found several images", dezoomer.name());
+                    multiple_images = Some(e);
+                    false
+                }
                 Err(e) => {
                     debug!("{} cannot process this image: {}", dezoomer.name(), e);
                     errs.push((dezoomer.name(), e));
@@ -68,11 +95,17 @@ impl Dezoomer for AutoDezoomer {
         }
         if successes.is_empty() {
             info!("No dezoomer can dezoom {:?}", data.uri);
-            Err(needs_uri.unwrap_or_else(|| DezoomerError::wrap(AutoDezoomerError(errs))))
+            Err(multiple_images
+                .or(needs_uri)
+                .unwrap_or_else(|| DezoomerError::wrap(AutoDezoomerError(errs))))
         } else {
             Ok(successes)
         }
     }
+
+    fn http_headers(&self) -> HashMap<String, String> {
+        self.dezoomers.iter().flat_map(|d| d.http_headers()).collect()
+    }
 }
 
 #[derive(Debug)]
@@ -95,3 +128,13 @@ impl std::fmt::Display for AutoDezoomerError {
         Ok(())
     }
 }
+
+#[test]
+fn test_specific_request_first() {
+    let mut dezoomer = AutoDezoomer::default();
+    let uri = "https://example.com/arcgis/rest/services/Old_map/MapServer";
+    match dezoomer.zoom_levels(&DezoomerInput { uri: uri.into(), contents: None }) {
+        Err(DezoomerError::NeedsData { uri }) => assert!(uri.ends_with("MapServer?f=json"), "{}", uri),
+        _ => panic!("The service description should be requested"),
+    }
+}
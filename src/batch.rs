@@ -0,0 +1,248 @@
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use futures::stream::StreamExt;
+use log::info;
+
+use crate::{Arguments, dezoomify_all, ZoomError};
+use crate::progress::{InputError, ProgressEvent};
+
+/// A line of a batch file: `url[,output_name[,level]]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchEntry {
+    pub uri: String,
+    pub outfile: Option<PathBuf>,
+    pub level: Option<LevelSelector>,
+}
+
+/// Which zoom level to download for an entry of a batch file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LevelSelector {
+    /// `largest`
+    Largest,
+    /// `WIDTHxHEIGHT`, `WIDTHx` or `xHEIGHT`: the largest level that fits in the given size
+    MaxSize { width: Option<u32>, height: Option<u32> },
+}
+
+impl FromStr for LevelSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "largest" { return Ok(LevelSelector::Largest); }
+        let err = || format!("Invalid level '{}'. Expected 'largest' or a maximal size such as '2000x1000'", s);
+        let mut parts = s.splitn(2, 'x');
+        let mut parse_dim = || match parts.next().map(str::trim) {
+            Some("") => Ok(None),
+            Some(n) => n.parse().map(Some).map_err(|_| err()),
+            None => Err(err()),
+        };
+        let width = parse_dim()?;
+        let height = parse_dim()?;
+        if width.is_none() && height.is_none() { return Err(err()); }
+        Ok(LevelSelector::MaxSize { width, height })
+    }
+}
+
+/// Splits a line of a csv file into fields. Fields that contain commas can be quoted.
+fn csv_fields(line: &str) -> Result<Vec<String>, &'static str> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut chars = line.chars().peekable();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => { chars.next(); field.push('"') }
+            '"' if quoted => quoted = false,
+            '"' if field.trim().is_empty() => { field.clear(); quoted = true }
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    if quoted { return Err("unterminated quoted field"); }
+    fields.push(field);
+    Ok(fields.into_iter().map(|f| f.trim().to_string()).collect())
+}
+
+impl FromStr for BatchEntry {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let fields = csv_fields(line)?;
+        let mut fields = fields.into_iter().map(|f| Some(f).filter(|f| !f.is_empty()));
+        let uri = fields.next().flatten().ok_or("missing url")?;
+        let outfile = fields.next().flatten().map(PathBuf::from);
+        let level = fields.next().flatten().map(|l| l.parse()).transpose()?;
+        if fields.next().is_some() {
+            return Err("too many fields. Expected 'url,output_name,level'".into());
+        }
+        Ok(BatchEntry { uri, outfile, level })
+    }
+}
+
+/// Reads a batch file. Empty lines and lines that start with '#' are ignored.
+pub fn parse_batch_file(contents: &str) -> Result<Vec<BatchEntry>, ZoomError> {
+    contents.lines().enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(|(i, line)| line.parse().map_err(|message| ZoomError::InvalidBatchLine { line: i + 1, message }))
+        .collect()
+}
+
+impl BatchEntry {
+    /// The arguments to use to download this entry.
+    /// A batch never stops to ask the user which level or image to download.
+    fn arguments(&self, base: &Arguments) -> Arguments {
+        let mut args = base.clone();
+        args.input_uri = Some(self.uri.clone());
+        args.outfile = self.outfile.clone();
+        args.batch = None;
+        match self.level {
+            Some(LevelSelector::Largest) => args.largest = true,
+            Some(LevelSelector::MaxSize { width, height }) => args.set_max_size(width, height),
+            None if !args.has_level_selection() => args.largest = true,
+            None => {}
+        }
+        if args.images.is_none() {
+            args.images = Some("all".parse().expect("'all' is a valid selection"));
+        }
+        args
+    }
+}
+
+/// When several images are downloaded at the same time, the entries that have no output name
+/// cannot choose one from the title of their image: two of them could pick the same file.
+/// They are given distinct numbered names before the downloads start,
+/// skipping the names of the files that already exist unless `reuse` is set.
+fn name_unnamed_entries(entries: &mut [BatchEntry], reuse: bool) {
+    let existing: HashSet<OsString> = std::fs::read_dir(".").into_iter().flatten()
+        .filter_map(|entry| Some(Path::new(&entry.ok()?.file_name()).file_stem()?.to_os_string()))
+        .collect();
+    let mut numbers = (1..).map(|i| format!("dezoomified_batch_{}", i))
+        .filter(|name| reuse || !existing.contains(OsStr::new(name)));
+    for entry in entries.iter_mut().filter(|e| e.outfile.is_none()) {
+        entry.outfile = numbers.next().map(PathBuf::from);
+    }
+}
+
+/// The outcome of a batch
+#[derive(Debug, Default)]
+pub struct BatchReport {
+    pub successes: Vec<(String, Vec<PathBuf>)>,
+    pub partial_downloads: Vec<(String, ZoomError)>,
+    pub failures: Vec<(String, ZoomError)>,
+}
+
+impl BatchReport {
+    pub fn is_success(&self) -> bool {
+        self.partial_downloads.is_empty() && self.failures.is_empty()
+    }
+
+    fn add(&mut self, uri: String, result: Result<Vec<PathBuf>, ZoomError>) {
+        match result {
+            Ok(paths) => self.successes.push((uri, paths)),
+            Err(e @ ZoomError::PartialDownload { .. }) | Err(e @ ZoomError::PartialImageList { .. }) =>
+                self.partial_downloads.push((uri, e)),
+            Err(e) => self.failures.push((uri, e)),
+        }
+    }
+}
+
+impl fmt::Display for BatchReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Batch finished: {} successful, {} partial downloads, {} failures",
+                 self.successes.len(), self.partial_downloads.len(), self.failures.len())?;
+        for (uri, e) in &self.partial_downloads {
+            writeln!(f, "  partial download: {}: {}", uri, e)?;
+        }
+        for (uri, e) in &self.failures {
+            writeln!(f, "  failure: {}: {}", uri, e)?;
+        }
+        Ok(())
+    }
+}
+
+/// Downloads all the images listed in a batch file,
+/// with at most `args.parallel_images` images downloaded at the same time
+pub async fn dezoomify_batch(args: &Arguments, batch_file: &Path) -> Result<BatchReport, ZoomError> {
+    let contents = std::fs::read_to_string(batch_file)?;
+    let mut entries = parse_batch_file(&contents)?;
+    if args.parallel_images > 1 {
+        name_unnamed_entries(&mut entries, args.resume);
+    }
+    info!("Downloading {} images from {:?}", entries.len(), batch_file);
+    let entry_args: Vec<Arguments> = entries.iter().map(|e| e.arguments(args)).collect();
+    let mut results = futures::stream::iter(entry_args.iter().zip(entries))
+        .map(|(args, entry)| async move {
+            let result = dezoomify_all(args).await;
+            if let Err(e) = &result { log::error!("Unable to download {}: {}", entry.uri, e); }
+            (entry.uri, result)
+        })
+        .buffer_unordered(args.parallel_images.max(1));
+    let mut report = BatchReport::default();
+    while let Some((uri, result)) = results.next().await {
+        report.add(uri, result);
+    }
+    args.progress.emit(&ProgressEvent::BatchSummary {
+        successes: report.successes.len(),
+        partial_downloads: report.partial_downloads.iter().map(|(uri, e)| InputError::new(uri, e)).collect(),
+        failures: report.failures.iter().map(|(uri, e)| InputError::new(uri, e)).collect(),
+    });
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_batch_file() {
+        let contents = "# images to download\n\
+            http://example.com/a.dzi\n\
+            \n\
+            http://example.com/b/info.json, b.png ,largest\n\
+            \"http://example.com/c/0,0,10,10/full/0/default.jpg\",,2000x\n";
+        let entries = parse_batch_file(contents).unwrap();
+        assert_eq!(entries, vec![
+            BatchEntry { uri: "http://example.com/a.dzi".into(), outfile: None, level: None },
+            BatchEntry {
+                uri: "http://example.com/b/info.json".into(),
+                outfile: Some("b.png".into()),
+                level: Some(LevelSelector::Largest),
+            },
+            BatchEntry {
+                uri: "http://example.com/c/0,0,10,10/full/0/default.jpg".into(),
+                outfile: None,
+                level: Some(LevelSelector::MaxSize { width: Some(2000), height: None }),
+            },
+        ]);
+    }
+
+    #[test]
+    fn test_invalid_batch_line() {
+        let err = parse_batch_file("http://a\nhttp://b,b.png,huge\n").unwrap_err();
+        assert_eq!(err.kind(), "InvalidBatchLine");
+        assert!(err.to_string().contains("line 2"), "{}", err);
+    }
+
+    #[test]
+    fn test_name_unnamed_entries() {
+        let mut entries = parse_batch_file("http://a\nhttp://b,b.png\nhttp://c\n").unwrap();
+        name_unnamed_entries(&mut entries, true);
+        let names: Vec<_> = entries.iter().map(|e| e.outfile.clone().unwrap()).collect();
+        assert_eq!(names, vec![
+            PathBuf::from("dezoomified_batch_1"),
+            PathBuf::from("b.png"),
+            PathBuf::from("dezoomified_batch_2"),
+        ]);
+    }
+
+    #[test]
+    fn test_parse_level_selector() {
+        assert_eq!("x500".parse(), Ok(LevelSelector::MaxSize { width: None, height: Some(500) }));
+        assert_eq!("10x20".parse(), Ok(LevelSelector::MaxSize { width: Some(10), height: Some(20) }));
+        assert!("x".parse::<LevelSelector>().is_err());
+        assert!("big".parse::<LevelSelector>().is_err());
+    }
+}
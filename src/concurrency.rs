@@ -0,0 +1,152 @@
+use std::time::Duration;
+
+use log::debug;
+use reqwest::StatusCode;
+
+use crate::rate_limit::Outcome;
+use crate::ZoomError;
+
+/// Decides how many tiles are downloaded at the same time.
+/// The number of parallel downloads grows slowly while the latency of the server stays low and requests succeed,
+/// and is halved when the server times out or returns errors (additive increase, multiplicative decrease).
+#[derive(Debug)]
+pub struct ConcurrencyController {
+    limit: f64,
+    min: usize,
+    max: usize,
+    /// Moving average of the latency of the last requests, in seconds
+    latency: Option<f64>,
+    /// Lowest recent average latency, that rises slowly when the server becomes slower
+    base_latency: Option<f64>,
+    /// Number of requests that finished since the limit was last decreased
+    since_decrease: usize,
+}
+
+impl ConcurrencyController {
+    pub fn new(initial: usize, max: usize) -> Self {
+        let max = max.max(1);
+        let initial = initial.max(1).min(max);
+        ConcurrencyController {
+            limit: initial as f64,
+            min: 1,
+            max,
+            latency: None,
+            base_latency: None,
+            since_decrease: initial,
+        }
+    }
+
+    /// A controller that never changes the number of parallel downloads
+    pub fn fixed(parallelism: usize) -> Self {
+        let mut controller = Self::new(parallelism, parallelism);
+        controller.min = controller.max;
+        controller
+    }
+
+    /// Current maximum number of parallel downloads
+    pub fn limit(&self) -> usize {
+        self.limit as usize
+    }
+
+    /// Adapts the limit to the outcome of a request.
+    /// Returns the new limit if it changed.
+    pub fn record(&mut self, outcome: Outcome, latency: Duration) -> Option<usize> {
+        let before = self.limit();
+        self.since_decrease += 1;
+        match outcome {
+            Outcome::Success => {
+                let latency = latency.as_secs_f64();
+                let average = self.latency.map_or(latency, |avg| 0.7 * avg + 0.3 * latency);
+                self.latency = Some(average);
+                let base = self.base_latency.map_or(average, |base| average.min(base * 1.01));
+                self.base_latency = Some(base);
+                if average <= 2. * base {
+                    self.limit = (self.limit + 1. / self.limit).min(self.max as f64);
+                }
+            }
+            Outcome::OtherError => {}
+            Outcome::Overloaded { .. } => {
+                // Requests that were sent before the last decrease do not trigger a new one
+                if self.since_decrease >= before {
+                    self.limit = (self.limit / 2.).max(self.min as f64);
+                    self.since_decrease = 0;
+                }
+            }
+        }
+        let after = self.limit();
+        if after != before {
+            debug!("Changing the number of parallel downloads from {} to {}", before, after);
+            Some(after)
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether a failed tile download shows that the server is struggling:
+/// only timeouts, 429 (Too Many Requests) and 503 (Service Unavailable) do.
+/// Missing tiles (404) and tiles that cannot be decoded do not slow the download down.
+pub fn failure_outcome(error: &ZoomError) -> Outcome {
+    match error {
+        ZoomError::Networking { source } if source.is_timeout() => Outcome::Overloaded { retry_after: None },
+        ZoomError::Networking { source } => match source.status() {
+            Some(StatusCode::TOO_MANY_REQUESTS) | Some(StatusCode::SERVICE_UNAVAILABLE) =>
+                Outcome::Overloaded { retry_after: None },
+            _ => Outcome::OtherError,
+        },
+        _ => Outcome::OtherError,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAST: Duration = Duration::from_millis(100);
+    const OVERLOADED: Outcome = Outcome::Overloaded { retry_after: None };
+
+    #[test]
+    fn test_additive_increase() {
+        let mut controller = ConcurrencyController::new(4, 6);
+        let changes: Vec<usize> = (0..100)
+            .filter_map(|_| controller.record(Outcome::Success, FAST))
+            .collect();
+        assert_eq!(changes, vec![5, 6]);
+        assert_eq!(controller.limit(), 6);
+    }
+
+    #[test]
+    fn test_no_increase_when_latency_rises() {
+        let mut controller = ConcurrencyController::new(4, 100);
+        for _ in 0..10 { controller.record(Outcome::Success, FAST); }
+        let limit = controller.limit();
+        for _ in 0..10 { controller.record(Outcome::Success, FAST * 10); }
+        assert_eq!(controller.limit(), limit);
+    }
+
+    #[test]
+    fn test_multiplicative_decrease() {
+        let mut controller = ConcurrencyController::new(16, 64);
+        assert_eq!(controller.record(OVERLOADED, FAST), Some(8));
+        // The other requests that were in flight fail too, but the limit is decreased only once
+        for _ in 0..7 { assert_eq!(controller.record(OVERLOADED, FAST), None); }
+        assert_eq!(controller.record(OVERLOADED, FAST), Some(4));
+        assert_eq!(controller.record(Outcome::OtherError, FAST), None);
+    }
+
+    #[test]
+    fn test_failure_outcome() {
+        let not_found = std::io::Error::new(std::io::ErrorKind::NotFound, "missing");
+        assert_eq!(failure_outcome(&not_found.into()), Outcome::OtherError);
+        let decode_error = ZoomError::PostProcessing { source: "invalid tile".into() };
+        assert_eq!(failure_outcome(&decode_error), Outcome::OtherError);
+    }
+
+    #[test]
+    fn test_fixed() {
+        let mut controller = ConcurrencyController::fixed(3);
+        assert_eq!(controller.record(OVERLOADED, FAST), None);
+        for _ in 0..10 { assert_eq!(controller.record(Outcome::Success, FAST), None); }
+        assert_eq!(controller.limit(), 3);
+    }
+}
@@ -0,0 +1,329 @@
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::debug;
+use reqwest::header::{self, HeaderMap};
+use url::Url;
+
+use crate::ZoomError;
+
+/// A cookie, as stored in a cookie jar
+#[derive(Debug, Clone, PartialEq)]
+struct Cookie {
+    name: String,
+    value: String,
+    /// Lowercase domain name, without a leading dot
+    domain: String,
+    /// Whether the cookie is also sent to the subdomains of `domain`
+    include_subdomains: bool,
+    path: String,
+    secure: bool,
+    /// None for session cookies
+    expires: Option<SystemTime>,
+}
+
+impl Cookie {
+    fn matches(&self, url: &Url, now: SystemTime) -> bool {
+        let host = url.host_str().unwrap_or_default().to_ascii_lowercase();
+        let domain_matches = host == self.domain || (
+            self.include_subdomains && host.ends_with(&format!(".{}", self.domain))
+        );
+        domain_matches
+            && path_matches(&self.path, url.path())
+            && (!self.secure || url.scheme() == "https")
+            && !self.is_expired(now)
+    }
+
+    fn is_expired(&self, now: SystemTime) -> bool {
+        match self.expires {
+            Some(expires) => expires <= now,
+            None => false,
+        }
+    }
+
+    fn same_key(&self, other: &Cookie) -> bool {
+        self.name == other.name && self.domain == other.domain && self.path == other.path
+    }
+}
+
+/// Whether a cookie with the given path should be sent with a request to the given path
+fn path_matches(cookie_path: &str, request_path: &str) -> bool {
+    request_path == cookie_path || (
+        request_path.starts_with(cookie_path) && (
+            cookie_path.ends_with('/') || request_path[cookie_path.len()..].starts_with('/')
+        )
+    )
+}
+
+#[derive(Debug, Default)]
+struct JarContents {
+    cookies: Vec<Cookie>,
+    loaded_files: Vec<PathBuf>,
+}
+
+/// The cookies shared by all the requests of a download:
+/// the ones loaded from a cookie file, and the ones set by the servers.
+#[derive(Debug, Default)]
+pub struct CookieJar {
+    contents: Mutex<JarContents>,
+}
+
+impl CookieJar {
+    fn lock(&self) -> std::sync::MutexGuard<'_, JarContents> {
+        self.contents.lock().expect("the cookie jar lock is poisoned")
+    }
+
+    /// Adds the cookies from a file in the Netscape format used by curl and by browser extensions.
+    /// A file is loaded only once, so that the cookies updated by the servers are not overwritten.
+    pub fn load_file(&self, path: &Path) -> Result<(), ZoomError> {
+        let mut contents = self.lock();
+        if contents.loaded_files.iter().any(|p| p == path) { return Ok(()); }
+        let text = std::fs::read_to_string(path)?;
+        let cookies = parse_cookie_file(&text).map_err(|(line, message)| ZoomError::InvalidCookieFile {
+            path: path.to_string_lossy().to_string(),
+            line,
+            message,
+        })?;
+        debug!("Loaded {} cookies from {:?}", cookies.len(), path);
+        for cookie in cookies { store(&mut contents.cookies, cookie); }
+        contents.loaded_files.push(path.to_path_buf());
+        Ok(())
+    }
+
+    /// The value of the Cookie header to send with a request to the given url
+    pub fn cookie_header(&self, url: &Url) -> Option<String> {
+        let now = SystemTime::now();
+        let contents = self.lock();
+        let mut cookies: Vec<&Cookie> = contents.cookies.iter().filter(|c| c.matches(url, now)).collect();
+        if cookies.is_empty() { return None; }
+        // More specific paths first, as browsers do
+        cookies.sort_by_key(|c| std::cmp::Reverse(c.path.len()));
+        Some(cookies.iter().map(|c| format!("{}={}", c.name, c.value)).collect::<Vec<_>>().join("; "))
+    }
+
+    /// Stores the cookies set by a response
+    pub fn store_response(&self, url: &Url, headers: &HeaderMap) {
+        let now = SystemTime::now();
+        let mut contents = self.lock();
+        for value in headers.get_all(header::SET_COOKIE) {
+            let cookie = value.to_str().ok().and_then(|v| parse_set_cookie(v, url, now));
+            if let Some(cookie) = cookie {
+                debug!("Received the cookie {} from {}", cookie.name, url);
+                store(&mut contents.cookies, cookie);
+            }
+        }
+        contents.cookies.retain(|c| !c.is_expired(now));
+    }
+}
+
+/// Adds a cookie to a list, replacing the cookie with the same name, domain and path
+fn store(cookies: &mut Vec<Cookie>, cookie: Cookie) {
+    match cookies.iter_mut().find(|c| c.same_key(&cookie)) {
+        Some(existing) => *existing = cookie,
+        None => cookies.push(cookie),
+    }
+}
+
+/// Parses a cookie file in the Netscape format, with one cookie per line:
+/// `domain include_subdomains path secure expires name value`, separated by tabs.
+/// Returns the number of the invalid line and an error message on failure.
+fn parse_cookie_file(text: &str) -> Result<Vec<Cookie>, (usize, String)> {
+    let mut cookies = vec![];
+    for (i, line) in text.lines().enumerate() {
+        // curl marks HttpOnly cookies with a prefix that looks like a comment
+        let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+        if line.trim().is_empty() || line.starts_with('#') { continue; }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 7 {
+            return Err((i + 1, format!("expected 7 fields separated by tabs, found {}", fields.len())));
+        }
+        let boolean = |s: &str| match s {
+            "TRUE" => Ok(true),
+            "FALSE" => Ok(false),
+            _ => Err((i + 1, format!("expected TRUE or FALSE, found '{}'", s))),
+        };
+        let expires: u64 = fields[4].parse()
+            .map_err(|_| (i + 1, format!("invalid expiration date '{}'", fields[4])))?;
+        cookies.push(Cookie {
+            domain: fields[0].trim_start_matches('.').to_ascii_lowercase(),
+            include_subdomains: boolean(fields[1])?,
+            path: fields[2].to_string(),
+            secure: boolean(fields[3])?,
+            expires: Some(expires).filter(|&e| e > 0).map(|e| UNIX_EPOCH + Duration::from_secs(e)),
+            name: fields[5].to_string(),
+            value: fields[6].to_string(),
+        });
+    }
+    Ok(cookies)
+}
+
+/// Parses the value of a Set-Cookie header received from the given url.
+/// Returns None for invalid cookies, and for cookies for domains other than the one of the url.
+fn parse_set_cookie(header: &str, url: &Url, now: SystemTime) -> Option<Cookie> {
+    let host = url.host_str()?.to_ascii_lowercase();
+    let mut parts = header.split(';');
+    let (name, value) = split_pair(parts.next()?)?;
+    let name = name.trim();
+    if name.is_empty() { return None; }
+    let mut cookie = Cookie {
+        name: name.to_string(),
+        value: value.trim().to_string(),
+        domain: host.clone(),
+        include_subdomains: false,
+        path: default_path(url.path()),
+        secure: false,
+        expires: None,
+    };
+    let mut max_age = None;
+    for attribute in parts {
+        let (key, value) = split_pair(attribute).unwrap_or((attribute, ""));
+        let value = value.trim();
+        match key.trim().to_ascii_lowercase().as_str() {
+            "domain" if !value.is_empty() => {
+                let domain = value.trim_start_matches('.').to_ascii_lowercase();
+                if !domain_covers(&domain, &host) { return None; }
+                cookie.domain = domain;
+                cookie.include_subdomains = true;
+            }
+            "path" if value.starts_with('/') => cookie.path = value.to_string(),
+            "secure" => cookie.secure = true,
+            "max-age" => max_age = value.parse::<i64>().ok(),
+            "expires" => cookie.expires = cookie.expires.or_else(|| parse_cookie_date(value)),
+            _ => {}
+        }
+    }
+    // Max-Age takes precedence over Expires
+    if let Some(seconds) = max_age {
+        cookie.expires = Some(if seconds <= 0 { UNIX_EPOCH } else { now + Duration::from_secs(seconds as u64) });
+    }
+    Some(cookie)
+}
+
+/// Whether a server can set a cookie for the given domain: the domain must be the host itself,
+/// or one of its parent domains that is not a top-level domain.
+/// There is no list of public suffixes: a server can still set a cookie for a domain such as co.uk.
+fn domain_covers(domain: &str, host: &str) -> bool {
+    domain == host || (
+        domain.contains('.')
+            && host.ends_with(&format!(".{}", domain))
+            && host.parse::<IpAddr>().is_err()
+    )
+}
+
+/// Splits `key=value` at the first equal sign
+fn split_pair(s: &str) -> Option<(&str, &str)> {
+    let mut parts = s.splitn(2, '=');
+    Some((parts.next()?, parts.next()?))
+}
+
+/// The directory of the path of the request, used when a cookie does not specify its path
+fn default_path(request_path: &str) -> String {
+    match request_path.rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(i) => request_path[..i].to_string(),
+    }
+}
+
+/// Parses dates such as "Wed, 21 Oct 2015 07:28:00 GMT" or "Wed, 21-Oct-2015 07:28:00 GMT"
+fn parse_cookie_date(date: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+    let normalized = date.replace(&['-', ','][..], " ");
+    let mut tokens = normalized.split_whitespace().skip_while(|t| t.parse::<u32>().is_err());
+    let day: u64 = tokens.next()?.parse().ok()?;
+    let month_name = tokens.next()?.to_ascii_lowercase();
+    let month = MONTHS.iter().position(|&m| month_name.starts_with(m))? as u64 + 1;
+    let mut year: u64 = tokens.next()?.parse().ok()?;
+    if year < 70 { year += 2000 } else if year < 100 { year += 1900 }
+    let time: Vec<u64> = tokens.next()?.split(':').map(|t| t.parse().ok()).collect::<Option<_>>()?;
+    if time.len() != 3 || year < 1970 { return None; }
+    let seconds = days_since_epoch(year, month, day) * 86400 + time[0] * 3600 + time[1] * 60 + time[2];
+    Some(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+/// Number of days between 1970-01-01 and the given date of the proleptic gregorian calendar
+fn days_since_epoch(year: u64, month: u64, day: u64) -> u64 {
+    let (year, month) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(s: &str) -> Url { Url::parse(s).unwrap() }
+
+    #[test]
+    fn test_cookie_file() {
+        let jar = CookieJar::default();
+        let file = "# Netscape HTTP Cookie File\n\
+            .example.com\tTRUE\t/\tFALSE\t0\tsession\tabc\n\
+            #HttpOnly_tiles.example.com\tFALSE\t/images\tTRUE\t0\ttoken\txyz\n\
+            old.example.com\tFALSE\t/\tFALSE\t1\texpired\t1\n";
+        let cookies = parse_cookie_file(file).unwrap();
+        jar.lock().cookies = cookies;
+        assert_eq!(jar.cookie_header(&url("http://www.example.com/a.dzi")), Some("session=abc".into()));
+        assert_eq!(
+            jar.cookie_header(&url("https://tiles.example.com/images/0_0.jpg")),
+            Some("token=xyz; session=abc".into())
+        );
+        assert_eq!(jar.cookie_header(&url("http://tiles.example.com/images/0_0.jpg")), Some("session=abc".into()));
+        assert_eq!(jar.cookie_header(&url("https://tiles.example.com/imagesX")), Some("session=abc".into()));
+        assert_eq!(jar.cookie_header(&url("http://old.example.com/")), Some("session=abc".into()));
+        assert_eq!(jar.cookie_header(&url("http://example.org/")), None);
+    }
+
+    #[test]
+    fn test_invalid_cookie_file() {
+        assert_eq!(parse_cookie_file("\nexample.com\tTRUE\t/\n").unwrap_err().0, 2);
+        assert!(parse_cookie_file("example.com\tYES\t/\tFALSE\t0\ta\tb").is_err());
+    }
+
+    #[test]
+    fn test_set_cookie() {
+        let jar = CookieJar::default();
+        let mut headers = HeaderMap::new();
+        headers.append(header::SET_COOKIE, "sid=123; Path=/; Domain=.example.com; HttpOnly".parse().unwrap());
+        headers.append(header::SET_COOKIE, "viewer=1; Max-Age=3600".parse().unwrap());
+        headers.append(header::SET_COOKIE, "evil=1; Domain=other.com".parse().unwrap());
+        headers.append(header::SET_COOKIE, "tld=1; Domain=.com".parse().unwrap());
+        headers.append(header::SET_COOKIE, "parent=1; Domain=w.example.com".parse().unwrap());
+        jar.store_response(&url("http://www.example.com/viewer/page.html"), &headers);
+        assert_eq!(jar.cookie_header(&url("http://tiles.example.com/1.jpg")), Some("sid=123".into()));
+        assert_eq!(jar.cookie_header(&url("http://other.com/1.jpg")), None);
+        assert_eq!(
+            jar.cookie_header(&url("http://www.example.com/viewer/tiles/1.jpg")),
+            Some("viewer=1; sid=123".into())
+        );
+        // A cookie is deleted by setting it again with an expiration date in the past
+        let mut headers = HeaderMap::new();
+        headers.append(header::SET_COOKIE, "sid=; Path=/; Domain=example.com; Expires=Thu, 01-Jan-1970 00:00:01 GMT"
+            .parse().unwrap());
+        jar.store_response(&url("http://www.example.com/"), &headers);
+        assert_eq!(jar.cookie_header(&url("http://tiles.example.com/1.jpg")), None);
+    }
+
+    #[test]
+    fn test_domain_covers() {
+        assert!(domain_covers("example.com", "www.example.com"));
+        assert!(domain_covers("localhost", "localhost"));
+        assert!(!domain_covers("com", "www.example.com"));
+        assert!(!domain_covers("ample.com", "www.example.com"));
+        assert!(!domain_covers("other.com", "www.example.com"));
+        assert!(!domain_covers("0.1", "10.0.0.1"));
+        assert!(domain_covers("10.0.0.1", "10.0.0.1"));
+    }
+
+    #[test]
+    fn test_parse_cookie_date() {
+        let expected = UNIX_EPOCH + Duration::from_secs(1_445_412_480);
+        assert_eq!(parse_cookie_date("Wed, 21 Oct 2015 07:28:00 GMT"), Some(expected));
+        assert_eq!(parse_cookie_date("Wednesday, 21-Oct-15 07:28:00 GMT"), Some(expected));
+        assert_eq!(parse_cookie_date("tomorrow"), None);
+    }
+}
@@ -36,6 +36,14 @@ impl DezoomerInput {
     }
 }
 
+/// A reference to one of the images listed in an input that contains several images,
+/// such as the pages of a book. `uri` is the input to give to a dezoomer to download the image.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ImageReference {
+    pub uri: String,
+    pub title: Option<String>,
+}
+
 /// A single image with a given width and height
 pub type ZoomLevel = Box<dyn TileProvider + Sync>;
 
@@ -73,6 +81,11 @@ pub trait Dezoomer {
     fn wrong_dezoomer(&self) -> DezoomerError {
         DezoomerError::WrongDezoomer { name: self.name() }
     }
+    /// A collection of http headers to use when downloading the data requested with
+    /// `DezoomerError::NeedsData`
+    fn http_headers(&self) -> HashMap<String, String> {
+        HashMap::new()
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -128,6 +141,10 @@ pub trait TileProvider: Debug {
     fn http_headers(&self) -> HashMap<String, String> {
         HashMap::new()
     }
+
+    /// Whether the tiles are the six faces of a cube panorama, side by side,
+    /// in the order of `encoder::equirectangular::CUBE_FACES`
+    fn is_cube(&self) -> bool { false }
 }
 
 /// Used to iterate over all the batches of tiles in a zoom level
@@ -178,6 +195,8 @@ pub trait TilesRect: Debug {
     fn post_process_fn(&self) -> PostProcessFn {
         PostProcessFn::None
     }
+    /// Whether the tile at the given position exists. Sparse images do not have all their tiles.
+    fn has_tile(&self, _pos: Vec2d) -> bool { true }
 
     fn tile_count(&self) -> u32 {
         let Vec2d { x, y } = self.size().ceil_div(self.tile_size());
@@ -197,7 +216,9 @@ impl<T: TilesRect> TileProvider for T {
         let Vec2d { x: w, y: h } = self.size().ceil_div(tile_size);
         let this: &T = self.borrow(); // Immutable borrow
         (0..h)
-            .flat_map(move |y| (0..w).map(move |x| this.tile_ref(Vec2d { x, y })))
+            .flat_map(move |y| (0..w).map(move |x| Vec2d { x, y }))
+            .filter(|&pos| this.has_tile(pos))
+            .map(|pos| this.tile_ref(pos))
             .collect()
     }
 
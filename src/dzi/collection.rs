@@ -0,0 +1,67 @@
+use serde::Deserialize;
+
+use crate::dezoomer::ImageReference;
+use crate::network::resolve_relative;
+
+/// A Deep Zoom Collection (.dzc file), that lists several deep zoom images
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct DzcFile {
+    #[serde(rename = "Items")]
+    pub items: Items,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct Items {
+    #[serde(rename = "I", default)]
+    pub items: Vec<Item>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct Item {
+    #[serde(rename = "Id")]
+    pub id: Option<u32>,
+    #[serde(rename = "N")]
+    pub n: u32,
+    #[serde(rename = "Source")]
+    pub source: String,
+}
+
+impl DzcFile {
+    /// The dzi files of the items of the collection
+    pub fn images(&self, collection_uri: &str) -> Vec<ImageReference> {
+        self.items.items.iter().map(|item| ImageReference {
+            uri: resolve_relative(collection_uri, &item.source),
+            title: Some(format!("Item {}", item.id.unwrap_or(item.n))),
+        }).collect()
+    }
+}
+
+/// Whether an xml file is a collection, and not a single image
+pub fn is_collection(contents: &[u8]) -> bool {
+    let start = &contents[..contents.len().min(512)];
+    String::from_utf8_lossy(start).contains("<Collection")
+}
+
+#[test]
+fn test_dzc() {
+    let contents = br#"<?xml version="1.0" encoding="utf-8"?>
+        <Collection MaxLevel="7" TileSize="256" Format="jpg" NextItemId="2"
+                    xmlns="http://schemas.microsoft.com/deepzoom/2008">
+          <Items>
+            <I Id="0" N="0" Source="items/first.dzi">
+              <Size Width="600" Height="300" />
+            </I>
+            <I Id="7" N="1" Source="http://example.com/second.dzi">
+              <Size Width="1200" Height="800" />
+              <Viewport Width="1" X="-0" Y="-0" />
+            </I>
+          </Items>
+        </Collection>"#;
+    assert!(is_collection(contents));
+    let dzc: DzcFile = serde_xml_rs::from_reader(&contents[..]).unwrap();
+    let images = dzc.images("http://example.com/collection/photos.dzc");
+    assert_eq!(images, vec![
+        ImageReference { uri: "http://example.com/collection/items/first.dzi".into(), title: Some("Item 0".into()) },
+        ImageReference { uri: "http://example.com/second.dzi".into(), title: Some("Item 7".into()) },
+    ]);
+}
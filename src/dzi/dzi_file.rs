@@ -1,4 +1,4 @@
-use serde::Deserialize;
+use serde::{de, Deserialize, Deserializer};
 
 use crate::Vec2d;
 
@@ -14,9 +14,110 @@ pub struct DziFile {
     pub format: String,
     #[serde(rename = "Size", default)]
     pub sizes: Vec<Size>,
+    /// Address of the tiles directory, only found in json descriptors
+    #[serde(rename = "Url", default)]
+    pub url: Option<String>,
+    /// The parts of sparse images that have tiles
+    #[serde(rename = "DisplayRects", default)]
+    pub display_rects: Option<DisplayRects>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct DisplayRects {
+    #[serde(rename = "DisplayRect", default)]
+    pub rects: Vec<DisplayRect>,
+}
+
+/// A rectangle of the full resolution image, that has tiles between two levels
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct DisplayRect {
+    #[serde(rename = "MinLevel", default)]
+    pub min_level: u32,
+    #[serde(rename = "MaxLevel", default)]
+    pub max_level: u32,
+    #[serde(rename = "Rect")]
+    pub rect: Rect,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct Rect {
+    #[serde(rename = "X", default)]
+    pub x: u32,
+    #[serde(rename = "Y", default)]
+    pub y: u32,
+    #[serde(rename = "Width")]
+    pub width: u32,
+    #[serde(rename = "Height")]
+    pub height: u32,
+}
+
+impl DisplayRect {
+    /// Whether the rectangle intersects the given area of a level, in the pixels of this level
+    pub fn covers(&self, level: u32, max_level: u32, position: Vec2d, size: Vec2d) -> bool {
+        if level < self.min_level || level > self.max_level { return false; }
+        let scale = Vec2d::square(1 << max_level.saturating_sub(level).min(31));
+        let start = Vec2d { x: self.rect.x, y: self.rect.y } / scale;
+        let end = (Vec2d { x: self.rect.x, y: self.rect.y } + Vec2d { x: self.rect.width, y: self.rect.height })
+            .ceil_div(scale);
+        let area_end = position + size;
+        position.x < end.x && start.x < area_end.x && position.y < end.y && start.y < area_end.y
+    }
+}
+
+/// The json flavor of dzi files, accepted by OpenSeadragon:
+/// `{"Image": {"TileSize": "256", "Overlap": "1", "Format": "jpg", "Size": {"Width": "600", "Height": "300"}}}`
+#[derive(Debug, Deserialize)]
+struct DziJson {
+    #[serde(rename = "Image")]
+    image: DziJsonImage,
+}
+
+#[derive(Debug, Deserialize)]
+struct DziJsonImage {
+    #[serde(rename = "Url", default)]
+    url: Option<String>,
+    #[serde(rename = "Format", default)]
+    format: String,
+    #[serde(rename = "Overlap", default, deserialize_with = "number")]
+    overlap: u32,
+    #[serde(rename = "TileSize", deserialize_with = "number")]
+    tile_size: u32,
+    #[serde(rename = "Size")]
+    size: JsonSize,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonSize {
+    #[serde(rename = "Width", deserialize_with = "number")]
+    width: u32,
+    #[serde(rename = "Height", deserialize_with = "number")]
+    height: u32,
+}
+
+/// Numbers are often written as strings in json dzi files
+fn number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Number { Number(u32), String(String) }
+    match Number::deserialize(deserializer)? {
+        Number::Number(n) => Ok(n),
+        Number::String(s) => s.trim().parse().map_err(de::Error::custom),
+    }
 }
 
 impl DziFile {
+    pub fn from_json(contents: &[u8]) -> Result<Self, serde_json::Error> {
+        let DziJson { image } = serde_json::from_slice(contents)?;
+        Ok(DziFile {
+            overlap: image.overlap,
+            tile_size: image.tile_size,
+            format: image.format,
+            sizes: vec![Size { width: image.size.width, height: image.size.height }],
+            url: image.url,
+            display_rects: None,
+        })
+    }
+
     pub fn get_size(&self) -> Result<Vec2d, DziError> {
         let size = self.sizes.get(0).ok_or(DziError::NoSize)?;
         Ok(Vec2d { x: size.width, y: size.height })
@@ -55,4 +156,44 @@ fn test_dzi() {
     assert_eq!(dzi.get_size().unwrap(), Vec2d { x: 5393, y: 3852 });
     assert_eq!(dzi.get_tile_size(), Vec2d { x: 256, y: 256 });
     assert_eq!(dzi.max_level(), 13);
-}
\ No newline at end of file
+}
+#[test]
+fn test_dzi_json() {
+    let dzi = DziFile::from_json(br#"{"Image": {
+        "xmlns": "http://schemas.microsoft.com/deepzoom/2008",
+        "Url": "http://example.com/image_files/",
+        "Format": "jpg", "Overlap": "1", "TileSize": 254,
+        "Size": {"Width": "5393", "Height": 3852}
+    }}"#).unwrap();
+    assert_eq!(dzi.get_size().unwrap(), Vec2d { x: 5393, y: 3852 });
+    assert_eq!(dzi.get_tile_size(), Vec2d::square(254));
+    assert_eq!(dzi.overlap, 1);
+    assert_eq!(dzi.url.as_deref(), Some("http://example.com/image_files/"));
+}
+
+#[test]
+fn test_display_rects() {
+    let dzi: DziFile = serde_xml_rs::from_str(
+        r#"
+        <Image TileSize="256" Overlap="1" Format="jpg">
+            <Size Width="4000" Height="3000"/>
+            <DisplayRects>
+                <DisplayRect MinLevel="0" MaxLevel="12">
+                    <Rect X="0" Y="0" Width="1000" Height="800"/>
+                </DisplayRect>
+                <DisplayRect MinLevel="10" MaxLevel="12">
+                    <Rect X="3000" Y="2000" Width="1000" Height="1000"/>
+                </DisplayRect>
+            </DisplayRects>
+        </Image>"#,
+    ).unwrap();
+    let rects = dzi.display_rects.unwrap().rects;
+    assert_eq!(rects.len(), 2);
+    let tile = Vec2d::square(256);
+    assert!(rects[0].covers(12, 12, Vec2d { x: 768, y: 768 }, tile));
+    assert!(!rects[0].covers(12, 12, Vec2d { x: 1024, y: 0 }, tile));
+    // At level 11, the rectangle is 500 pixels wide
+    assert!(rects[0].covers(11, 12, Vec2d { x: 256, y: 0 }, tile));
+    assert!(!rects[0].covers(11, 12, Vec2d { x: 512, y: 0 }, tile));
+    assert!(!rects[1].covers(9, 12, Vec2d { x: 0, y: 0 }, tile));
+}
@@ -1,17 +1,25 @@
 use std::sync::Arc;
 
 use custom_error::custom_error;
-use dzi_file::DziFile;
+use collection::DzcFile;
+use dzi_file::{DisplayRect, DziFile};
 
 use crate::dezoomer::*;
-use crate::network::remove_bom;
+use crate::network::{remove_bom, resolve_relative};
 
+mod collection;
 mod dzi_file;
 
 /// A dezoomer for Deep Zoom Images
 /// See https://docs.microsoft.com/en-us/previous-versions/windows/silverlight/dotnet-windows-silverlight/cc645043%28v%3dvs.95%29
+/// When the tiles are not in the standard `_files` folder, their url template can be given
+/// in the fragment of the dzi url: `image.dzi#tiles=http://example.com/tiles/{level}/{x}_{y}.{format}`
 #[derive(Default)]
-pub struct DziDezoomer;
+pub struct DziDezoomer {
+    tiles_template: Option<String>,
+}
+
+const TILES_OPTION: &str = "tiles=";
 
 impl Dezoomer for DziDezoomer {
     fn name(&self) -> &'static str {
@@ -19,14 +27,32 @@ impl Dezoomer for DziDezoomer {
     }
 
     fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
+        let mut uri_parts = data.uri.splitn(2, '#');
+        let dzi_uri = uri_parts.next().unwrap_or_default();
+        if let Some(template) = uri_parts.next().filter(|f| f.starts_with(TILES_OPTION)) {
+            // Resolving the url escapes the braces of the placeholders
+            let template = resolve_relative(dzi_uri, &template[TILES_OPTION.len()..]);
+            self.tiles_template = Some(template.replace("%7B", "{").replace("%7D", "}"));
+            return Err(DezoomerError::NeedsData { uri: dzi_uri.to_string() });
+        }
         let DezoomerInputWithContents { uri, contents } = data.with_contents()?;
-        let levels = load_from_properties(uri, contents)?;
+        if collection::is_collection(contents) {
+            let mut images = load_collection(uri, contents)?;
+            return if images.len() == 1 {
+                Err(DezoomerError::NeedsData { uri: images.swap_remove(0).uri })
+            } else {
+                Err(DezoomerError::MultipleImages { images })
+            };
+        }
+        let levels = load_from_properties(uri, contents, self.tiles_template.as_deref())?;
         Ok(levels)
     }
 }
 
 custom_error! {pub DziError
     XmlError{source: serde_xml_rs::Error} = "Unable to parse the dzi file: {source}",
+    JsonError{source: serde_json::Error} = "Unable to parse the json dzi file: {source}",
+    EmptyCollection = "The deep zoom collection does not contain any image",
     NoSize = "Expected a size in the DZI file",
     InvalidTileSize = "Invalid tile size",
 }
@@ -37,18 +63,49 @@ impl From<DziError> for DezoomerError {
     }
 }
 
-fn load_from_properties(url: &str, contents: &[u8]) -> Result<ZoomLevels, DziError> {
+fn load_collection(url: &str, contents: &[u8]) -> Result<Vec<ImageReference>, DziError> {
+    let collection: DzcFile = serde_xml_rs::from_reader(remove_bom(contents))?;
+    let images = collection.images(url);
+    if images.is_empty() { return Err(DziError::EmptyCollection); }
+    Ok(images)
+}
+
+/// The template of the tile urls in the standard layout: next to the dzi file, in a folder ending with `_files`.
+/// The query string of the dzi url, that often contains access tokens, is kept.
+fn default_tiles_template(url: &str) -> String {
+    let mut parts = url.splitn(2, '?');
+    let path = parts.next().unwrap_or_default();
+    let query = parts.next().map(|q| format!("?{}", q)).unwrap_or_default();
+    let name_start = path.rfind('/').map_or(0, |i| i + 1);
+    let stem_end = path[name_start..].rfind('.').map_or(path.len(), |i| name_start + i);
+    format!("{}_files/{{level}}/{{x}}_{{y}}.{{format}}{}", &path[..stem_end], query)
+}
 
+fn load_from_properties(url: &str, contents: &[u8], tiles_template: Option<&str>) -> Result<ZoomLevels, DziError> {
     // Workaround for https://github.com/netvl/xml-rs/issues/155
     // which the original author seems unwilling to fix
-    let image_properties: DziFile = serde_xml_rs::from_reader(remove_bom(contents))?;
+    let contents = remove_bom(contents);
+    let mut image_properties: DziFile = if contents.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'{') {
+        DziFile::from_json(contents)?
+    } else {
+        serde_xml_rs::from_reader(contents)?
+    };
 
     if image_properties.tile_size == 0 {
         return Err(DziError::InvalidTileSize);
     }
 
-    let dot_pos = url.rfind('.').unwrap_or(url.len() - 1);
-    let base_url = &Arc::new(format!("{}_files", &url[0..dot_pos]));
+    let tiles_template = &Arc::new(match (tiles_template, &image_properties.url) {
+        (Some(template), _) => template.to_string(),
+        (None, Some(tiles_url)) => format!(
+            "{}/{{level}}/{{x}}_{{y}}.{{format}}",
+            resolve_relative(url, tiles_url.trim_end_matches('/'))
+        ),
+        (None, None) => default_tiles_template(url),
+    });
+    let display_rects = &Arc::new(std::mem::take(&mut image_properties.display_rects)
+        .map(|r| r.rects)
+        .unwrap_or_default());
 
     let size = image_properties.get_size()?;
     let max_level = image_properties.max_level();
@@ -61,7 +118,9 @@ fn load_from_properties(url: &str, contents: &[u8]) -> Result<ZoomLevels, DziErr
     })
     .enumerate()
     .map(|(level_num, size)| DziLevel {
-        base_url: Arc::clone(base_url),
+        tiles_template: Arc::clone(tiles_template),
+        display_rects: Arc::clone(display_rects),
+        max_level,
         size,
         tile_size: image_properties.get_tile_size(),
         format: image_properties.format.clone(),
@@ -73,7 +132,11 @@ fn load_from_properties(url: &str, contents: &[u8]) -> Result<ZoomLevels, DziErr
 }
 
 struct DziLevel {
-    base_url: Arc<String>,
+    /// Url of the tiles, with `{level}`, `{x}`, `{y}` and `{format}` placeholders
+    tiles_template: Arc<String>,
+    /// Parts of a sparse image that have tiles. Empty when all the tiles exist.
+    display_rects: Arc<Vec<DisplayRect>>,
+    max_level: u32,
     size: Vec2d,
     tile_size: Vec2d,
     format: String,
@@ -91,14 +154,24 @@ impl TilesRect for DziLevel {
     }
 
     fn tile_url(&self, pos: Vec2d) -> String {
-        format!(
-            "{base}/{level}/{x}_{y}.{format}",
-            base = self.base_url,
-            level = self.level,
-            x = pos.x,
-            y = pos.y,
-            format = self.format
-        )
+        self.tiles_template
+            .replace("{level}", &self.level.to_string())
+            .replace("{x}", &pos.x.to_string())
+            .replace("{y}", &pos.y.to_string())
+            .replace("{format}", &self.format)
+    }
+
+    fn has_tile(&self, pos: Vec2d) -> bool {
+        let position = self.tile_size * pos;
+        self.display_rects.is_empty() || self.display_rects.iter()
+            .any(|r| r.covers(self.level, self.max_level, position, self.tile_size))
+    }
+
+    fn tile_count(&self) -> u32 {
+        let Vec2d { x: w, y: h } = self.size.ceil_div(self.tile_size);
+        (0..h).flat_map(|y| (0..w).map(move |x| Vec2d { x, y }))
+            .filter(|&pos| self.has_tile(pos))
+            .count() as u32
     }
 
     fn tile_ref(&self, pos: Vec2d) -> TileReference {
@@ -131,7 +204,7 @@ fn test_panorama() {
           <Size Width="600" Height="300"/>
           <DisplayRects></DisplayRects>
         </Image>"#;
-    let mut props = load_from_properties(url, contents).unwrap();
+    let mut props = load_from_properties(url, contents, None).unwrap();
     assert_eq!(props.len(), 11);
     let level = &mut props[1];
     let tiles: Vec<String> = level.next_tiles(None).into_iter().map(|t| t.url).collect();
@@ -153,5 +226,65 @@ fn test_dzi_with_bom() {
         <Image TileSize=\"256\" Overlap=\"0\" Format=\"jpg\" xmlns=\"http://schemas.microsoft.com/deepzoom/2008\">
         <Size Width=\"6261\" Height=\"6047\" />
         </Image>";
-    load_from_properties("http://test.com/test.xml", contents.as_ref()).unwrap();
+    load_from_properties("http://test.com/test.xml", contents.as_ref(), None).unwrap();
+}
+
+#[test]
+fn test_json_dzi() {
+    let contents = br#"{"Image": {"Url": "tiles/", "Format": "png", "Overlap": "0", "TileSize": "512",
+                      "Size": {"Width": "600", "Height": "300"}}}"#;
+    let mut levels = load_from_properties("http://x.fr/y/test.json", contents, None).unwrap();
+    let tiles: Vec<String> = levels[0].next_tiles(None).into_iter().map(|t| t.url).collect();
+    assert_eq!(tiles, vec!["http://x.fr/y/tiles/10/0_0.png", "http://x.fr/y/tiles/10/1_0.png"]);
+}
+
+#[test]
+fn test_collection() {
+    let contents = br#"<Collection MaxLevel="7" TileSize="256" Format="jpg">
+        <Items><I Id="3" N="0" Source="a.dzi"><Size Width="6" Height="3"/></I></Items>
+        </Collection>"#;
+    let input = DezoomerInput { uri: "http://x.fr/c.dzc".into(), contents: Some(contents.to_vec()) };
+    match DziDezoomer::default().zoom_levels(&input) {
+        Err(DezoomerError::NeedsData { uri }) => assert_eq!(uri, "http://x.fr/a.dzi"),
+        _ => panic!("The only image of the collection should be requested"),
+    }
+}
+
+#[test]
+fn test_tile_urls() {
+    assert_eq!(default_tiles_template("http://x.fr/y.z/image.dzi?sig=a.b&se=2"),
+               "http://x.fr/y.z/image_files/{level}/{x}_{y}.{format}?sig=a.b&se=2");
+    assert_eq!(default_tiles_template("http://x.fr/image"), "http://x.fr/image_files/{level}/{x}_{y}.{format}");
+    let mut dezoomer = DziDezoomer::default();
+    let input = DezoomerInput { uri: "http://x.fr/a/image.dzi#tiles=../tiles/{level}-{x}-{y}.{format}".into(), contents: None };
+    match dezoomer.zoom_levels(&input) {
+        Err(DezoomerError::NeedsData { uri }) => assert_eq!(uri, "http://x.fr/a/image.dzi"),
+        _ => panic!("The dzi file should be requested without the fragment"),
+    }
+    let contents = br#"<Image TileSize="256" Overlap="0" Format="png"><Size Width="300" Height="10"/></Image>"#;
+    let input = DezoomerInput { uri: "http://x.fr/a/image.dzi".into(), contents: Some(contents.to_vec()) };
+    let mut levels = dezoomer.zoom_levels(&input).unwrap();
+    let tiles: Vec<String> = levels[0].next_tiles(None).into_iter().map(|t| t.url).collect();
+    assert_eq!(tiles, vec!["http://x.fr/tiles/9-0-0.png", "http://x.fr/tiles/9-1-0.png"]);
+}
+
+#[test]
+fn test_sparse() {
+    let contents = br#"<Image TileSize="256" Overlap="1" Format="jpg">
+        <Size Width="2000" Height="1000"/>
+        <DisplayRects>
+            <DisplayRect MinLevel="0" MaxLevel="11"><Rect X="0" Y="0" Width="300" Height="200"/></DisplayRect>
+            <DisplayRect MinLevel="11" MaxLevel="11"><Rect X="1800" Y="900" Width="200" Height="100"/></DisplayRect>
+        </DisplayRects>
+    </Image>"#;
+    let mut levels = load_from_properties("http://x.fr/s.dzi", contents, None).unwrap();
+    let tiles: Vec<String> = levels[0].next_tiles(None).into_iter().map(|t| t.url).collect();
+    assert_eq!(tiles, vec![
+        "http://x.fr/s_files/11/0_0.jpg",
+        "http://x.fr/s_files/11/1_0.jpg",
+        "http://x.fr/s_files/11/7_3.jpg",
+    ]);
+    assert!(levels[0].name().contains("3 tiles"), "{}", levels[0].name());
+    let tiles = levels[1].next_tiles(None);
+    assert_eq!(tiles.len(), 1);
 }
@@ -0,0 +1,231 @@
+/**
+Low-level helpers to write BigTIFF files.
+A BigTIFF file starts with a 16-byte header pointing to the first image file directory (IFD).
+Each IFD lists the tags of an image (size, compression, position of the pixel data, ...)
+and points to the next IFD.
+See https://www.awaresystems.be/imaging/tiff/bigtiff.html
+*/
+use std::collections::BTreeMap;
+use std::io::{self, Seek, SeekFrom, Write};
+
+pub mod tags {
+    pub const NEW_SUBFILE_TYPE: u16 = 254;
+    pub const IMAGE_WIDTH: u16 = 256;
+    pub const IMAGE_LENGTH: u16 = 257;
+    pub const BITS_PER_SAMPLE: u16 = 258;
+    pub const COMPRESSION: u16 = 259;
+    pub const PHOTOMETRIC_INTERPRETATION: u16 = 262;
+    pub const STRIP_OFFSETS: u16 = 273;
+    pub const SAMPLES_PER_PIXEL: u16 = 277;
+    pub const ROWS_PER_STRIP: u16 = 278;
+    pub const STRIP_BYTE_COUNTS: u16 = 279;
+    pub const PLANAR_CONFIGURATION: u16 = 284;
+    pub const SOFTWARE: u16 = 305;
+    pub const TILE_WIDTH: u16 = 322;
+    pub const TILE_LENGTH: u16 = 323;
+    pub const TILE_OFFSETS: u16 = 324;
+    pub const TILE_BYTE_COUNTS: u16 = 325;
+    pub const Y_CB_CR_SUB_SAMPLING: u16 = 530;
+}
+
+pub mod compression {
+    pub const NONE: u16 = 1;
+    pub const JPEG: u16 = 7;
+    pub const DEFLATE: u16 = 8;
+}
+
+pub const PHOTOMETRIC_RGB: u16 = 2;
+pub const PHOTOMETRIC_YCBCR: u16 = 6;
+
+/// Value of the NewSubfileType tag for reduced-resolution versions of the main image
+pub const SUBFILE_REDUCED_IMAGE: u32 = 1;
+
+/// Size of the BigTIFF header, and thus the offset of the first byte after it
+pub const HEADER_SIZE: u64 = 16;
+
+/// The position of the first IFD offset in the header
+const FIRST_IFD_POINTER: u64 = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum IfdValue {
+    Short(Vec<u16>),
+    Long(Vec<u32>),
+    Long8(Vec<u64>),
+    Ascii(String),
+}
+
+impl IfdValue {
+    fn field_type(&self) -> u16 {
+        match self {
+            IfdValue::Ascii(_) => 2,
+            IfdValue::Short(_) => 3,
+            IfdValue::Long(_) => 4,
+            IfdValue::Long8(_) => 16,
+        }
+    }
+
+    fn count(&self) -> u64 {
+        let count = match self {
+            IfdValue::Short(v) => v.len(),
+            IfdValue::Long(v) => v.len(),
+            IfdValue::Long8(v) => v.len(),
+            IfdValue::Ascii(s) => s.len() + 1, // Null-terminated
+        };
+        count as u64
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            IfdValue::Short(v) => v.iter().flat_map(|x| x.to_le_bytes().to_vec()).collect(),
+            IfdValue::Long(v) => v.iter().flat_map(|x| x.to_le_bytes().to_vec()).collect(),
+            IfdValue::Long8(v) => v.iter().flat_map(|x| x.to_le_bytes().to_vec()).collect(),
+            IfdValue::Ascii(s) => s.bytes().chain(std::iter::once(0)).collect(),
+        }
+    }
+}
+
+/// An image file directory: the set of tags that describe a single image
+#[derive(Debug, Default, Clone)]
+pub struct Ifd {
+    entries: BTreeMap<u16, IfdValue>,
+}
+
+impl Ifd {
+    pub fn set(&mut self, tag: u16, value: IfdValue) {
+        self.entries.insert(tag, value);
+    }
+
+    /// Size in bytes of the IFD itself, without the values that do not fit inline
+    fn size(&self) -> u64 {
+        8 + 20 * self.entries.len() as u64 + 8
+    }
+
+    /// Total number of bytes written by `write`
+    pub fn byte_len(&self) -> u64 {
+        let extra_data_len: u64 = self.entries.values()
+            .map(|value| value.to_bytes().len() as u64)
+            .filter(|&len| len > 8)
+            .map(|len| len + len % 2)
+            .sum();
+        self.size() + extra_data_len
+    }
+
+    /// Writes the IFD at the given offset, which must be the current position of the writer,
+    /// followed by the values that are too large to fit inline in the IFD entries.
+    /// Returns the position of the field that points to the next IFD.
+    pub fn write<W: Write>(&self, writer: &mut W, offset: u64) -> io::Result<u64> {
+        let mut ifd = Vec::with_capacity(self.size() as usize);
+        let mut extra_data = vec![];
+        let extra_data_offset = offset + self.size();
+        ifd.extend_from_slice(&(self.entries.len() as u64).to_le_bytes());
+        for (&tag, value) in &self.entries {
+            ifd.extend_from_slice(&tag.to_le_bytes());
+            ifd.extend_from_slice(&value.field_type().to_le_bytes());
+            ifd.extend_from_slice(&value.count().to_le_bytes());
+            let mut bytes = value.to_bytes();
+            if bytes.len() <= 8 {
+                bytes.resize(8, 0);
+                ifd.extend_from_slice(&bytes);
+            } else {
+                let value_offset = extra_data_offset + extra_data.len() as u64;
+                ifd.extend_from_slice(&value_offset.to_le_bytes());
+                extra_data.extend_from_slice(&bytes);
+                // Values should start on a word boundary
+                if extra_data.len() % 2 == 1 { extra_data.push(0) }
+            }
+        }
+        let next_ifd_pointer = offset + ifd.len() as u64;
+        ifd.extend_from_slice(&0u64.to_le_bytes());
+        writer.write_all(&ifd)?;
+        writer.write_all(&extra_data)?;
+        Ok(next_ifd_pointer)
+    }
+}
+
+/// Writes a BigTIFF header that does not point to any IFD yet.
+/// Use `set_first_ifd` once the first IFD has been written.
+pub fn write_header<W: Write>(writer: &mut W) -> io::Result<()> {
+    writer.write_all(b"II")?; // Little-endian
+    writer.write_all(&43u16.to_le_bytes())?; // BigTIFF version number
+    writer.write_all(&8u16.to_le_bytes())?; // Size of offsets
+    writer.write_all(&0u16.to_le_bytes())?;
+    writer.write_all(&0u64.to_le_bytes())?; // First IFD offset, set later
+    Ok(())
+}
+
+pub fn set_first_ifd<W: Write + Seek>(writer: &mut W, ifd_offset: u64) -> io::Result<()> {
+    write_offset_at(writer, FIRST_IFD_POINTER, ifd_offset)
+}
+
+/// Overwrites the 8-byte offset at the given position, and then goes back to the end of the file
+pub fn write_offset_at<W: Write + Seek>(writer: &mut W, position: u64, offset: u64) -> io::Result<()> {
+    writer.seek(SeekFrom::Start(position))?;
+    writer.write_all(&offset.to_le_bytes())?;
+    writer.seek(SeekFrom::End(0))?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod test_reader {
+    //! A minimal BigTIFF reader, used to check the output of the encoders
+    use std::collections::BTreeMap;
+    use std::convert::TryInto;
+
+    fn u16_at(data: &[u8], pos: usize) -> u16 { u16::from_le_bytes(data[pos..pos + 2].try_into().unwrap()) }
+
+    fn u64_at(data: &[u8], pos: usize) -> u64 { u64::from_le_bytes(data[pos..pos + 8].try_into().unwrap()) }
+
+    /// Returns the tags of all the IFDs in the file, with their values as integers
+    pub fn read_ifds(data: &[u8]) -> Vec<BTreeMap<u16, Vec<u64>>> {
+        assert_eq!(&data[0..4], &[b'I', b'I', 43, 0], "Invalid BigTIFF header");
+        let mut ifds = vec![];
+        let mut ifd_offset = u64_at(data, 8) as usize;
+        while ifd_offset != 0 {
+            let count = u64_at(data, ifd_offset) as usize;
+            let mut tags = BTreeMap::new();
+            for i in 0..count {
+                let entry = ifd_offset + 8 + 20 * i;
+                let (tag, field_type) = (u16_at(data, entry), u16_at(data, entry + 2));
+                let value_count = u64_at(data, entry + 4) as usize;
+                let size = match field_type { 1 | 2 | 7 => 1, 3 => 2, 4 => 4, _ => 8 };
+                let value_pos = if size * value_count <= 8 { entry + 12 } else { u64_at(data, entry + 12) as usize };
+                let values = (0..value_count).map(|j| {
+                    let bytes = &data[value_pos + j * size..value_pos + (j + 1) * size];
+                    bytes.iter().rev().fold(0u64, |acc, &b| (acc << 8) | u64::from(b))
+                }).collect();
+                tags.insert(tag, values);
+            }
+            ifds.push(tags);
+            ifd_offset = u64_at(data, ifd_offset + 8 + 20 * count) as usize;
+        }
+        ifds
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_write_ifd() {
+        let mut out = Cursor::new(vec![]);
+        write_header(&mut out).unwrap();
+        let mut ifd = Ifd::default();
+        ifd.set(tags::IMAGE_LENGTH, IfdValue::Long(vec![20]));
+        ifd.set(tags::IMAGE_WIDTH, IfdValue::Long(vec![10]));
+        ifd.set(tags::STRIP_OFFSETS, IfdValue::Long8(vec![1, 2, 3]));
+        ifd.set(tags::SOFTWARE, IfdValue::Ascii("dezoomify-rs".into()));
+        let next_ifd_pointer = ifd.write(&mut out, HEADER_SIZE).unwrap();
+        assert_eq!(next_ifd_pointer, HEADER_SIZE + 8 + 4 * 20);
+        assert_eq!(out.get_ref().len() as u64, HEADER_SIZE + ifd.byte_len());
+        set_first_ifd(&mut out, HEADER_SIZE).unwrap();
+        let ifds = test_reader::read_ifds(out.get_ref());
+        assert_eq!(ifds.len(), 1);
+        assert_eq!(ifds[0][&tags::IMAGE_WIDTH], vec![10]);
+        assert_eq!(ifds[0][&tags::IMAGE_LENGTH], vec![20]);
+        assert_eq!(ifds[0][&tags::STRIP_OFFSETS], vec![1, 2, 3]);
+        assert_eq!(ifds[0][&tags::SOFTWARE].len(), "dezoomify-rs".len() + 1);
+    }
+}
@@ -1,10 +1,10 @@
 use std::path::{PathBuf, Path};
 use std::io;
-use image::{GenericImage, ImageBuffer, Pixel, ImageResult};
+use image::{DynamicImage, GenericImage, ImageBuffer, Pixel, ImageResult};
 use log::debug;
 
 use crate::Vec2d;
-use crate::encoder::{Encoder, crop_tile};
+use crate::encoder::{Encoder, crop_tile, OutputImage};
 use crate::tile::Tile;
 use crate::ZoomError;
 use std::io::BufWriter;
@@ -34,6 +34,15 @@ impl Canvas {
             image_writer,
         })
     }
+
+    /// A canvas that stores the image in memory instead of writing it to a file
+    pub fn in_memory(size: Vec2d, output: OutputImage) -> Self {
+        Canvas {
+            image: empty_buffer(size),
+            destination: PathBuf::new(),
+            image_writer: ImageWriter::Memory(output),
+        }
+    }
 }
 
 impl Encoder for Canvas {
@@ -47,6 +56,12 @@ impl Encoder for Canvas {
     }
 
     fn finalize(self: &mut Self) -> io::Result<()> {
+        if let ImageWriter::Memory(output) = &self.image_writer {
+            // Move the image out of the canvas instead of copying it, since it may be very large
+            let image = std::mem::replace(&mut self.image, ImageBuffer::new(0, 0));
+            *output.lock().expect("the output image lock is poisoned") = Some(DynamicImage::ImageRgba8(image));
+            return Ok(());
+        }
         self.image_writer.write(&self.image, &self.destination).map_err(|e| {
             match e {
                 image::ImageError::IoError(e) => e,
@@ -62,6 +77,7 @@ impl Encoder for Canvas {
 pub enum ImageWriter {
     Generic,
     Jpeg { quality: u8 },
+    Memory(OutputImage),
 }
 
 impl ImageWriter {
@@ -76,6 +92,7 @@ impl ImageWriter {
             ImageWriter::Generic => {
                 image.save(destination)?;
             },
+            ImageWriter::Memory(_) => unreachable!("in-memory images are not written to a file"),
         };
         Ok(())
     }
@@ -0,0 +1,194 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use image::{DynamicImage, GenericImage, ImageOutputFormat};
+use image::imageops::FilterType;
+use log::{debug, warn};
+
+use crate::{Vec2d, ZoomError};
+use crate::encoder::retiler::{Retiler, TileSaver};
+use crate::errors::image_error_to_io_error;
+use crate::tile::Tile;
+
+use super::{Encoder, TileFormat, TilingOptions};
+
+/**
+An encoder that re-tiles the image in the Deep Zoom format.
+It creates an xml descriptor (`name.dzi`) and a folder (`name_files`) that contains
+one sub-folder per zoom level, with tiles named `column_row.jpg`.
+Zoom level 0 is a single pixel, and each level is twice as large as the previous one.
+**/
+pub struct DziEncoder {
+    retiler: Retiler<DziTileSaver>,
+    tile_saver: Arc<DziTileSaver>,
+    destination: PathBuf,
+}
+
+impl DziEncoder {
+    pub fn new(destination: PathBuf, size: Vec2d, quality: u8, tiling: TilingOptions) -> Result<Self, ZoomError> {
+        let stem = destination.file_stem().unwrap_or_default().to_string_lossy();
+        let files_dir = destination.with_file_name(format!("{}_files", stem));
+        debug!("Creating the deep zoom tiles directory at {:?}", &files_dir);
+        std::fs::create_dir_all(&files_dir)?;
+        let tile_saver = Arc::new(DziTileSaver {
+            files_dir,
+            max_level: max_level(size),
+            tiling,
+            quality,
+        });
+        let tile_size = Vec2d::square(tiling.tile_size);
+        let retiler = Retiler::new(size, tile_size, tiling.overlap, Arc::clone(&tile_saver), 1);
+        Ok(DziEncoder { retiler, tile_saver, destination })
+    }
+
+    /// The retiler stops at the first level that fits in a single tile,
+    /// but deep zoom images have levels down to a single pixel.
+    /// The smallest levels are created by resizing the smallest level created by the retiler.
+    fn write_small_levels(&self) -> io::Result<()> {
+        let saver = &self.tile_saver;
+        let retiler_levels = self.retiler.level_count();
+        let smallest_level = saver.max_level + 1 - retiler_levels;
+        if smallest_level == 0 { return Ok(()); }
+        let mut size = self.size().ceil_div(2u32.pow(retiler_levels - 1));
+        let mut image = DynamicImage::new_rgb8(size.x, size.y);
+        let tile_size = saver.tiling.tile_size;
+        let tiles = size.ceil_div(tile_size);
+        for row in 0..tiles.y {
+            for col in 0..tiles.x {
+                let path = saver.tile_path(smallest_level, col, row);
+                let tile = match image::open(&path) {
+                    Ok(tile) => tile,
+                    Err(e) => {
+                        warn!("Unable to read the tile {:?} in order to create the smaller levels: {}", path, e);
+                        continue;
+                    }
+                };
+                let position = Vec2d { x: col, y: row } * tile_size - Vec2d::square(saver.tiling.overlap);
+                image.copy_from(&tile, position.x, position.y).map_err(image_error_to_io_error)?;
+            }
+        }
+        for level in (0..smallest_level).rev() {
+            size = size.ceil_div(2);
+            image = image.resize_exact(size.x, size.y, FilterType::Triangle);
+            saver.write_tile(level, 0, 0, &image)?;
+        }
+        Ok(())
+    }
+
+    fn descriptor(&self) -> String {
+        let tiling = self.tile_saver.tiling;
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+            <Image xmlns=\"http://schemas.microsoft.com/deepzoom/2008\" \
+            Format=\"{format}\" Overlap=\"{overlap}\" TileSize=\"{tile_size}\">\n  \
+            <Size Width=\"{width}\" Height=\"{height}\"/>\n\
+            </Image>\n",
+            format = tiling.format.extension(),
+            overlap = tiling.overlap,
+            tile_size = tiling.tile_size,
+            width = self.size().x,
+            height = self.size().y,
+        )
+    }
+}
+
+impl Encoder for DziEncoder {
+    fn add_tile(&mut self, tile: Tile) -> io::Result<()> {
+        self.retiler.add_tile(&tile)
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        self.retiler.finalize();
+        self.write_small_levels()?;
+        debug!("Writing the deep zoom descriptor to {:?}", self.destination);
+        OpenOptions::new().write(true).truncate(true).create(true)
+            .open(&self.destination)?
+            .write_all(self.descriptor().as_bytes())
+    }
+
+    fn size(&self) -> Vec2d {
+        self.retiler.size()
+    }
+}
+
+/// Number of the largest level of a deep zoom image of the given size
+fn max_level(size: Vec2d) -> u32 {
+    32 - (size.x.max(size.y).max(1) - 1).leading_zeros()
+}
+
+struct DziTileSaver {
+    files_dir: PathBuf,
+    max_level: u32,
+    tiling: TilingOptions,
+    quality: u8,
+}
+
+impl DziTileSaver {
+    fn tile_path(&self, level: u32, col: u32, row: u32) -> PathBuf {
+        self.files_dir
+            .join(level.to_string())
+            .join(format!("{}_{}.{}", col, row, self.tiling.format.extension()))
+    }
+
+    fn write_tile(&self, level: u32, col: u32, row: u32, image: &DynamicImage) -> io::Result<()> {
+        let path = self.tile_path(level, col, row);
+        debug!("Writing tile to {:?}", path);
+        std::fs::create_dir_all(path.parent().expect("tiles are in a directory"))?;
+        let format = match self.tiling.format {
+            TileFormat::Jpeg => ImageOutputFormat::Jpeg(self.quality),
+            TileFormat::Png => ImageOutputFormat::Png,
+        };
+        let file = &mut BufWriter::new(File::create(&path)?);
+        image.write_to(file, format).map_err(image_error_to_io_error)
+    }
+}
+
+impl TileSaver for DziTileSaver {
+    fn save_tile(&self, scale_factor: u32, _size: Vec2d, tile: Tile) -> io::Result<()> {
+        let level = self.max_level - scale_factor.trailing_zeros();
+        let Vec2d { x: col, y: row } = tile.position / (self.tiling.tile_size * scale_factor);
+        self.write_tile(level, col, row, &tile.image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GenericImageView, ImageBuffer, Rgb};
+
+    use super::*;
+
+    #[test]
+    fn test_max_level() {
+        assert_eq!(max_level(Vec2d { x: 1, y: 1 }), 0);
+        assert_eq!(max_level(Vec2d { x: 2, y: 1 }), 1);
+        assert_eq!(max_level(Vec2d { x: 5393, y: 3852 }), 13);
+    }
+
+    #[test]
+    fn test_dzi_encoder() {
+        let destination = std::env::temp_dir().join("dezoomify-rs-test-dzi-encoder.dzi");
+        let files_dir = destination.with_file_name("dezoomify-rs-test-dzi-encoder_files");
+        let _ = std::fs::remove_dir_all(&files_dir);
+        let size = Vec2d { x: 6, y: 3 };
+        let tiling = TilingOptions { tile_size: 4, overlap: 1, format: TileFormat::Png };
+        let mut encoder = DziEncoder::new(destination.clone(), size, 100, tiling).unwrap();
+        let image = ImageBuffer::from_fn(size.x, size.y, |x, y| Rgb([x as u8, y as u8, 0]));
+        encoder.add_tile(Tile { position: Vec2d { x: 0, y: 0 }, image: DynamicImage::ImageRgb8(image) }).unwrap();
+        encoder.finalize().unwrap();
+
+        let descriptor = std::fs::read_to_string(&destination).unwrap();
+        assert!(descriptor.contains(r#"Format="png" Overlap="1" TileSize="4""#));
+        assert!(descriptor.contains(r#"<Size Width="6" Height="3"/>"#));
+        let tile = |level: u32, name: &str| image::open(files_dir.join(level.to_string()).join(name)).unwrap();
+        // The second tile of the largest level starts one pixel before its position in the grid
+        let second_tile = tile(3, "1_0.png");
+        assert_eq!(second_tile.dimensions(), (3, 3));
+        assert_eq!(second_tile.get_pixel(0, 0)[0], 3);
+        assert_eq!(tile(3, "0_0.png").dimensions(), (5, 3));
+        assert_eq!(tile(2, "0_0.png").dimensions(), (3, 2));
+        assert_eq!(tile(1, "0_0.png").dimensions(), (2, 1));
+        assert_eq!(tile(0, "0_0.png").dimensions(), (1, 1));
+    }
+}
@@ -0,0 +1,170 @@
+use std::f64::consts::PI;
+use std::io;
+
+use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+use log::debug;
+
+use crate::encoder::Encoder;
+use crate::tile::Tile;
+use crate::Vec2d;
+
+/// Order of the faces of a cube panorama, when they are placed side by side in a single image
+pub const CUBE_FACES: [&str; 6] = ["front", "right", "back", "left", "up", "down"];
+
+/// Number of rows of the panorama that are computed and sent together to the output encoder
+const STRIP_HEIGHT: u32 = 64;
+
+/// Size of the equirectangular panorama made from the given strip of cube faces
+pub fn equirectangular_size(faces_size: Vec2d) -> Vec2d {
+    let face = faces_size.y;
+    Vec2d { x: 4 * face, y: 2 * face }
+}
+
+/// Receives the six faces of a cube panorama side by side, in the order of `CUBE_FACES`,
+/// and writes them to another encoder as a single 2:1 equirectangular image.
+/// The faces are kept in memory until all the tiles have been received.
+pub struct EquirectangularEncoder {
+    inner: Box<dyn Encoder>,
+    face_size: u32,
+    faces: Vec<RgbaImage>,
+}
+
+impl EquirectangularEncoder {
+    pub fn new(inner: Box<dyn Encoder>, faces_size: Vec2d) -> Self {
+        let face_size = faces_size.y;
+        let faces = CUBE_FACES.iter().map(|_| RgbaImage::new(face_size, face_size)).collect();
+        EquirectangularEncoder { inner, face_size, faces }
+    }
+
+    /// Bilinear interpolation of the pixels of a face around the given coordinates, between 0 and 1
+    fn sample(&self, face: usize, s: f64, t: f64) -> Rgba<u8> {
+        let image = &self.faces[face];
+        let max = f64::from(self.face_size - 1);
+        let px = (s * f64::from(self.face_size) - 0.5).clamp(0., max);
+        let py = (t * f64::from(self.face_size) - 0.5).clamp(0., max);
+        let (x0, y0) = (px.floor() as u32, py.floor() as u32);
+        let (x1, y1) = ((x0 + 1).min(self.face_size - 1), (y0 + 1).min(self.face_size - 1));
+        let (fx, fy) = (px - f64::from(x0), py - f64::from(y0));
+        let corners = [
+            (image.get_pixel(x0, y0), (1. - fx) * (1. - fy)),
+            (image.get_pixel(x1, y0), fx * (1. - fy)),
+            (image.get_pixel(x0, y1), (1. - fx) * fy),
+            (image.get_pixel(x1, y1), fx * fy),
+        ];
+        let mut result = [0u8; 4];
+        for (c, value) in result.iter_mut().enumerate() {
+            let v: f64 = corners.iter().map(|(p, weight)| f64::from(p.0[c]) * weight).sum();
+            *value = v.round() as u8;
+        }
+        Rgba(result)
+    }
+}
+
+impl Encoder for EquirectangularEncoder {
+    fn add_tile(&mut self, tile: Tile) -> io::Result<()> {
+        let Vec2d { x: tile_x, y: tile_y } = tile.position;
+        let Vec2d { x: width, y: height } = tile.size();
+        if tile_y >= self.face_size { return Ok(()); }
+        let height = height.min(self.face_size - tile_y);
+        for (i, face) in self.faces.iter_mut().enumerate() {
+            let face_x = i as u32 * self.face_size;
+            let start = tile_x.max(face_x);
+            let end = (tile_x + width).min(face_x + self.face_size);
+            if start >= end { continue; }
+            let part = tile.image.view(start - tile_x, 0, end - start, height).to_image();
+            image::imageops::replace(face, &part, start - face_x, tile_y);
+        }
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        let Vec2d { x: width, y: height } = self.size();
+        debug!("Reprojecting the cube faces into a {}x{} panorama", width, height);
+        // Sine and cosine of the longitude of each column
+        let longitudes: Vec<(f64, f64)> = (0..width)
+            .map(|x| ((f64::from(x) + 0.5) / f64::from(width) * 2. * PI - PI).sin_cos())
+            .collect();
+        for strip_y in (0..height).step_by(STRIP_HEIGHT as usize) {
+            let rows = STRIP_HEIGHT.min(height - strip_y);
+            let mut strip = RgbaImage::new(width, rows);
+            for y in 0..rows {
+                let latitude = PI / 2. - (f64::from(strip_y + y) + 0.5) / f64::from(height) * PI;
+                let (sin_lat, cos_lat) = latitude.sin_cos();
+                for (x, &(sin_lon, cos_lon)) in longitudes.iter().enumerate() {
+                    let (face, s, t) = face_coordinates(cos_lat * sin_lon, sin_lat, cos_lat * cos_lon);
+                    strip.put_pixel(x as u32, y, self.sample(face, s, t));
+                }
+            }
+            let position = Vec2d { x: 0, y: strip_y };
+            self.inner.add_tile(Tile { image: DynamicImage::ImageRgba8(strip), position })?;
+        }
+        self.faces.clear();
+        self.inner.finalize()
+    }
+
+    fn size(&self) -> Vec2d {
+        self.inner.size()
+    }
+}
+
+/// The face of the cube seen in the given direction, and the coordinates between 0 and 1
+/// of the corresponding point on this face, as seen from the inside of the cube.
+/// x points to the right, y up, and z to the front.
+fn face_coordinates(x: f64, y: f64, z: f64) -> (usize, f64, f64) {
+    let (ax, ay, az) = (x.abs(), y.abs(), z.abs());
+    let (face, s, t) = if ax >= ay && ax >= az {
+        if x > 0. { (1, -z / ax, -y / ax) } else { (3, z / ax, -y / ax) }
+    } else if ay >= az {
+        if y > 0. { (4, x / ay, z / ay) } else { (5, x / ay, -z / ay) }
+    } else if z > 0. {
+        (0, x / az, -y / az)
+    } else {
+        (2, -x / az, -y / az)
+    };
+    (face, (s + 1.) / 2., (t + 1.) / 2.)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encoder::canvas::Canvas;
+    use crate::encoder::OutputImage;
+
+    use super::*;
+
+    #[test]
+    fn test_face_coordinates() {
+        assert_eq!(face_coordinates(0., 0., 1.), (0, 0.5, 0.5));
+        assert_eq!(face_coordinates(1., 0., 0.), (1, 0.5, 0.5));
+        assert_eq!(face_coordinates(0., 0., -1.), (2, 0.5, 0.5));
+        assert_eq!(face_coordinates(-1., 0., 0.), (3, 0.5, 0.5));
+        assert_eq!(face_coordinates(0., -1., 0.), (5, 0.5, 0.5));
+        // Looking up, the front of the panorama is at the bottom of the face
+        assert_eq!(face_coordinates(0., 1., 0.5), (4, 0.5, 0.75));
+        // Looking at the front, the right of the panorama is on the right of the face
+        assert_eq!(face_coordinates(0.5, 0., 1.), (0, 0.75, 0.5));
+    }
+
+    #[test]
+    fn test_reprojection() {
+        let face = 8;
+        let colors: Vec<Rgba<u8>> = (0..6u8).map(|i| Rgba([i * 40, 255 - i * 40, 0, 255])).collect();
+        let faces_size = Vec2d { x: 6 * face, y: face };
+        let output = OutputImage::default();
+        let canvas = Canvas::in_memory(equirectangular_size(faces_size), output.clone());
+        let mut encoder = EquirectangularEncoder::new(Box::new(canvas), faces_size);
+        for (i, &color) in colors.iter().enumerate() {
+            let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(face, face, color));
+            encoder.add_tile(Tile { image, position: Vec2d { x: i as u32 * face, y: 0 } }).unwrap();
+        }
+        encoder.finalize().unwrap();
+        let image = output.lock().unwrap().take().unwrap();
+        assert_eq!(image.dimensions(), (4 * face, 2 * face));
+        let at = |x: u32, y: u32| image.get_pixel(x, y);
+        assert_eq!(at(16, 8), colors[0]); // front
+        assert_eq!(at(24, 8), colors[1]); // right
+        assert_eq!(at(0, 8), colors[2]); // back
+        assert_eq!(at(8, 8), colors[3]); // left
+        assert_eq!(at(16, 0), colors[4]); // up
+        assert_eq!(at(16, 15), colors[5]); // down
+    }
+}
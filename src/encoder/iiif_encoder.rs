@@ -29,7 +29,7 @@ impl IiifEncoder {
         let tile_saver = IIIFTileSaver { root_path: destination.clone(), quality };
         let tile_size = Vec2d::square(512);
         Ok(IiifEncoder {
-            retiler: Retiler::new(size, tile_size, Arc::new(tile_saver), 1),
+            retiler: Retiler::new(size, tile_size, 0, Arc::new(tile_saver), 1),
             root_path: destination,
         })
     }
@@ -95,7 +95,7 @@ struct IIIFTileSaver {
 }
 
 impl TileSaver for IIIFTileSaver {
-    fn save_tile(&self, size: Vec2d, tile: Tile) -> io::Result<()> {
+    fn save_tile(&self, _scale_factor: u32, size: Vec2d, tile: Tile) -> io::Result<()> {
         let tile_size = tile.size();
         let region = format!("{},{},{},{}",
                              tile.position.x, tile.position.y,
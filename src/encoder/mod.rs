@@ -1,4 +1,5 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 use image::{DynamicImage, GenericImageView, SubImage};
 use log::debug;
@@ -12,6 +13,12 @@ pub mod png_encoder;
 pub mod pixel_streamer;
 pub mod tile_buffer;
 pub mod iiif_encoder;
+pub mod bigtiff;
+pub mod tiff_encoder;
+pub mod pyramid_tiff_encoder;
+pub mod dzi_encoder;
+pub mod zoomify_encoder;
+pub mod equirectangular;
 mod retiler;
 
 pub trait Encoder: Send + 'static {
@@ -23,11 +30,76 @@ pub trait Encoder: Send + 'static {
     fn size(&self) -> Vec2d;
 }
 
-fn encoder_for_name(destination: PathBuf, size: Vec2d, compression: u8) -> Result<Box<dyn Encoder>, ZoomError> {
+/// Image format of the individual tiles, for the output formats that are made of several tiles
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TileFormat {
+    Jpeg,
+    Png,
+}
+
+impl TileFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            TileFormat::Jpeg => "jpg",
+            TileFormat::Png => "png",
+        }
+    }
+}
+
+impl FromStr for TileFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "jpg" | "jpeg" => Ok(TileFormat::Jpeg),
+            "png" => Ok(TileFormat::Png),
+            _ => Err("Invalid tile format. Expected 'jpg' or 'png'"),
+        }
+    }
+}
+
+/// Receives the image assembled by an in-memory encoder
+pub type OutputImage = std::sync::Arc<std::sync::Mutex<Option<DynamicImage>>>;
+
+/// How to split the image into tiles, for the output formats that are made of several tiles
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TilingOptions {
+    pub tile_size: u32,
+    pub overlap: u32,
+    pub format: TileFormat,
+}
+
+fn encoder_for_name(
+    destination: PathBuf,
+    size: Vec2d,
+    compression: u8,
+    tiling: TilingOptions,
+) -> Result<Box<dyn Encoder>, ZoomError> {
     let extension = destination.extension().unwrap_or_default();
+    // Cloud optimized GeoTIFF files are conventionally named *.cog.tif
+    let is_cog = destination.file_stem().map(Path::new).and_then(Path::extension) == Some("cog".as_ref());
     if extension == "png" {
         debug!("Using the streaming png encoder");
         Ok(Box::new(png_encoder::PngEncoder::new(destination, size, compression)?))
+    } else if extension == "ptif" || extension == "ptiff" || (is_cog && (extension == "tif" || extension == "tiff")) {
+        let tile_compression = if compression == 0 {
+            pyramid_tiff_encoder::TileCompression::Deflate
+        } else {
+            pyramid_tiff_encoder::TileCompression::Jpeg { quality: 100u8.saturating_sub(compression) }
+        };
+        debug!("Using the pyramidal TIFF encoder with {:?} tiles", tile_compression);
+        Ok(Box::new(pyramid_tiff_encoder::PyramidTiffEncoder::new(destination, size, tile_compression)?))
+    } else if extension == "tif" || extension == "tiff" {
+        debug!("Using the streaming BigTIFF encoder");
+        Ok(Box::new(tiff_encoder::TiffEncoder::new(destination, size, compression)?))
+    } else if extension == "dzi" {
+        debug!("Using the deep zoom tiling encoder with {:?}", tiling);
+        let quality = 100u8.saturating_sub(compression);
+        Ok(Box::new(dzi_encoder::DziEncoder::new(destination, size, quality, tiling)?))
+    } else if extension == "zoomify" {
+        debug!("Using the zoomify tiling encoder");
+        let quality = 100u8.saturating_sub(compression);
+        Ok(Box::new(zoomify_encoder::ZoomifyEncoder::new(destination, size, quality)?))
     } else if extension == "iiif" {
         debug!("Using the iiif tiling encoder");
 	let quality = 100u8.saturating_sub(compression);
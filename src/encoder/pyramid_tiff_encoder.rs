@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+use image::{DynamicImage, GenericImage, ImageOutputFormat, RgbImage};
+use log::debug;
+
+use crate::{Vec2d, ZoomError};
+use crate::encoder::retiler::{Retiler, TileSaver};
+use crate::errors::image_error_to_io_error;
+use crate::tile::Tile;
+
+use super::bigtiff::{self, compression, Ifd, IfdValue, tags};
+use super::Encoder;
+
+/// Tiled TIFF readers such as iipsrv and GDAL commonly use 256x256 tiles
+const TILE_SIZE: u32 = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TileCompression {
+    Jpeg { quality: u8 },
+    Deflate,
+}
+
+/**
+An encoder that writes a tiled, multi-resolution BigTIFF file, also known as a pyramidal TIFF.
+The file follows the layout of Cloud Optimized GeoTIFFs:
+all the image file directories are at the beginning of the file, starting with the full resolution image,
+followed by the tiles of the overviews, from the smallest to the largest, and then by the full resolution tiles.
+
+Since tiles are received in any order, they are first compressed and written to a temporary file,
+and then copied in the right order to the final file when the image is finalized.
+**/
+pub struct PyramidTiffEncoder {
+    retiler: Retiler<TiffTileSaver>,
+    tile_saver: Arc<TiffTileSaver>,
+    destination: PathBuf,
+}
+
+impl PyramidTiffEncoder {
+    pub fn new(destination: PathBuf, size: Vec2d, tile_compression: TileCompression) -> Result<Self, ZoomError> {
+        let tmp_path = destination.with_extension("tiles.part");
+        debug!("Storing the tiles of the pyramidal TIFF in {:?}", tmp_path);
+        let tmp_file = OpenOptions::new().read(true).write(true).truncate(true).create(true).open(&tmp_path)?;
+        let tile_saver = Arc::new(TiffTileSaver {
+            original_size: size,
+            tile_compression,
+            tmp_path,
+            store: Mutex::new(TileStore {
+                writer: BufWriter::new(tmp_file),
+                position: 0,
+                tiles: HashMap::new(),
+            }),
+        });
+        let retiler = Retiler::new(size, Vec2d::square(TILE_SIZE), 0, Arc::clone(&tile_saver), 1);
+        Ok(PyramidTiffEncoder { retiler, tile_saver, destination })
+    }
+
+    fn write_tiff(&self) -> io::Result<()> {
+        let mut store = self.tile_saver.store.lock().unwrap();
+        store.writer.flush()?;
+        let size = self.size();
+        let levels: Vec<Level> = (0..self.retiler.level_count())
+            .map(|n| Level::new(size, 2u32.pow(n)))
+            .collect();
+
+        // The tile data comes after all the IFDs, starting with the smallest overview
+        let mut ifds: Vec<Ifd> = levels.iter().enumerate()
+            .map(|(n, level)| self.tile_saver.ifd(n, level))
+            .collect();
+        let ifds_len: u64 = ifds.iter().map(Ifd::byte_len).sum();
+        let mut position = bigtiff::HEADER_SIZE + ifds_len;
+        let mut tile_order = vec![];
+        for (n, (level, ifd)) in levels.iter().zip(ifds.iter_mut()).enumerate().rev() {
+            let tile_count = level.tile_count();
+            let mut offsets = Vec::with_capacity(tile_count);
+            let mut byte_counts = Vec::with_capacity(tile_count);
+            for index in 0..tile_count {
+                let (offset, len) = store.tiles.get(&(n, index)).copied().unwrap_or((0, 0));
+                if len > 0 {
+                    tile_order.push(offset..offset + len);
+                    offsets.push(position);
+                    position += len;
+                } else {
+                    debug!("Tile {} of level {} is missing", index, n);
+                    offsets.push(0);
+                }
+                byte_counts.push(len);
+            }
+            ifd.set(tags::TILE_OFFSETS, IfdValue::Long8(offsets));
+            ifd.set(tags::TILE_BYTE_COUNTS, IfdValue::Long8(byte_counts));
+        }
+
+        debug!("Writing pyramidal TIFF with {} levels to {:?}", levels.len(), self.destination);
+        let file = OpenOptions::new().write(true).truncate(true).create(true).open(&self.destination)?;
+        let mut writer = BufWriter::new(file);
+        bigtiff::write_header(&mut writer)?;
+        let mut ifd_offset = bigtiff::HEADER_SIZE;
+        let mut next_ifd_pointers = vec![];
+        for ifd in &ifds {
+            next_ifd_pointers.push(ifd.write(&mut writer, ifd_offset)?);
+            ifd_offset += ifd.byte_len();
+        }
+        bigtiff::set_first_ifd(&mut writer, bigtiff::HEADER_SIZE)?;
+        let mut ifd_offset = bigtiff::HEADER_SIZE;
+        for (ifd, &next_ifd_pointer) in ifds.iter().zip(next_ifd_pointers.iter()).take(ifds.len() - 1) {
+            ifd_offset += ifd.byte_len();
+            bigtiff::write_offset_at(&mut writer, next_ifd_pointer, ifd_offset)?;
+        }
+
+        let tmp_file = store.writer.get_mut();
+        let mut buffer = vec![];
+        for range in tile_order {
+            tmp_file.seek(SeekFrom::Start(range.start))?;
+            buffer.resize((range.end - range.start) as usize, 0);
+            tmp_file.read_exact(&mut buffer)?;
+            writer.write_all(&buffer)?;
+        }
+        writer.flush()
+    }
+}
+
+impl Encoder for PyramidTiffEncoder {
+    fn add_tile(&mut self, tile: Tile) -> io::Result<()> {
+        self.retiler.add_tile(&tile)
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        self.retiler.finalize();
+        let result = self.write_tiff();
+        debug!("Removing temporary tile file {:?}", self.tile_saver.tmp_path);
+        std::fs::remove_file(&self.tile_saver.tmp_path)?;
+        result
+    }
+
+    fn size(&self) -> Vec2d {
+        self.retiler.size()
+    }
+}
+
+/// Dimensions of the image at a given zoom level
+struct Level {
+    size: Vec2d,
+    tiles: Vec2d,
+}
+
+impl Level {
+    fn new(original_size: Vec2d, scale_factor: u32) -> Self {
+        let size = original_size.ceil_div(scale_factor);
+        Level { size, tiles: size.ceil_div(TILE_SIZE) }
+    }
+
+    fn tile_count(&self) -> usize {
+        self.tiles.area() as usize
+    }
+}
+
+struct TileStore {
+    writer: BufWriter<File>,
+    position: u64,
+    /// Offset and length in the temporary file of each tile, by level number and tile index
+    tiles: HashMap<(usize, usize), (u64, u64)>,
+}
+
+struct TiffTileSaver {
+    original_size: Vec2d,
+    tile_compression: TileCompression,
+    tmp_path: PathBuf,
+    store: Mutex<TileStore>,
+}
+
+impl TiffTileSaver {
+    fn encode(&self, image: &DynamicImage) -> io::Result<Vec<u8>> {
+        // All tiles in a TIFF file have the same size, even at the right and bottom edges of the image
+        let mut padded = RgbImage::new(TILE_SIZE, TILE_SIZE);
+        padded.copy_from(&image.to_rgb(), 0, 0).map_err(image_error_to_io_error)?;
+        let mut data = vec![];
+        match self.tile_compression {
+            TileCompression::Jpeg { quality } => {
+                DynamicImage::ImageRgb8(padded).write_to(&mut data, ImageOutputFormat::Jpeg(quality)).map_err(image_error_to_io_error)?;
+            }
+            TileCompression::Deflate => {
+                let mut encoder = ZlibEncoder::new(data, Compression::default());
+                encoder.write_all(&padded.into_raw())?;
+                data = encoder.finish()?;
+            }
+        }
+        Ok(data)
+    }
+
+    fn ifd(&self, level_number: usize, level: &Level) -> Ifd {
+        let mut ifd = Ifd::default();
+        if level_number > 0 {
+            ifd.set(tags::NEW_SUBFILE_TYPE, IfdValue::Long(vec![bigtiff::SUBFILE_REDUCED_IMAGE]));
+        } else {
+            ifd.set(tags::SOFTWARE, IfdValue::Ascii("dezoomify-rs".into()));
+        }
+        ifd.set(tags::IMAGE_WIDTH, IfdValue::Long(vec![level.size.x]));
+        ifd.set(tags::IMAGE_LENGTH, IfdValue::Long(vec![level.size.y]));
+        ifd.set(tags::BITS_PER_SAMPLE, IfdValue::Short(vec![8, 8, 8]));
+        ifd.set(tags::SAMPLES_PER_PIXEL, IfdValue::Short(vec![3]));
+        ifd.set(tags::PLANAR_CONFIGURATION, IfdValue::Short(vec![1]));
+        ifd.set(tags::TILE_WIDTH, IfdValue::Long(vec![TILE_SIZE]));
+        ifd.set(tags::TILE_LENGTH, IfdValue::Long(vec![TILE_SIZE]));
+        // The positions of the tiles are known only when the size of all the IFDs is known
+        ifd.set(tags::TILE_OFFSETS, IfdValue::Long8(vec![0; level.tile_count()]));
+        ifd.set(tags::TILE_BYTE_COUNTS, IfdValue::Long8(vec![0; level.tile_count()]));
+        match self.tile_compression {
+            TileCompression::Jpeg { .. } => {
+                // The jpeg encoder converts pixels to YCbCr, without chroma subsampling
+                ifd.set(tags::COMPRESSION, IfdValue::Short(vec![compression::JPEG]));
+                ifd.set(tags::PHOTOMETRIC_INTERPRETATION, IfdValue::Short(vec![bigtiff::PHOTOMETRIC_YCBCR]));
+                ifd.set(tags::Y_CB_CR_SUB_SAMPLING, IfdValue::Short(vec![1, 1]));
+            }
+            TileCompression::Deflate => {
+                ifd.set(tags::COMPRESSION, IfdValue::Short(vec![compression::DEFLATE]));
+                ifd.set(tags::PHOTOMETRIC_INTERPRETATION, IfdValue::Short(vec![bigtiff::PHOTOMETRIC_RGB]));
+            }
+        }
+        ifd
+    }
+}
+
+impl TileSaver for TiffTileSaver {
+    fn save_tile(&self, scale_factor: u32, _size: Vec2d, tile: Tile) -> io::Result<()> {
+        let level_number = scale_factor.trailing_zeros() as usize;
+        let level = Level::new(self.original_size, scale_factor);
+        let Vec2d { x: col, y: row } = tile.position / (TILE_SIZE * scale_factor);
+        let index = (row * level.tiles.x + col) as usize;
+        let data = self.encode(&tile.image)?;
+        let mut store = self.store.lock().unwrap();
+        let offset = store.position;
+        store.writer.write_all(&data)?;
+        store.position += data.len() as u64;
+        debug!("Saved tile {} of level {} ({} bytes)", index, level_number, data.len());
+        store.tiles.insert((level_number, index), (offset, data.len() as u64));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+
+    use flate2::read::ZlibDecoder;
+    use image::{GenericImageView, ImageBuffer, Rgb};
+
+    use super::*;
+    use super::bigtiff::test_reader::read_ifds;
+
+    fn encode(name: &str, tile_compression: TileCompression) -> Vec<u8> {
+        let destination = temp_dir().join(name);
+        let size = Vec2d { x: 300, y: 200 };
+        let mut encoder = PyramidTiffEncoder::new(destination.clone(), size, tile_compression).unwrap();
+        let image = ImageBuffer::from_pixel(size.x, size.y, Rgb([10u8, 20, 30]));
+        encoder.add_tile(Tile { position: Vec2d { x: 0, y: 0 }, image: DynamicImage::ImageRgb8(image) }).unwrap();
+        encoder.finalize().unwrap();
+        assert!(!destination.with_extension("tiles.part").exists());
+        std::fs::read(&destination).unwrap()
+    }
+
+    #[test]
+    fn test_pyramid_layout() {
+        let data = encode("dezoomify-rs-test-pyramid.ptif", TileCompression::Deflate);
+        let ifds = read_ifds(&data);
+        assert_eq!(ifds.len(), 2);
+        assert_eq!(ifds[0][&tags::IMAGE_WIDTH], vec![300]);
+        assert_eq!(ifds[0][&tags::IMAGE_LENGTH], vec![200]);
+        assert!(!ifds[0].contains_key(&tags::NEW_SUBFILE_TYPE));
+        assert_eq!(ifds[1][&tags::IMAGE_WIDTH], vec![150]);
+        assert_eq!(ifds[1][&tags::NEW_SUBFILE_TYPE], vec![1]);
+        let full_offsets = &ifds[0][&tags::TILE_OFFSETS];
+        let overview_offsets = &ifds[1][&tags::TILE_OFFSETS];
+        assert_eq!(full_offsets.len(), 2);
+        assert_eq!(overview_offsets.len(), 1);
+        // The smallest overview comes first, right after the IFDs
+        assert!(overview_offsets[0] < full_offsets[0]);
+        assert!(full_offsets[0] < full_offsets[1]);
+        assert!(ifds.iter().all(|ifd| ifd[&tags::TILE_BYTE_COUNTS].iter().all(|&c| c > 0)));
+
+        let offset = full_offsets[1] as usize;
+        let len = ifds[0][&tags::TILE_BYTE_COUNTS][1] as usize;
+        let mut pixels = vec![];
+        ZlibDecoder::new(&data[offset..offset + len]).read_to_end(&mut pixels).unwrap();
+        assert_eq!(pixels.len(), (3 * TILE_SIZE * TILE_SIZE) as usize);
+        assert_eq!(&pixels[0..3], &[10, 20, 30]);
+        // The right part of the last tile is outside of the image, and is padded with black pixels
+        assert_eq!(&pixels[3 * 43..3 * 44], &[10, 20, 30]);
+        assert_eq!(&pixels[3 * 44..3 * 45], &[0, 0, 0]);
+    }
+
+    #[test]
+    fn test_pyramid_jpeg() {
+        let data = encode("dezoomify-rs-test-pyramid-jpeg.ptif", TileCompression::Jpeg { quality: 90 });
+        let ifds = read_ifds(&data);
+        assert_eq!(ifds[0][&tags::COMPRESSION], vec![u64::from(compression::JPEG)]);
+        let offset = ifds[1][&tags::TILE_OFFSETS][0] as usize;
+        let len = ifds[1][&tags::TILE_BYTE_COUNTS][0] as usize;
+        let tile = image::load_from_memory(&data[offset..offset + len]).unwrap();
+        assert_eq!(Vec2d::from(tile.dimensions()), Vec2d::square(TILE_SIZE));
+    }
+}
@@ -1,8 +1,9 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::io;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use fixedbitset::FixedBitSet;
 use image::{DynamicImage, GenericImageView, SubImage};
@@ -10,12 +11,18 @@ use image::GenericImage;
 use image::imageops::FilterType;
 use log::{debug, warn};
 
-use crate::{max_size_in_rect, Tile};
+use crate::Tile;
 use crate::errors::image_error_to_io_error;
 use crate::Vec2d;
 
+/// Distinguishes the temporary files of the retilers running in the same process,
+/// for instance when several images are converted at the same time.
+static NEXT_RETILER_ID: AtomicUsize = AtomicUsize::new(0);
+
 pub trait TileSaver {
-    fn save_tile(&self, size: Vec2d, tile: Tile) -> io::Result<()>;
+    /// Saves a finished tile. `size` is the size of the region covered by the tile in the original image,
+    /// and `scale_factor` is the ratio between that size and the size of the tile image.
+    fn save_tile(&self, scale_factor: u32, size: Vec2d, tile: Tile) -> io::Result<()>;
 }
 
 /**
@@ -38,29 +45,40 @@ Every level passes the source tile to it's child when it is done with it.
 pub struct Retiler<T: TileSaver> {
     original_size: Vec2d,
     pub tile_size: Vec2d,
+    /// Number of pixels that each tile shares with its neighbors, at the resolution of the level
+    overlap: u32,
     scale_factor: u32,
     next_level: Option<Box<Retiler<T>>>,
     tiles: HashMap<Vec2d, TmpTile>,
+    /// Tiles that have already been saved. With overlapping tiles, a source tile can cover
+    /// a finished tile again, which must not start a new partial tile that would overwrite it.
+    saved_tiles: HashSet<Vec2d>,
     tile_saver: Arc<T>,
+    id: usize,
 }
 
 struct TmpTile {
     done_pixels: FixedBitSet,
+    /// Where the partially filled tile is stored between two source tiles
+    path: PathBuf,
 }
 
 impl<T: TileSaver> Retiler<T> {
-    pub fn new(size: Vec2d, tile_size: Vec2d, tile_saver: Arc<T>, scale_factor: u32) -> Retiler<T> {
+    pub fn new(size: Vec2d, tile_size: Vec2d, overlap: u32, tile_saver: Arc<T>, scale_factor: u32) -> Retiler<T> {
         let next_level =
             if (size / scale_factor).fits_inside(tile_size) { None } else {
                 let tile_saver = Arc::clone(&tile_saver);
-                let level = Retiler::new(size, tile_size, tile_saver, scale_factor * 2);
+                let level = Retiler::new(size, tile_size, overlap, tile_saver, scale_factor * 2);
                 Some(Box::new(level))
             };
         Retiler {
             original_size: size,
             tile_size: tile_size * scale_factor,
+            overlap,
             next_level,
             tiles: HashMap::new(),
+            saved_tiles: HashSet::new(),
+            id: NEXT_RETILER_ID.fetch_add(1, Ordering::Relaxed),
             tile_saver,
             scale_factor,
         }
@@ -70,9 +88,24 @@ impl<T: TileSaver> Retiler<T> {
         self.original_size / self.scale_factor
     }
 
+    /// Overlap between tiles, in pixels of the original image
+    fn scaled_overlap(&self) -> Vec2d {
+        Vec2d::square(self.overlap * self.scale_factor)
+    }
+
+    /// Position and size in the original image of the region covered by the tile
+    /// at the given position in the tile grid, including its overlap with the neighboring tiles
+    fn tile_region(&self, position: Vec2d) -> (Vec2d, Vec2d) {
+        let top_left = position - self.scaled_overlap();
+        let bottom_right = (position + self.tile_size + self.scaled_overlap()).min(self.original_size);
+        (top_left, bottom_right - top_left)
+    }
+
     fn tile_positions(&self, position: Vec2d, size: Vec2d) -> impl Iterator<Item=Vec2d> {
-        let top_left = (position / self.tile_size) * self.tile_size;
-        let bottom_right = ((position + size).ceil_div(self.tile_size)) * self.tile_size;
+        let top_left = ((position - self.scaled_overlap()) / self.tile_size) * self.tile_size;
+        let bottom_right = (position + size + self.scaled_overlap())
+            .min(self.original_size)
+            .ceil_div(self.tile_size) * self.tile_size;
         let dy = self.tile_size.y as usize;
         let dx = self.tile_size.x as usize;
         (top_left.y..bottom_right.y)
@@ -87,8 +120,8 @@ impl<T: TileSaver> Retiler<T> {
     }
 
     pub fn add_tile(&mut self, tile: &Tile) -> io::Result<()> {
-        let tile_size = self.tile_size;
         let scale_factor = self.scale_factor;
+        let retiler_id = self.id;
         let scaled_size = tile.size().ceil_div(scale_factor);
         let covered_tiles_positions = self.tile_positions(tile.position, tile.size());
         let scaled_tile = if scale_factor == 1 { None } else {
@@ -99,16 +132,17 @@ impl<T: TileSaver> Retiler<T> {
         };
         let scaled_tile = scaled_tile.as_ref().unwrap_or(tile);
         for cur_pos in covered_tiles_positions {
-            let cur_tile_size = max_size_in_rect(cur_pos, tile_size, self.original_size);
+            if self.saved_tiles.contains(&cur_pos) { continue; }
+            let (region_pos, cur_tile_size) = self.tile_region(cur_pos);
             let scaled_tile_size = cur_tile_size.ceil_div(scale_factor);
 
             let tmp_tile = self.tiles.entry(cur_pos)
                 .or_insert_with(|| {
                     debug!("Creating a new partial tile at scale factor {} position {} size {}", scale_factor, cur_pos, cur_tile_size);
-                    TmpTile::new(scaled_tile_size)
+                    TmpTile::new(scaled_tile_size, TmpTile::path(retiler_id, region_pos, scale_factor))
                 });
             let finished = tmp_tile.add_tile(
-                cur_pos,
+                region_pos,
                 cur_tile_size,
                 self.original_size,
                 scale_factor,
@@ -116,6 +150,7 @@ impl<T: TileSaver> Retiler<T> {
             if let Some(tile_img) = finished {
                 self.tile_save(cur_pos, cur_tile_size, tile_img)?;
                 self.tiles.remove(&cur_pos);
+                self.saved_tiles.insert(cur_pos);
             }
         }
 
@@ -129,15 +164,15 @@ impl<T: TileSaver> Retiler<T> {
     /// Add all partially downloaded tiles to the final image
     pub fn finalize(&mut self) {
         for (position, tile) in std::mem::take(&mut self.tiles).into_iter() {
-            let cur_tile_size = max_size_in_rect(position, self.tile_size, self.original_size);
+            let (_, cur_tile_size) = self.tile_region(position);
             warn!("The target tile of size {} at zoom level {} and position {} \
             was not fully covered by source tiles. It misses {} pixels.",
                   cur_tile_size, self.scale_factor, position, tile.missing_pixels());
-            let tmp_tile_path = TmpTile::path(position, self.scale_factor);
-            let result = image::open(&tmp_tile_path)
+            let tmp_tile_path = &tile.path;
+            let result = image::open(tmp_tile_path)
                 .map_err(image_error_to_io_error)
                 .and_then(|image| self.tile_save(position, cur_tile_size, image))
-                .and_then(|()| std::fs::remove_file(&tmp_tile_path));
+                .and_then(|()| std::fs::remove_file(tmp_tile_path));
             if let Err(e) = result {
                 warn!("Additionally, the following error occurred \
                 when trying to add the partial tile to the final image: {}", e)
@@ -149,7 +184,7 @@ impl<T: TileSaver> Retiler<T> {
     }
 
     pub fn tile_save(&self, position: Vec2d, size: Vec2d, image: DynamicImage) -> io::Result<()> {
-        self.tile_saver.save_tile(size, Tile { position, image })
+        self.tile_saver.save_tile(self.scale_factor, size, Tile { position, image })
     }
 
     pub fn level_count(&self) -> u32 {
@@ -160,10 +195,11 @@ impl<T: TileSaver> Retiler<T> {
 }
 
 impl TmpTile {
-    fn new(size: Vec2d) -> TmpTile {
+    fn new(size: Vec2d, path: PathBuf) -> TmpTile {
         let bits = size.area().try_into().expect("Tile size too large");
         TmpTile {
-            done_pixels: FixedBitSet::with_capacity(bits)
+            done_pixels: FixedBitSet::with_capacity(bits),
+            path,
         }
     }
 
@@ -195,7 +231,7 @@ impl TmpTile {
         let bottom_right = tile.bottom_right().min(self_bottom_right) - scaled_self_position;
         let scaled_size = self_size.ceil_div(scale_factor);
 
-        let tmp_tile_path = Self::path(self_position, scale_factor);
+        let tmp_tile_path = self.path.clone();
         debug!("Opening partial tile of size {} at {:?} in order to paste pixels from {} to {}",
                scaled_size, &tmp_tile_path, top_left, bottom_right);
         let mut tile_img = image::open(&tmp_tile_path)
@@ -219,11 +255,12 @@ impl TmpTile {
         }
     }
 
-    fn path(position: Vec2d, scale_factor: u32) -> PathBuf {
+    fn path(retiler_id: usize, position: Vec2d, scale_factor: u32) -> PathBuf {
         let pid = std::process::id();
         let mut path = std::env::temp_dir();
-        path.push(format!("dezoomify_{}_level_{}_position_{}x{}.bmp",
+        path.push(format!("dezoomify_{}_{}_level_{}_position_{}x{}.bmp",
                           pid,
+                          retiler_id,
                           scale_factor,
                           position.x, position.y));
         path
@@ -255,19 +292,19 @@ mod tests {
 
     #[derive(Default)]
     struct TestTileSaver {
-        added: std::cell::RefCell<Vec<(Vec2d, Tile)>>
+        added: std::sync::Mutex<Vec<(Vec2d, Tile)>>
     }
 
     impl TileSaver for TestTileSaver {
-        fn save_tile(&self, size: Vec2d, tile: Tile) -> io::Result<()> {
-            self.added.borrow_mut().push((size, tile));
+        fn save_tile(&self, _scale_factor: u32, size: Vec2d, tile: Tile) -> io::Result<()> {
+            self.added.lock().unwrap().push((size, tile));
             Ok(())
         }
     }
 
     impl TestTileSaver {
         fn get_added(&self) -> Vec<(Vec2d, Tile)> {
-            self.added.borrow().clone()
+            self.added.lock().unwrap().clone()
         }
     }
 
@@ -278,7 +315,7 @@ mod tests {
         let tile_size = Vec2d { x: 2, y: 2 };
 
         let tile_saver = Arc::new(TestTileSaver::default());
-        let mut retiler = Retiler::new(image_size, tile_size, Arc::clone(&tile_saver), 1);
+        let mut retiler = Retiler::new(image_size, tile_size, 0, Arc::clone(&tile_saver), 1);
         retiler.add_tile(&Tile {
             image: plain_image(Vec2d { x: 2, y: 1 }, 64),
             position: Vec2d { x: 0, y: 0 },
@@ -297,4 +334,46 @@ mod tests {
             (Vec2d { x: 2, y: 1 }, Tile { position: Vec2d { x: 0, y: 2 }, image: plain_image(Vec2d { x: 2, y: 1 }, 16) }),
         ]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_retiler_overlap() {
+        init();
+        let image_size = Vec2d { x: 4, y: 1 };
+        let tile_size = Vec2d { x: 2, y: 2 };
+        let tile_saver = Arc::new(TestTileSaver::default());
+        let mut retiler = Retiler::new(image_size, tile_size, 1, Arc::clone(&tile_saver), 1);
+        let source_tile = Tile {
+            image: DynamicImage::ImageLuma8(ImageBuffer::from_raw(4, 1, vec![1, 2, 3, 4]).unwrap()),
+            position: Vec2d { x: 0, y: 0 },
+        };
+        retiler.add_tile(&source_tile).unwrap();
+        retiler.finalize();
+        let added: Vec<_> = tile_saver.get_added().into_iter()
+            .filter(|(size, tile)| tile.image.width() == size.x) // Keep only the first level
+            .map(|(size, tile)| (size, tile.position, tile.image.to_luma().into_raw()))
+            .collect();
+        assert_eq!(added, vec![
+            (Vec2d { x: 3, y: 1 }, Vec2d { x: 0, y: 0 }, vec![1, 2, 3]),
+            (Vec2d { x: 3, y: 1 }, Vec2d { x: 2, y: 0 }, vec![2, 3, 4]),
+        ]);
+    }
+
+    #[test]
+    fn test_retiler_overlap_saves_tiles_once() {
+        init();
+        let tile_saver = Arc::new(TestTileSaver::default());
+        let mut retiler = Retiler::new(Vec2d { x: 8, y: 1 }, Vec2d::square(2), 1, Arc::clone(&tile_saver), 1);
+        // At the second level, the first source tile completes the tile at the origin,
+        // and the second one covers its overlapping border again after rounding
+        for (x, width) in &[(0, 5), (5, 3)] {
+            let image = DynamicImage::ImageLuma8(ImageBuffer::from_pixel(*width, 1, image::Luma([1u8])));
+            retiler.add_tile(&Tile { image, position: Vec2d { x: *x, y: 0 } }).unwrap();
+        }
+        retiler.finalize();
+        let mut saved: Vec<_> = tile_saver.get_added().into_iter().map(|(size, tile)| (size, tile.position)).collect();
+        let count = saved.len();
+        saved.sort_by_key(|&(size, position)| (size.x, size.y, position.x, position.y));
+        saved.dedup();
+        assert_eq!(saved.len(), count, "a tile was saved twice");
+    }
+}
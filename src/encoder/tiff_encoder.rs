@@ -0,0 +1,201 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Seek, Write};
+use std::path::PathBuf;
+
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+use log::debug;
+
+use crate::{Vec2d, ZoomError};
+use crate::tile::Tile;
+
+use super::bigtiff::{self, compression, Ifd, IfdValue, tags};
+use super::Encoder;
+use super::pixel_streamer::PixelStreamer;
+
+/// Approximate size of the uncompressed pixel data in a single strip
+const STRIP_BYTES: usize = 1 << 20;
+
+/// A streaming encoder that writes BigTIFF files, which are not limited in size.
+/// Pixels are written as soon as they are received, in strips of a few rows each.
+pub struct TiffEncoder {
+    pixel_streamer: Option<PixelStreamer<StripWriter<BufWriter<File>>>>,
+    size: Vec2d,
+}
+
+impl TiffEncoder {
+    pub fn new(destination: PathBuf, size: Vec2d, compression: u8) -> Result<Self, ZoomError> {
+        let file = OpenOptions::new().write(true).truncate(true).create(true).open(destination)?;
+        let mut writer = BufWriter::new(file);
+        bigtiff::write_header(&mut writer)?;
+        let row_bytes = 3 * size.x as usize;
+        let rows_per_strip = (STRIP_BYTES / row_bytes.max(1)).max(1);
+        let compression = match compression {
+            0 => None,
+            1..=19 => Some(Compression::fast()),
+            20..=60 => Some(Compression::default()),
+            _ => Some(Compression::best()),
+        };
+        let strip_writer = StripWriter::new(writer, row_bytes * rows_per_strip, compression);
+        let pixel_streamer = Some(PixelStreamer::new(strip_writer, size));
+        Ok(TiffEncoder { pixel_streamer, size })
+    }
+}
+
+impl Encoder for TiffEncoder {
+    fn add_tile(&mut self, tile: Tile) -> io::Result<()> {
+        self.pixel_streamer
+            .as_mut()
+            .expect("tried to add a tile in a finalized image")
+            .add_tile(tile)
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        let mut pixel_streamer = self.pixel_streamer
+            .take().expect("Tried to finalize an image twice");
+        pixel_streamer.finalize()?;
+        let strip_writer = pixel_streamer.into_writer();
+        let rows_per_strip = strip_writer.strip_len / (3 * self.size.x as usize).max(1);
+        let compression = if strip_writer.compression.is_some() { compression::DEFLATE } else { compression::NONE };
+        let (mut writer, strips) = strip_writer.finish()?;
+
+        let mut ifd = Ifd::default();
+        ifd.set(tags::IMAGE_WIDTH, IfdValue::Long(vec![self.size.x]));
+        ifd.set(tags::IMAGE_LENGTH, IfdValue::Long(vec![self.size.y]));
+        ifd.set(tags::BITS_PER_SAMPLE, IfdValue::Short(vec![8, 8, 8]));
+        ifd.set(tags::COMPRESSION, IfdValue::Short(vec![compression]));
+        ifd.set(tags::PHOTOMETRIC_INTERPRETATION, IfdValue::Short(vec![bigtiff::PHOTOMETRIC_RGB]));
+        ifd.set(tags::SAMPLES_PER_PIXEL, IfdValue::Short(vec![3]));
+        ifd.set(tags::ROWS_PER_STRIP, IfdValue::Long(vec![rows_per_strip as u32]));
+        ifd.set(tags::PLANAR_CONFIGURATION, IfdValue::Short(vec![1]));
+        ifd.set(tags::SOFTWARE, IfdValue::Ascii("dezoomify-rs".into()));
+        let (offsets, byte_counts) = strips.into_iter().unzip();
+        ifd.set(tags::STRIP_OFFSETS, IfdValue::Long8(offsets));
+        ifd.set(tags::STRIP_BYTE_COUNTS, IfdValue::Long8(byte_counts));
+
+        let ifd_offset = writer.seek(io::SeekFrom::End(0))?;
+        debug!("Writing the TIFF image file directory at offset {}", ifd_offset);
+        ifd.write(&mut writer, ifd_offset)?;
+        bigtiff::set_first_ifd(&mut writer, ifd_offset)?;
+        writer.flush()
+    }
+
+    fn size(&self) -> Vec2d {
+        self.size
+    }
+}
+
+/// Splits the pixel data it receives into strips of a fixed size,
+/// compresses them, and keeps track of where each strip was written
+struct StripWriter<W: Write> {
+    writer: W,
+    position: u64,
+    strip_len: usize,
+    buffer: Vec<u8>,
+    compression: Option<Compression>,
+    /// Offset and length of each written strip
+    strips: Vec<(u64, u64)>,
+}
+
+impl<W: Write> StripWriter<W> {
+    fn new(writer: W, strip_len: usize, compression: Option<Compression>) -> Self {
+        StripWriter {
+            writer,
+            position: bigtiff::HEADER_SIZE,
+            strip_len,
+            buffer: Vec::with_capacity(strip_len),
+            compression,
+            strips: vec![],
+        }
+    }
+
+    fn write_strip(&mut self) -> io::Result<()> {
+        let data = if let Some(compression) = self.compression {
+            let mut encoder = ZlibEncoder::new(Vec::new(), compression);
+            encoder.write_all(&self.buffer)?;
+            encoder.finish()?
+        } else {
+            std::mem::take(&mut self.buffer)
+        };
+        self.writer.write_all(&data)?;
+        self.strips.push((self.position, data.len() as u64));
+        self.position += data.len() as u64;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Writes the last, possibly incomplete, strip
+    fn finish(mut self) -> io::Result<(W, Vec<(u64, u64)>)> {
+        if !self.buffer.is_empty() { self.write_strip()?; }
+        Ok((self.writer, self.strips))
+    }
+}
+
+impl<W: Write> Write for StripWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.strip_len - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        if self.buffer.len() == self.strip_len { self.write_strip()?; }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Incomplete strips are written only when finishing the image
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::io::Read;
+
+    use flate2::read::ZlibDecoder;
+    use image::{DynamicImage, ImageBuffer};
+
+    use super::*;
+    use super::bigtiff::test_reader::read_ifds;
+
+    fn encode(name: &str, compression: u8) -> (Vec<u8>, Vec<std::collections::BTreeMap<u16, Vec<u64>>>) {
+        let destination = temp_dir().join(name);
+        let mut encoder = TiffEncoder::new(destination.clone(), Vec2d { x: 2, y: 2 }, compression).unwrap();
+        // The bottom tile is added first
+        encoder.add_tile(Tile {
+            position: Vec2d { x: 0, y: 1 },
+            image: DynamicImage::ImageRgb8(ImageBuffer::from_raw(2, 1, vec![7, 8, 9, 10, 11, 12]).unwrap()),
+        }).unwrap();
+        encoder.add_tile(Tile {
+            position: Vec2d { x: 1, y: 0 },
+            image: DynamicImage::ImageRgb8(ImageBuffer::from_raw(1, 1, vec![4, 5, 6]).unwrap()),
+        }).unwrap();
+        encoder.finalize().unwrap();
+        let data = std::fs::read(&destination).unwrap();
+        let ifds = read_ifds(&data);
+        (data, ifds)
+    }
+
+    #[test]
+    fn test_tiff_uncompressed() {
+        let (data, ifds) = encode("dezoomify-rs-tiff-test.tiff", 0);
+        assert_eq!(ifds.len(), 1);
+        let ifd = &ifds[0];
+        assert_eq!(ifd[&tags::IMAGE_WIDTH], vec![2]);
+        assert_eq!(ifd[&tags::IMAGE_LENGTH], vec![2]);
+        assert_eq!(ifd[&tags::COMPRESSION], vec![u64::from(compression::NONE)]);
+        assert_eq!(ifd[&tags::STRIP_OFFSETS], vec![bigtiff::HEADER_SIZE]);
+        assert_eq!(ifd[&tags::STRIP_BYTE_COUNTS], vec![12]);
+        assert_eq!(&data[16..28], &[0, 0, 0, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+    }
+
+    #[test]
+    fn test_tiff_deflate() {
+        let (data, ifds) = encode("dezoomify-rs-tiff-test-deflate.tiff", 50);
+        let ifd = &ifds[0];
+        assert_eq!(ifd[&tags::COMPRESSION], vec![u64::from(compression::DEFLATE)]);
+        let offset = ifd[&tags::STRIP_OFFSETS][0] as usize;
+        let len = ifd[&tags::STRIP_BYTE_COUNTS][0] as usize;
+        let mut pixels = vec![];
+        ZlibDecoder::new(&data[offset..offset + len]).read_to_end(&mut pixels).unwrap();
+        assert_eq!(pixels, vec![0, 0, 0, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+    }
+}
@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 /**
 Used to receive tiles asynchronously and provide them to the encoder
@@ -7,17 +7,38 @@ use log::debug;
 use tokio::sync::mpsc;
 
 use crate::{Vec2d, ZoomError};
-use crate::encoder::{Encoder, encoder_for_name};
+use crate::encoder::{Encoder, encoder_for_name, OutputImage, TilingOptions};
+use crate::encoder::canvas::Canvas;
+use crate::encoder::equirectangular::{equirectangular_size, EquirectangularEncoder};
 use crate::tile::Tile;
 
+/// Where the image is written
+pub enum Output {
+    /// A file, whose extension determines its format
+    File { destination: PathBuf, compression: u8, tiling: TilingOptions },
+    /// An image in memory
+    Memory(OutputImage),
+}
+
+impl Output {
+    fn path(&self) -> Option<&Path> {
+        match self {
+            Output::File { destination, .. } => Some(destination),
+            Output::Memory(_) => None,
+        }
+    }
+}
+
 /// Data structure used to store tiles until the final image size is known
 pub enum TileBuffer {
     Buffering {
-        destination: PathBuf,
+        output: Output,
         buffer: Vec<Tile>,
-        compression: u8,
+        equirectangular: bool,
     },
     Writing {
+        destination: Option<PathBuf>,
+        size: Vec2d,
         tile_sender: mpsc::Sender<TileBufferMsg>,
         error_receiver: mpsc::Receiver<std::io::Error>,
     },
@@ -27,22 +48,44 @@ impl TileBuffer {
     /// Create an encoder for an image of the given size at the path
     /// Errors out if the encoder cannot create files with the given extension
     /// or at the given size
-    pub async fn new(destination: PathBuf, compression: u8) -> Result<Self, ZoomError> {
+    pub async fn new(destination: PathBuf, compression: u8, tiling: TilingOptions) -> Result<Self, ZoomError> {
         Ok(TileBuffer::Buffering {
-            destination,
+            output: Output::File { destination, compression, tiling },
             buffer: vec![],
-            compression,
+            equirectangular: false,
         })
     }
 
+    /// Create a buffer that assembles the image in memory.
+    /// The image is stored in `image` when the buffer is finalized.
+    pub fn in_memory(image: OutputImage) -> Self {
+        TileBuffer::Buffering { output: Output::Memory(image), buffer: vec![], equirectangular: false }
+    }
+
+    /// The tiles are the six faces of a cube panorama, side by side.
+    /// Reproject them into a single equirectangular image.
+    pub fn reproject_cube(&mut self) {
+        if let TileBuffer::Buffering { equirectangular, .. } = self {
+            *equirectangular = true;
+        }
+    }
+
     pub async fn set_size(&mut self, size: Vec2d) -> Result<(), ZoomError> {
         let next_state = match self {
-            TileBuffer::Buffering { buffer, destination, compression } => {
-                debug!("Creating a tile writer for an image of size {}", size);
-                let mut e = encoder_for_name(destination.clone(), size, *compression)?;
+            TileBuffer::Buffering { buffer, output, equirectangular } => {
+                let output_size = if *equirectangular { equirectangular_size(size) } else { size };
+                debug!("Creating a tile writer for an image of size {}", output_size);
+                let mut e = match output {
+                    Output::File { destination, compression, tiling } =>
+                        encoder_for_name(destination.clone(), output_size, *compression, *tiling)?,
+                    Output::Memory(image) => Box::new(Canvas::in_memory(output_size, image.clone())),
+                };
+                if *equirectangular {
+                    e = Box::new(EquirectangularEncoder::new(e, size));
+                }
                 debug!("Adding buffered tiles: {:?}", buffer);
                 for tile in buffer.drain(..) { e.add_tile(tile)?; }
-                buffer_tiles(e).await
+                buffer_tiles(output.path().map(PathBuf::from), output_size, e).await
             }
             TileBuffer::Writing { .. } => unreachable!("The size of the image can be set only once")
         };
@@ -50,13 +93,29 @@ impl TileBuffer {
         Ok(())
     }
 
+    /// Path of the image being created, if it is not created in memory
+    pub fn destination(&self) -> Option<&Path> {
+        match self {
+            TileBuffer::Buffering { output, .. } => output.path(),
+            TileBuffer::Writing { destination, .. } => destination.as_deref(),
+        }
+    }
+
+    /// Size of the image, once it is known
+    pub fn size(&self) -> Option<Vec2d> {
+        match self {
+            TileBuffer::Buffering { .. } => None,
+            TileBuffer::Writing { size, .. } => Some(*size),
+        }
+    }
+
     /// Add a tile to the image
     pub async fn add_tile(&mut self, tile: Tile) -> Result<(), ZoomError> {
         match self {
             TileBuffer::Buffering { buffer, .. } => {
                 buffer.push(tile)
             }
-            TileBuffer::Writing { tile_sender, error_receiver } => {
+            TileBuffer::Writing { tile_sender, error_receiver, .. } => {
                 if let Ok(e) = error_receiver.try_recv() { return Err(e.into()) }
                 tile_sender.send(TileBufferMsg::AddTile(tile))
                     .await.expect("The tile writer ended unexpectedly");
@@ -76,7 +135,7 @@ impl TileBuffer {
         }
         let (tile_sender, error_receiver) = match self {
             TileBuffer::Buffering { .. } => unreachable!("Just set the size"),
-            TileBuffer::Writing { tile_sender, error_receiver } => (tile_sender, error_receiver)
+            TileBuffer::Writing { tile_sender, error_receiver, .. } => (tile_sender, error_receiver)
         };
         tile_sender.send(TileBufferMsg::Close).await?;
         debug!("Waiting for the image encoding task to finish");
@@ -91,7 +150,7 @@ pub enum TileBufferMsg {
     Close,
 }
 
-async fn buffer_tiles(mut encoder: Box<dyn Encoder>) -> TileBuffer {
+async fn buffer_tiles(destination: Option<PathBuf>, size: Vec2d, mut encoder: Box<dyn Encoder>) -> TileBuffer {
     let (tile_sender, mut tile_receiver) = mpsc::channel(1024);
     let (mut error_sender, error_receiver) = mpsc::channel(1);
     tokio::spawn(async move {
@@ -113,6 +172,8 @@ async fn buffer_tiles(mut encoder: Box<dyn Encoder>) -> TileBuffer {
         }
     });
     TileBuffer::Writing {
+        destination,
+        size,
         tile_sender,
         error_receiver,
     }
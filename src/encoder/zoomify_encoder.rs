@@ -0,0 +1,165 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use image::{DynamicImage, GenericImageView, ImageOutputFormat, RgbImage};
+use log::debug;
+
+use crate::{max_size_in_rect, Vec2d, ZoomError};
+use crate::encoder::retiler::{Retiler, TileSaver};
+use crate::errors::image_error_to_io_error;
+use crate::tile::Tile;
+use crate::zoomify::image_properties::{ImageProperties, ZoomLevelInfo};
+
+use super::Encoder;
+
+/// Zoomify tiles are always 256x256
+const TILE_SIZE: u32 = 256;
+
+/**
+An encoder that re-tiles the image in the zoomify format.
+It creates a folder that contains an `ImageProperties.xml` file
+and the tiles, in folders called `TileGroupN` that contain at most 256 tiles each.
+The levels are computed by the same code as the one used by the zoomify dezoomer,
+so that the output can always be read back. Like the official converter,
+that code rounds the size of the smaller levels up to an even number of pixels,
+so the tiles on the right and bottom edges are padded to match.
+**/
+pub struct ZoomifyEncoder {
+    retiler: Retiler<ZoomifyTileSaver>,
+    root_path: PathBuf,
+    image_properties: ImageProperties,
+}
+
+impl ZoomifyEncoder {
+    pub fn new(destination: PathBuf, size: Vec2d, quality: u8) -> Result<Self, ZoomError> {
+        let _ = std::fs::remove_file(&destination);
+        debug!("Creating zoomify directory at {:?}", &destination);
+        std::fs::create_dir_all(&destination)?;
+        let image_properties = ImageProperties::new(size, TILE_SIZE);
+        let tile_saver = ZoomifyTileSaver {
+            root_path: destination.clone(),
+            levels: image_properties.levels(),
+            quality,
+        };
+        let tile_size = Vec2d::square(TILE_SIZE);
+        Ok(ZoomifyEncoder {
+            retiler: Retiler::new(size, tile_size, 0, Arc::new(tile_saver), 1),
+            root_path: destination,
+            image_properties,
+        })
+    }
+}
+
+impl Encoder for ZoomifyEncoder {
+    fn add_tile(&mut self, tile: Tile) -> io::Result<()> {
+        self.retiler.add_tile(&tile)
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        self.retiler.finalize();
+        let properties_path = self.root_path.join("ImageProperties.xml");
+        debug!("Writing zoomify metadata to {:?}", properties_path);
+        OpenOptions::new().write(true).truncate(true).create(true)
+            .open(properties_path)?
+            .write_all(self.image_properties.to_xml().as_bytes())
+    }
+
+    fn size(&self) -> Vec2d {
+        self.retiler.size()
+    }
+}
+
+struct ZoomifyTileSaver {
+    root_path: PathBuf,
+    /// Zoomify levels, from the smallest to the largest
+    levels: Vec<ZoomLevelInfo>,
+    quality: u8,
+}
+
+impl TileSaver for ZoomifyTileSaver {
+    fn save_tile(&self, scale_factor: u32, _size: Vec2d, tile: Tile) -> io::Result<()> {
+        let levels_from_largest = scale_factor.trailing_zeros() as usize;
+        if levels_from_largest >= self.levels.len() {
+            debug!("Ignoring tile at scale factor {}, which has no corresponding zoomify level", scale_factor);
+            return Ok(());
+        }
+        let level = self.levels.len() - 1 - levels_from_largest;
+        let level_info = &self.levels[level];
+        let pos = tile.position / (TILE_SIZE * scale_factor);
+        let level_tiles = level_info.size.ceil_div(level_info.tile_size);
+        if pos.x >= level_tiles.x || pos.y >= level_tiles.y {
+            debug!("Ignoring tile {} at level {}, which is outside of the zoomify level", pos, level);
+            return Ok(());
+        }
+        let dir = self.root_path.join(format!("TileGroup{}", level_info.tile_group(pos)));
+        let image_path = dir.join(format!("{}-{}-{}.jpg", level, pos.x, pos.y));
+        debug!("Writing tile to {:?}", image_path);
+        std::fs::create_dir_all(&dir)?;
+        let size = max_size_in_rect(pos * level_info.tile_size, level_info.tile_size, level_info.size);
+        let image = extend_to_size(tile.image, size);
+        let file = &mut BufWriter::new(File::create(&image_path)?);
+        image.write_to(file, ImageOutputFormat::Jpeg(self.quality)).map_err(image_error_to_io_error)
+    }
+}
+
+/// Resizes the canvas of the image to the given size, repeating its last row and column
+fn extend_to_size(image: DynamicImage, size: Vec2d) -> DynamicImage {
+    let Vec2d { x: width, y: height } = image.dimensions().into();
+    if (width, height) == (size.x, size.y) || width == 0 || height == 0 { return image; }
+    let rgb = image.to_rgb();
+    DynamicImage::ImageRgb8(RgbImage::from_fn(size.x, size.y, |x, y| {
+        *rgb.get_pixel(x.min(width - 1), y.min(height - 1))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, GenericImageView, ImageBuffer, Rgb};
+
+    use super::*;
+
+    #[test]
+    fn test_zoomify_encoder() {
+        let destination = std::env::temp_dir().join("dezoomify-rs-test-zoomify-encoder.zoomify");
+        let _ = std::fs::remove_dir_all(&destination);
+        let size = Vec2d { x: 600, y: 300 };
+        let mut encoder = ZoomifyEncoder::new(destination.clone(), size, 90).unwrap();
+        let image = ImageBuffer::from_pixel(size.x, size.y, Rgb([200u8, 100, 0]));
+        encoder.add_tile(Tile { position: Vec2d { x: 0, y: 0 }, image: DynamicImage::ImageRgb8(image) }).unwrap();
+        encoder.finalize().unwrap();
+
+        let properties = std::fs::read_to_string(destination.join("ImageProperties.xml")).unwrap();
+        // Levels: 600x300 (3x2 tiles), 300x150 (2x1 tiles), 150x76 (1 tile)
+        assert!(properties.contains(r#"WIDTH="600" HEIGHT="300" NUMTILES="9""#));
+        let parsed: ImageProperties = serde_xml_rs::from_str(&properties).unwrap();
+        let levels = parsed.levels();
+        assert_eq!(levels.len(), 3);
+        for (z, level) in levels.iter().enumerate() {
+            let grid = level.size.ceil_div(level.tile_size);
+            for y in 0..grid.y {
+                for x in 0..grid.x {
+                    let pos = Vec2d { x, y };
+                    let name = format!("{}-{}-{}.jpg", z, x, y);
+                    let path = destination.join(format!("TileGroup{}", level.tile_group(pos))).join(&name);
+                    let tile = image::open(&path).unwrap_or_else(|e| panic!("{}: {}", name, e));
+                    let expected = max_size_in_rect(pos * level.tile_size, level.tile_size, level.size);
+                    assert_eq!(Vec2d::from(tile.dimensions()), expected, "size of {}", name);
+                }
+            }
+        }
+        let tile = |name: &str| image::open(destination.join("TileGroup0").join(name)).unwrap();
+        assert_eq!(tile("0-0-0.jpg").dimensions(), (150, 76));
+        assert_eq!(tile("1-1-0.jpg").dimensions(), (44, 150));
+        assert_eq!(tile("2-2-1.jpg").dimensions(), (88, 44));
+    }
+
+    #[test]
+    fn test_extend_to_size() {
+        let image = DynamicImage::ImageRgb8(ImageBuffer::from_fn(2, 1, |x, _| Rgb([x as u8, 0, 0])));
+        let extended = extend_to_size(image, Vec2d { x: 3, y: 2 }).to_rgb();
+        let pixels: Vec<u8> = extended.pixels().map(|p| p.0[0]).collect();
+        assert_eq!(pixels, vec![0, 1, 1, 0, 1, 1]);
+    }
+}
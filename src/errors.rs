@@ -1,9 +1,12 @@
 use std::error::Error;
+use std::path::PathBuf;
 
 use reqwest::{self, header};
 use tokio::sync::mpsc::error::SendError;
 use crate::encoder::tile_buffer::TileBufferMsg;
+use crate::dezoomer::ImageReference;
 use custom_error::custom_error;
+use crate::Vec2d;
 
 custom_error! {
     pub ZoomError
@@ -11,9 +14,14 @@ custom_error! {
     Dezoomer{source: DezoomerError} = "Dezoomer error: {source}",
     NoLevels = "A zoomable image was found, but it did not contain any zoom level",
     NoTile = "Could not get any tile for the image",
-    PartialDownload{successful_tiles: u64, total_tiles: u64} =
-        "Only {successful_tiles} tiles out of {total_tiles} could be downloaded. \
-        The resulting image was still created.",
+    /// `destination` and `size` describe the image that was created despite the missing tiles
+    PartialDownload{successful_tiles: u64, total_tiles: u64, destination: Option<PathBuf>, size: Option<Vec2d>} =
+        @{ format!("Only {} tiles out of {} could be downloaded. The resulting image was still created.",
+                   successful_tiles, total_tiles) },
+    PartialImageList{successful_images: usize, total_images: usize} =
+        "Only {successful_images} images out of {total_images} could be downloaded.",
+    EmptyImageSelection{available_images: usize} =
+        "None of the selected images exist. The input contains {available_images} images.",
     Image{source: image::ImageError} = "invalid image error: {source}",
     PostProcessing{source: Box<dyn Error>} = "unable to process the downloaded tile: {source}",
     Io{source: std::io::Error} = "Input/Output error: {source}",
@@ -31,6 +39,56 @@ custom_error! {
     BufferToImage{source: BufferToImageError} = "{}",
     WriteError{source: SendError<TileBufferMsg>} = "Unable to write tile {:?}",
     PngError{source: png::EncodingError} = "PNG encoding error: {}",
+    RegionNeedsSize = "A region in percent can only be used when the size of the image is known. \
+                       Use a region in pixels instead.",
+    EmptyRegion{region: String} = "The region {region} does not contain any pixel of the image",
+    InvalidBatchLine{line: usize, message: String} = "Invalid batch file at line {line}: {message}",
+    NoLevelSelected = "No zoom level was selected",
+    NoCubeLevel = "The image is not a cube panorama, so it cannot be reprojected",
+    InvalidProxy{proxy: String, message: String} = "Invalid proxy '{proxy}': {message}",
+    InvalidUrl{url: String, message: String} = "Invalid url '{url}': {message}",
+    TooManyRedirects{url: String} = "Too many redirects while loading {url}",
+    InvalidCertificate{path: String, message: String} = "Unable to load the certificate {path}: {message}",
+    InvalidCookieFile{path: String, line: usize, message: String} =
+        "Invalid cookie file {path} at line {line}: {message}",
+}
+
+impl ZoomError {
+    /// Name of the error variant, for machine-readable error reports
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ZoomError::Networking { .. } => "Networking",
+            ZoomError::Dezoomer { .. } => "Dezoomer",
+            ZoomError::NoLevels => "NoLevels",
+            ZoomError::NoTile => "NoTile",
+            ZoomError::PartialDownload { .. } => "PartialDownload",
+            ZoomError::PartialImageList { .. } => "PartialImageList",
+            ZoomError::EmptyImageSelection { .. } => "EmptyImageSelection",
+            ZoomError::Image { .. } => "Image",
+            ZoomError::PostProcessing { .. } => "PostProcessing",
+            ZoomError::Io { .. } => "Io",
+            ZoomError::Yaml { .. } => "Yaml",
+            ZoomError::TileCopyError { .. } => "TileCopyError",
+            ZoomError::MalformedTileStr { .. } => "MalformedTileStr",
+            ZoomError::NoSuchDezoomer { .. } => "NoSuchDezoomer",
+            ZoomError::InvalidHeaderName { .. } => "InvalidHeaderName",
+            ZoomError::InvalidHeaderValue { .. } => "InvalidHeaderValue",
+            ZoomError::AsyncError { .. } => "AsyncError",
+            ZoomError::BufferToImage { .. } => "BufferToImage",
+            ZoomError::WriteError { .. } => "WriteError",
+            ZoomError::PngError { .. } => "PngError",
+            ZoomError::RegionNeedsSize => "RegionNeedsSize",
+            ZoomError::EmptyRegion { .. } => "EmptyRegion",
+            ZoomError::InvalidBatchLine { .. } => "InvalidBatchLine",
+            ZoomError::NoLevelSelected => "NoLevelSelected",
+            ZoomError::NoCubeLevel => "NoCubeLevel",
+            ZoomError::InvalidProxy { .. } => "InvalidProxy",
+            ZoomError::InvalidUrl { .. } => "InvalidUrl",
+            ZoomError::TooManyRedirects { .. } => "TooManyRedirects",
+            ZoomError::InvalidCertificate { .. } => "InvalidCertificate",
+            ZoomError::InvalidCookieFile { .. } => "InvalidCookieFile",
+        }
+    }
 }
 
 custom_error! {
@@ -41,6 +99,11 @@ custom_error! {
 
 custom_error! {pub DezoomerError
     NeedsData{uri: String}           = "Need to download data from {uri}",
+    /// Like `NeedsData`, for an image tile that may not exist.
+    /// The contents given back to the dezoomer are empty when the tile is missing.
+    NeedsTile{uri: String}           = "Need to know whether the tile {uri} exists",
+    MultipleImages{images: Vec<ImageReference>} =
+        @{ format!("The input contains {} images", images.len()) },
     WrongDezoomer{name:&'static str} = "The '{name}' dezoomer cannot handle this URI",
     Other{source: Box<dyn Error>}    = "Unable to create the dezoomer: {source}"
 }
@@ -0,0 +1,405 @@
+use std::collections::{HashMap, VecDeque};
+
+use custom_error::custom_error;
+use log::{debug, info};
+use serde::Deserialize;
+
+use crate::auto::AutoDezoomer;
+use crate::dezoomer::*;
+
+/// A dezoomer for HTTP Archive (HAR) files, exported from the network panel of the browser developer tools.
+/// It finds the metadata file of the zoomable image in the recorded requests,
+/// and uses the other dezoomers to read it.
+/// The headers and cookies of the recorded request are reused to download the tiles.
+#[derive(Default)]
+pub struct HarDezoomer {
+    replay: Option<Replay>,
+}
+
+custom_error! {pub HarError
+    Json{source: serde_json::Error} = "Invalid HAR file: {source}",
+    NoImage{requests: usize} = "None of the {requests} requests recorded in the HAR file \
+                                 is the metadata file of a zoomable image",
+}
+
+impl From<HarError> for DezoomerError {
+    fn from(err: HarError) -> Self {
+        DezoomerError::Other { source: err.into() }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Har {
+    log: HarLog,
+}
+
+#[derive(Debug, Deserialize)]
+struct HarLog {
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Entry {
+    request: Request,
+    #[serde(default)]
+    response: Response,
+}
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    url: String,
+    #[serde(default)]
+    headers: Vec<NameValue>,
+    #[serde(default)]
+    cookies: Vec<NameValue>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Response {
+    #[serde(default)]
+    status: u16,
+    #[serde(default)]
+    content: Content,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Content {
+    #[serde(rename = "mimeType", default)]
+    mime_type: String,
+    text: Option<String>,
+    encoding: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NameValue {
+    name: String,
+    value: String,
+}
+
+/// Headers that describe a single request or connection, and should not be sent again
+const SKIPPED_HEADERS: &[&str] = &[
+    "host", "content-length", "connection", "accept-encoding", "range",
+    "if-none-match", "if-modified-since", "upgrade-insecure-requests",
+];
+
+impl Entry {
+    fn is_successful(&self) -> bool {
+        // Requests that were recorded without a response have a status of 0
+        self.response.status == 0 || (200..300).contains(&self.response.status)
+    }
+
+    /// The body of the response, when it was recorded
+    fn contents(&self) -> Option<Vec<u8>> {
+        let content = &self.response.content;
+        let text = content.text.as_ref()?;
+        match content.encoding.as_deref() {
+            Some("base64") => base64::decode(text).ok(),
+            _ => Some(text.as_bytes().to_vec()),
+        }
+    }
+
+    /// Whether the response can be the metadata of a zoomable image, and not a tile or a script
+    fn may_be_metadata(&self) -> bool {
+        let mime = self.response.content.mime_type.to_ascii_lowercase();
+        let skipped = ["image/", "video/", "audio/", "font/", "text/css", "javascript"];
+        self.is_successful()
+            && !self.request.url.starts_with("data:")
+            && !skipped.iter().any(|s| mime.contains(s))
+    }
+
+    /// The headers of the request, to send again with the requests for the image
+    fn headers(&self) -> HashMap<String, String> {
+        let mut headers: HashMap<String, String> = self.request.headers.iter()
+            .filter(|h| !h.name.starts_with(':'))
+            .filter(|h| !SKIPPED_HEADERS.contains(&h.name.to_ascii_lowercase().as_str()))
+            .map(|h| (h.name.clone(), h.value.clone()))
+            .collect();
+        let has_cookie_header = headers.keys().any(|k| k.eq_ignore_ascii_case("cookie"));
+        if !has_cookie_header && !self.request.cookies.is_empty() {
+            let cookies = self.request.cookies.iter().map(|c| format!("{}={}", c.name, c.value));
+            headers.insert("Cookie".into(), cookies.collect::<Vec<_>>().join("; "));
+        }
+        headers
+    }
+}
+
+/// The state of the dezoomer after it read the HAR file
+struct Replay {
+    har_uri: String,
+    entries: Vec<Entry>,
+    /// Requests that were recorded without their response, and have to be sent again
+    candidates: VecDeque<usize>,
+    /// The request that is being sent again, and the dezoomer that will read its response
+    current: Option<(usize, AutoDezoomer)>,
+}
+
+impl Replay {
+    fn recorded_contents(&self, uri: &str) -> Option<Vec<u8>> {
+        self.entries.iter()
+            .filter(|e| e.request.url == uri && e.is_successful())
+            .find_map(Entry::contents)
+    }
+
+    /// Gives the input to the dezoomer, and then the recorded responses of the requests it makes
+    fn dezoom_recorded(&self, dezoomer: &mut dyn Dezoomer, input: DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
+        let mut input = input;
+        // Protects against dezoomers that keep requesting the same files
+        for _ in 0..16 {
+            match dezoomer.zoom_levels(&input) {
+                Err(DezoomerError::NeedsData { uri }) => match self.recorded_contents(&uri) {
+                    Some(contents) => input = DezoomerInput { uri, contents: Some(contents) },
+                    None => return Err(DezoomerError::NeedsData { uri }),
+                },
+                result => return result,
+            }
+        }
+        Err(DezoomerError::NeedsData { uri: input.uri })
+    }
+
+    fn with_headers(&self, entry: usize, levels: ZoomLevels) -> ZoomLevels {
+        info!("Found a zoomable image in the HAR file at {}", self.entries[entry].request.url);
+        let headers = self.entries[entry].headers();
+        levels.into_iter()
+            .map(|level| Box::new(HarLevel { level, headers: headers.clone() }) as ZoomLevel)
+            .collect()
+    }
+
+    /// Finds a zoomable image in the responses that were recorded in the HAR file.
+    /// When a recorded response refers to a file that was not recorded, this file is requested.
+    fn find_recorded(&mut self) -> Option<Result<ZoomLevels, DezoomerError>> {
+        let mut pending = None;
+        for (i, entry) in self.entries.iter().enumerate() {
+            if !entry.may_be_metadata() { continue; }
+            let contents = match entry.contents() {
+                Some(contents) => contents,
+                None => continue,
+            };
+            let input = DezoomerInput { uri: entry.request.url.clone(), contents: Some(contents) };
+            let mut dezoomer = AutoDezoomer::default();
+            match self.dezoom_recorded(&mut dezoomer, input) {
+                Ok(levels) => return Some(Ok(self.with_headers(i, levels))),
+                Err(e @ DezoomerError::MultipleImages { .. }) => return Some(Err(e)),
+                Err(DezoomerError::NeedsData { uri }) if pending.is_none() => pending = Some((i, dezoomer, uri)),
+                Err(e) => debug!("No zoomable image in the recorded response of {}: {}", entry.request.url, e),
+            }
+        }
+        let (entry, dezoomer, uri) = pending?;
+        self.current = Some((entry, dezoomer));
+        Some(Err(DezoomerError::NeedsData { uri }))
+    }
+
+    /// Requests that were recorded without their response, and that some dezoomer may be able to read
+    fn find_candidates(&mut self) {
+        self.candidates = self.entries.iter().enumerate()
+            .filter(|(_, entry)| entry.may_be_metadata() && entry.contents().is_none())
+            .filter(|(_, entry)| {
+                let input = DezoomerInput { uri: entry.request.url.clone(), contents: None };
+                match AutoDezoomer::default().zoom_levels(&input) {
+                    Err(DezoomerError::NeedsData { .. }) => true,
+                    result => result.is_ok(),
+                }
+            })
+            .map(|(i, _)| i)
+            .collect();
+        debug!("{} requests of the HAR file have to be sent again", self.candidates.len());
+    }
+
+    /// Asks for the response of the next request that may contain a zoomable image
+    fn next_candidate(&mut self) -> Result<ZoomLevels, DezoomerError> {
+        let entry = self.candidates.pop_front().ok_or(HarError::NoImage { requests: self.entries.len() })?;
+        let uri = self.entries[entry].request.url.clone();
+        self.current = Some((entry, AutoDezoomer::default()));
+        Err(DezoomerError::NeedsData { uri })
+    }
+
+    /// Reads the response of a request that was sent again
+    fn replayed(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
+        let (entry, mut dezoomer) = match self.current.take() {
+            Some(current) => current,
+            None => return self.next_candidate(),
+        };
+        let input = DezoomerInput { uri: data.uri.clone(), contents: data.contents.clone() };
+        match self.dezoom_recorded(&mut dezoomer, input) {
+            Ok(levels) => Ok(self.with_headers(entry, levels)),
+            Err(e @ DezoomerError::NeedsData { .. }) | Err(e @ DezoomerError::NeedsTile { .. }) => {
+                self.current = Some((entry, dezoomer));
+                Err(e)
+            }
+            Err(e @ DezoomerError::MultipleImages { .. }) => Err(e),
+            Err(e) => {
+                debug!("No zoomable image at {}: {}", self.entries[entry].request.url, e);
+                self.next_candidate()
+            }
+        }
+    }
+}
+
+impl Dezoomer for HarDezoomer {
+    fn name(&self) -> &'static str {
+        "har"
+    }
+
+    fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
+        if let Some(replay) = &mut self.replay {
+            if data.uri != replay.har_uri {
+                return replay.replayed(data);
+            }
+        }
+        self.assert(data.uri.to_ascii_lowercase().ends_with(".har"))?;
+        let DezoomerInputWithContents { uri, contents } = data.with_contents()?;
+        let har: Har = serde_json::from_slice(contents).map_err(HarError::from)?;
+        info!("Found {} requests in the HAR file", har.log.entries.len());
+        let mut replay = Replay {
+            har_uri: uri.to_string(),
+            entries: har.log.entries,
+            candidates: VecDeque::new(),
+            current: None,
+        };
+        replay.find_candidates();
+        let result = replay.find_recorded().unwrap_or_else(|| replay.next_candidate());
+        self.replay = Some(replay);
+        result
+    }
+
+    fn http_headers(&self) -> HashMap<String, String> {
+        match &self.replay {
+            Some(Replay { current: Some((entry, _)), entries, .. }) => entries[*entry].headers(),
+            _ => HashMap::new(),
+        }
+    }
+}
+
+/// A zoom level found in a HAR file, downloaded with the headers of the recorded request
+#[derive(Debug)]
+struct HarLevel {
+    level: ZoomLevel,
+    headers: HashMap<String, String>,
+}
+
+impl TileProvider for HarLevel {
+    fn next_tiles(&mut self, previous: Option<TileFetchResult>) -> Vec<TileReference> {
+        self.level.next_tiles(previous)
+    }
+
+    fn post_process_fn(&self) -> PostProcessFn {
+        self.level.post_process_fn()
+    }
+
+    fn name(&self) -> String {
+        self.level.name()
+    }
+
+    fn title(&self) -> Option<String> {
+        self.level.title()
+    }
+
+    fn size_hint(&self) -> Option<Vec2d> {
+        self.level.size_hint()
+    }
+
+    /// The recorded headers, such as the referer of the viewer page, replace the default ones of the level
+    fn http_headers(&self) -> HashMap<String, String> {
+        let mut headers = self.level.http_headers();
+        headers.retain(|name, _| !self.headers.keys().any(|k| k.eq_ignore_ascii_case(name)));
+        headers.extend(self.headers.clone());
+        headers
+    }
+
+    fn is_cube(&self) -> bool {
+        self.level.is_cube()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROPERTIES_URL: &str = "http://example.com/tiles/ImageProperties.xml";
+    const PROPERTIES: &str = r#"<IMAGE_PROPERTIES WIDTH="600" HEIGHT="300" NUMTILES="4" TILESIZE="256" />"#;
+
+    fn har(content: Option<&str>) -> DezoomerInput {
+        let content = match content {
+            Some(text) => serde_json::json!({"mimeType": "text/xml", "text": text}),
+            None => serde_json::json!({"mimeType": "text/xml"}),
+        };
+        let har = serde_json::json!({"log": {"entries": [
+            {
+                "request": {"url": "http://example.com/viewer.html", "headers": []},
+                "response": {"status": 200, "content": {"mimeType": "text/html", "text": "<html></html>"}}
+            },
+            {
+                "request": {"url": "http://example.com/tiles/TileGroup0/0-0-0.jpg", "headers": []},
+                "response": {"status": 200, "content": {"mimeType": "image/jpeg"}}
+            },
+            {
+                "request": {
+                    "url": PROPERTIES_URL,
+                    "headers": [
+                        {"name": ":authority", "value": "example.com"},
+                        {"name": "Referer", "value": "http://example.com/viewer.html"},
+                        {"name": "Accept-Encoding", "value": "gzip, br"},
+                        {"name": "X-Token", "value": "secret"}
+                    ],
+                    "cookies": [{"name": "session", "value": "42"}]
+                },
+                "response": {"status": 200, "content": content}
+            }
+        ]}});
+        DezoomerInput { uri: "recording.har".into(), contents: Some(har.to_string().into_bytes()) }
+    }
+
+    fn expected_headers() -> HashMap<String, String> {
+        vec![
+            ("Referer", "http://example.com/viewer.html"),
+            ("X-Token", "secret"),
+            ("Cookie", "session=42"),
+        ].into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_recorded_response() {
+        let levels = HarDezoomer::default().zoom_levels(&har(Some(PROPERTIES))).unwrap();
+        assert!(levels.iter().any(|l| l.size_hint() == Some(Vec2d { x: 600, y: 300 })));
+        assert_eq!(levels[0].http_headers(), expected_headers());
+    }
+
+    #[test]
+    fn test_replayed_request() {
+        let mut dezoomer = HarDezoomer::default();
+        match dezoomer.zoom_levels(&har(None)) {
+            Err(DezoomerError::NeedsData { uri }) => assert_eq!(uri, PROPERTIES_URL),
+            _ => panic!("The metadata request should be sent again"),
+        }
+        assert_eq!(dezoomer.http_headers(), expected_headers());
+        let response = DezoomerInput { uri: PROPERTIES_URL.into(), contents: Some(PROPERTIES.into()) };
+        let levels = dezoomer.zoom_levels(&response).unwrap();
+        assert_eq!(levels[0].http_headers(), expected_headers());
+    }
+
+    #[test]
+    fn test_cube_panorama() {
+        let krpano = r#"<krpano><image type="cube" tilesize="512">
+            <level tiledimagewidth="1000" tiledimageheight="1000"><cube url="%s/%v_%h.jpg"/></level>
+        </image></krpano>"#;
+        let har = serde_json::json!({"log": {"entries": [{
+            "request": {"url": "http://example.com/pano/tour.xml", "headers": []},
+            "response": {"status": 200, "content": {"mimeType": "text/xml", "text": krpano}}
+        }]}});
+        let input = DezoomerInput { uri: "pano.har".into(), contents: Some(har.to_string().into_bytes()) };
+        let levels = HarDezoomer::default().zoom_levels(&input).unwrap();
+        let cubes: Vec<_> = levels.iter().filter(|l| l.is_cube()).collect();
+        assert_eq!(cubes.len(), 1);
+        assert_eq!(cubes[0].size_hint(), Some(Vec2d { x: 6000, y: 1000 }));
+        let mut args = crate::arguments::Arguments::default();
+        args.equirectangular = true;
+        let levels = crate::cube_levels(levels, &args).unwrap();
+        assert!(levels.iter().all(|l| l.is_cube()));
+    }
+
+    #[test]
+    fn test_no_image() {
+        let input = DezoomerInput { uri: "empty.har".into(), contents: Some(br#"{"log":{"entries":[]}}"#.to_vec()) };
+        let err = HarDezoomer::default().zoom_levels(&input).err().unwrap();
+        assert!(err.to_string().contains("None of the 0 requests"), "{}", err);
+    }
+}
@@ -0,0 +1,259 @@
+use std::collections::BTreeMap;
+
+use log::warn;
+use serde::Deserialize;
+
+use crate::dezoomer::ImageReference;
+
+/// A IIIF Presentation API manifest, in version 2 or 3.
+/// Only the parts needed to find the image services of the canvases are parsed.
+/// See https://iiif.io/api/presentation/3.0/
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct Manifest {
+    #[serde(default)]
+    label: Option<Label>,
+    // Used in IIIF Presentation version 2 :
+    #[serde(default)]
+    sequences: Vec<Sequence>,
+    // Used in IIIF Presentation version 3 :
+    #[serde(default)]
+    items: Vec<Canvas>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct Sequence {
+    #[serde(default)]
+    canvases: Vec<Canvas>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct Canvas {
+    #[serde(default)]
+    label: Option<Label>,
+    // Used in IIIF Presentation version 2 :
+    #[serde(default)]
+    images: Vec<Annotation>,
+    // Used in IIIF Presentation version 3 :
+    #[serde(default)]
+    items: Vec<AnnotationPage>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct AnnotationPage {
+    #[serde(default)]
+    items: Vec<Annotation>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct Annotation {
+    #[serde(alias = "body")]
+    resource: Option<Resource>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct Resource {
+    #[serde(default)]
+    service: Option<OneOrMany<Service>>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct Service {
+    #[serde(alias = "@id")]
+    id: Option<String>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> OneOrMany<T> {
+    fn iter(&self) -> impl Iterator<Item=&T> {
+        match self {
+            OneOrMany::One(x) => std::slice::from_ref(x).iter(),
+            OneOrMany::Many(v) => v.iter(),
+        }
+    }
+}
+
+/// A label can be a plain string, a language-tagged value (version 2),
+/// a language map (version 3), or a list of any of these
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+enum Label {
+    Plain(String),
+    Value {
+        #[serde(rename = "@value")]
+        value: String,
+    },
+    LanguageMap(BTreeMap<String, Vec<String>>),
+    Multiple(Vec<Label>),
+}
+
+impl Label {
+    fn first(&self) -> Option<&str> {
+        match self {
+            Label::Plain(s) | Label::Value { value: s } => Some(s),
+            Label::LanguageMap(map) => {
+                // Prefer english, then values that are not tied to a language, then anything
+                ["en", "none"].iter()
+                    .filter_map(|lang| map.get(*lang))
+                    .chain(map.values())
+                    .flat_map(|values| values.iter())
+                    .map(|s| s.as_str())
+                    .next()
+            }
+            Label::Multiple(labels) => labels.iter().filter_map(Label::first).next(),
+        }
+    }
+}
+
+impl Canvas {
+    fn annotations(&self) -> impl Iterator<Item=&Annotation> {
+        self.images.iter().chain(self.items.iter().flat_map(|page| page.items.iter()))
+    }
+
+    fn info_url(&self) -> Option<String> {
+        self.annotations()
+            .filter_map(|annotation| annotation.resource.as_ref())
+            .filter_map(|resource| resource.service.as_ref())
+            .flat_map(|services| services.iter())
+            .filter_map(|service| service.id.as_ref())
+            .map(|id| format!("{}/info.json", id.trim_end_matches('/')))
+            .next()
+    }
+}
+
+impl Manifest {
+    pub fn is_empty(&self) -> bool {
+        self.sequences.is_empty() && self.items.is_empty()
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.label.as_ref().and_then(Label::first)
+    }
+
+    /// The list of the images in the manifest, as references to their info.json files
+    pub fn images(&self) -> Vec<ImageReference> {
+        self.sequences.iter()
+            .flat_map(|sequence| sequence.canvases.iter())
+            .chain(self.items.iter())
+            .filter_map(|canvas| {
+                let title = canvas.label.as_ref().and_then(Label::first).map(String::from);
+                let uri = canvas.info_url();
+                if uri.is_none() {
+                    warn!("The canvas {:?} does not have an image service", title);
+                }
+                uri.map(|uri| ImageReference { uri, title })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_v2() {
+        let manifest: Manifest = serde_json::from_str(r#"{
+          "@context": "http://iiif.io/api/presentation/2/context.json",
+          "@id": "http://example.org/iiif/book1/manifest",
+          "@type": "sc:Manifest",
+          "label": [{"@value": "Book 1", "@language": "en"}],
+          "sequences": [{
+            "@type": "sc:Sequence",
+            "canvases": [{
+              "@id": "http://example.org/iiif/book1/canvas/p1",
+              "@type": "sc:Canvas",
+              "label": "p. 1",
+              "height": 1000, "width": 750,
+              "images": [{
+                "@type": "oa:Annotation",
+                "motivation": "sc:painting",
+                "resource": {
+                  "@id": "http://example.org/iiif/book1/res/page1.jpg",
+                  "@type": "dctypes:Image",
+                  "service": {
+                    "@context": "http://iiif.io/api/image/2/context.json",
+                    "@id": "http://example.org/images/book1-page1/",
+                    "profile": "http://iiif.io/api/image/2/level1.json"
+                  }
+                },
+                "on": "http://example.org/iiif/book1/canvas/p1"
+              }]
+            }, {
+              "@id": "http://example.org/iiif/book1/canvas/p2",
+              "@type": "sc:Canvas",
+              "images": []
+            }]
+          }]
+        }"#).unwrap();
+        assert_eq!(manifest.title(), Some("Book 1"));
+        assert_eq!(manifest.images(), vec![
+            ImageReference {
+                uri: "http://example.org/images/book1-page1/info.json".into(),
+                title: Some("p. 1".into()),
+            }
+        ]);
+    }
+
+    #[test]
+    fn test_manifest_v3() {
+        let manifest: Manifest = serde_json::from_str(r#"{
+          "@context": "http://iiif.io/api/presentation/3/context.json",
+          "id": "https://example.org/iiif/book1/manifest",
+          "type": "Manifest",
+          "label": { "fr": [ "Livre 1" ], "en": [ "Book 1" ] },
+          "items": [
+            {
+              "id": "https://example.org/iiif/book1/canvas/p1",
+              "type": "Canvas",
+              "label": { "none": [ "p. 1" ] },
+              "items": [{
+                "id": "https://example.org/iiif/book1/page/p1/1",
+                "type": "AnnotationPage",
+                "items": [{
+                  "id": "https://example.org/iiif/book1/annotation/p0001-image",
+                  "type": "Annotation",
+                  "motivation": "painting",
+                  "body": {
+                    "id": "https://example.org/iiif/book1/page1/full/max/0/default.jpg",
+                    "type": "Image",
+                    "service": [{ "id": "https://example.org/iiif/book1/page1", "type": "ImageService3" }]
+                  },
+                  "target": "https://example.org/iiif/book1/canvas/p1"
+                }]
+              }]
+            },
+            {
+              "id": "https://example.org/iiif/book1/canvas/p2",
+              "type": "Canvas",
+              "items": [{
+                "type": "AnnotationPage",
+                "items": [{
+                  "type": "Annotation",
+                  "body": {
+                    "id": "https://example.org/iiif/book1/page2/full/max/0/default.jpg",
+                    "service": [{ "@id": "https://example.org/iiif/book1/page2", "@type": "ImageService2" }]
+                  }
+                }]
+              }]
+            }
+          ]
+        }"#).unwrap();
+        assert_eq!(manifest.title(), Some("Book 1"));
+        assert_eq!(manifest.images(), vec![
+            ImageReference {
+                uri: "https://example.org/iiif/book1/page1/info.json".into(),
+                title: Some("p. 1".into()),
+            },
+            ImageReference {
+                uri: "https://example.org/iiif/book1/page2/info.json".into(),
+                title: None,
+            },
+        ]);
+    }
+}
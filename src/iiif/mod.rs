@@ -3,6 +3,7 @@ use std::sync::Arc;
 use custom_error::custom_error;
 use serde::export::Formatter;
 
+use manifest::Manifest;
 use tile_info::ImageInfo;
 
 use crate::dezoomer::*;
@@ -11,14 +12,20 @@ use crate::max_size_in_rect;
 use log::info;
 
 pub mod tile_info;
+mod manifest;
 
 /// Dezoomer for the International Image Interoperability Framework.
+/// Takes either the URL of an image's info.json file,
+/// or the URL of a presentation manifest listing several images.
 /// See https://iiif.io/
 #[derive(Default)]
 pub struct IIIF;
 
 custom_error! {pub IIIFError
-    JsonError{source: serde_json::Error} = "Invalid IIIF info.json file: {source}"
+    JsonError{source: serde_json::Error} = "Invalid IIIF info.json file: {source}",
+    ManifestError{e: serde_json::Error} = "Invalid IIIF manifest: {e}",
+    NotAManifest = "The file is not a IIIF manifest",
+    EmptyManifest = "The IIIF manifest does not contain any image",
 }
 
 impl From<IIIFError> for DezoomerError {
@@ -33,14 +40,32 @@ impl Dezoomer for IIIF {
     }
 
     fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
-        self.assert(data.uri.ends_with("/info.json"))?;
         let with_contents = data.with_contents()?;
         let contents = with_contents.contents;
         let uri = with_contents.uri;
-        Ok(zoom_levels(uri, contents)?)
+        if uri.ends_with("/info.json") {
+            return Ok(zoom_levels(uri, contents)?);
+        }
+        let mut images = manifest_images(contents)?;
+        if images.len() == 1 {
+            let uri = images.swap_remove(0).uri;
+            Err(DezoomerError::NeedsData { uri })
+        } else {
+            Err(DezoomerError::MultipleImages { images })
+        }
     }
 }
 
+fn manifest_images(raw_manifest: &[u8]) -> Result<Vec<ImageReference>, IIIFError> {
+    let manifest: Manifest = serde_json::from_slice(raw_manifest)
+        .map_err(|e| IIIFError::ManifestError { e })?;
+    if manifest.is_empty() { return Err(IIIFError::NotAManifest); }
+    let images = manifest.images();
+    info!("Found {} images in the IIIF manifest {:?}", images.len(), manifest.title());
+    if images.is_empty() { return Err(IIIFError::EmptyManifest); }
+    Ok(images)
+}
+
 fn zoom_levels(url: &str, raw_info: &[u8]) -> Result<ZoomLevels, IIIFError> {
     let image_info: ImageInfo = serde_json::from_slice(raw_info)?;
     let img = Arc::new(image_info);
@@ -222,3 +247,29 @@ fn test_qualities() {
         "https://images.britishart.yale.edu/iiif/fd470c3e-ead0-4878-ac97-d63295753f82/0,0,5156,3816/515,381/0/native.png",
     ])
 }
+
+#[test]
+fn test_manifest() {
+    let canvas = |n: u32| format!(r#"{{
+        "type": "Canvas", "label": {{ "none": [ "p. {n}" ] }},
+        "items": [{{ "type": "AnnotationPage", "items": [{{
+            "type": "Annotation",
+            "body": {{ "service": [{{ "id": "http://test.com/page{n}", "type": "ImageService3" }}] }}
+        }}] }}]
+    }}"#, n = n);
+    let manifest = |canvases: &[String]| DezoomerInput {
+        uri: "http://test.com/manifest.json".into(),
+        contents: Some(format!(r#"{{ "type": "Manifest", "items": [{}] }}"#, canvases.join(",")).into_bytes()),
+    };
+    match IIIF.zoom_levels(&manifest(&[canvas(1)])) {
+        Err(DezoomerError::NeedsData { uri }) => assert_eq!(uri, "http://test.com/page1/info.json"),
+        _ => panic!("A manifest with a single image should request its info.json"),
+    }
+    match IIIF.zoom_levels(&manifest(&[canvas(1), canvas(2)])) {
+        Err(DezoomerError::MultipleImages { images }) => assert_eq!(images, vec![
+            ImageReference { uri: "http://test.com/page1/info.json".into(), title: Some("p. 1".into()) },
+            ImageReference { uri: "http://test.com/page2/info.json".into(), title: Some("p. 2".into()) },
+        ]),
+        _ => panic!("A manifest with several images should list them"),
+    }
+}
@@ -15,15 +15,29 @@ pub struct KrpanoImage {
     pub tilesize: Option<u32>,
     #[serde(default = "default_base_index")]
     pub baseindex: u32,
+    /// Names given to the sides of the cube in the urls, in the order left, front, right, back, up, down
+    pub cubelabels: Option<String>,
     #[serde(rename = "$value")]
     pub level: Vec<KrpanoLevel>,
 }
 
 fn default_base_index() -> u32 { 1 }
 
+impl KrpanoImage {
+    /// The names of the sides of the cube, in the order left, front, right, back, up, down
+    pub fn cube_labels(&self) -> Vec<String> {
+        let labels: Vec<String> = self.cubelabels.as_deref().unwrap_or("l|f|r|b|u|d")
+            .split('|').map(String::from).collect();
+        if labels.len() == 6 { labels } else { "lfrbud".chars().map(String::from).collect() }
+    }
+}
+
 pub struct LevelDesc {
+    /// The devices the level is meant for, such as "Mobile", or an empty string for all devices
+    pub variant: &'static str,
     pub name: &'static str,
-    pub size: Vec2d,
+    /// The size of the image, unknown for images that are not tiled
+    pub size: Option<Vec2d>,
     pub tilesize: Option<Vec2d>,
     pub url: TemplateString<TemplateVariable>,
     pub level_index: usize,
@@ -61,27 +75,30 @@ pub enum KrpanoLevel {
 }
 
 impl KrpanoLevel {
-    pub fn level_descriptions(self, size: Option<Vec2d>) -> Vec<Result<LevelDesc, &'static str>> {
+    pub fn level_descriptions(self, variant: &'static str, size: Option<Vec2d>) -> Vec<Result<LevelDesc, &'static str>> {
+        let d = |name, desc| shape_descriptions(variant, name, desc, size);
         match self {
             Self::Level(LevelAttributes { tiledimagewidth, tiledimageheight, shape }) => {
                 let size = Vec2d { x: tiledimagewidth, y: tiledimageheight };
-                shape.into_iter().flat_map(|level| level.level_descriptions(Some(size))).collect()
+                shape.into_iter().flat_map(|level| level.level_descriptions(variant, Some(size))).collect()
             },
-            Self::Cube(d) => shape_descriptions("Cube", d, size),
-            Self::Cylinder(d) => shape_descriptions("Cylinder", d, size),
-            Self::Flat(d) => shape_descriptions("Flat", d, size),
-            Self::Left(d) => shape_descriptions("Left", d, size),
-            Self::Right(d) => shape_descriptions("Right", d, size),
-            Self::Front(d) => shape_descriptions("Front", d, size),
-            Self::Back(d) => shape_descriptions("Back", d, size),
-            Self::Up(d) => shape_descriptions("Up", d, size),
-            Self::Down(d) => shape_descriptions("Down", d, size),
-            Self::Mobile(_) | Self::Tablet(_) => vec![], // Ignore
+            Self::Mobile(levels) => levels.into_iter().flat_map(|level| level.level_descriptions("Mobile", size)).collect(),
+            Self::Tablet(levels) => levels.into_iter().flat_map(|level| level.level_descriptions("Tablet", size)).collect(),
+            Self::Cube(desc) => d("Cube", desc),
+            Self::Cylinder(desc) => d("Cylinder", desc),
+            Self::Flat(desc) => d("Flat", desc),
+            Self::Left(desc) => d("Left", desc),
+            Self::Right(desc) => d("Right", desc),
+            Self::Front(desc) => d("Front", desc),
+            Self::Back(desc) => d("Back", desc),
+            Self::Up(desc) => d("Up", desc),
+            Self::Down(desc) => d("Down", desc),
         }
     }
 }
 
 fn shape_descriptions(
+    variant: &'static str,
     name: &'static str,
     desc: ShapeDesc,
     size: Option<Vec2d>,
@@ -90,18 +107,18 @@ fn shape_descriptions(
     if let Some(multires) = multires {
         parse_multires(&multires).enumerate().map(|(level_index, result)|
             result.map(|(size, tilesize)| LevelDesc {
+                variant,
                 name,
-                size,
+                size: Some(size),
                 tilesize: Some(tilesize),
                 url: url.clone(),
                 level_index,
             })
         ).collect()
-    } else if let Some(size) = size {
-        let tilesize = None;
-        vec![Ok(LevelDesc { name, size, tilesize, url, level_index: 0 })]
     } else {
-        vec![Err("missing multires attribute")]
+        // Without a size, the url points to a single image file
+        let tilesize = None;
+        vec![Ok(LevelDesc { variant, name, size, tilesize, url, level_index: 0 })]
     }
 }
 
@@ -171,17 +188,22 @@ impl FromStr for TemplateString<TemplateVariable> {
 }
 
 impl TemplateString<TemplateVariable> {
-    pub fn all_sides(self, level: usize) -> impl Iterator<Item=(&'static str, TemplateString<XY>)> + 'static {
+    /// The template for each side of the cube, given the names of the sides in the order of `cubelabels`
+    pub fn all_sides(self, level: usize, labels: &[String]) -> impl Iterator<Item=(&'static str, TemplateString<XY>)> + 'static {
         let has_side = self.0.iter().any(|x| match x {
             TemplateStringPart::Variable { variable, .. } => *variable == TemplateVariable::Side,
             _ => false
         });
-        let sides = if has_side { &["forward", "back", "left", "right", "up", "down"][..] } else { &[""] };
-        sides.iter().map(move |&side| (side, TemplateString(
-            self.0.iter().map(|part| {
-                part.with_side(side, level)
-            }).collect()
-        )))
+        // Name of each side, and its position in the cube labels
+        const SIDES: [(&str, usize); 6] = [("forward", 1), ("back", 3), ("left", 0), ("right", 2), ("up", 4), ("down", 5)];
+        let sides = if has_side { &SIDES[..] } else { &[("", 0)] };
+        let labels = labels.to_vec();
+        sides.iter().map(move |&(side, label_index)| {
+            let label = labels.get(label_index).map(String::as_str).unwrap_or_default();
+            (side, TemplateString(
+                self.0.iter().map(|part| part.with_side(label, level)).collect()
+            ))
+        })
     }
 }
 
@@ -193,7 +215,7 @@ pub enum TemplateStringPart<T> {
 }
 
 impl TemplateStringPart<TemplateVariable> {
-    fn with_side(&self, side: &'static str, level: usize) -> TemplateStringPart<XY> {
+    fn with_side(&self, label: &str, level: usize) -> TemplateStringPart<XY> {
         use TemplateStringPart::*;
         use TemplateVariable::*;
         match self {
@@ -203,7 +225,7 @@ impl TemplateStringPart<TemplateVariable> {
                 match variable {
                     X => Variable { padding, variable: XY::X },
                     Y => Variable { padding, variable: XY::Y },
-                    Side => Literal(Arc::new(side[..1].to_string())),
+                    Side => Literal(Arc::new(label.to_string())),
                     LevelIndex => {
                         let idx_str = format!("{v:0padding$}", v = level, padding = padding as usize);
                         Literal(Arc::new(idx_str))
@@ -254,6 +276,7 @@ mod test {
             image: vec![
                 KrpanoImage {
                     baseindex: 1,
+                    cubelabels: None,
                     tilesize: Some(512),
                     level: vec![
                         KrpanoLevel::Level(LevelAttributes {
@@ -286,6 +309,7 @@ mod test {
         assert_eq!(parsed, KrpanoMetadata {
             image: vec![KrpanoImage {
                 baseindex: 0,
+                cubelabels: None,
                 tilesize: Some(512),
                 level: vec![KrpanoLevel::Level(LevelAttributes {
                     tiledimagewidth: 3280,
@@ -313,6 +337,7 @@ mod test {
         assert_eq!(parsed, KrpanoMetadata {
             image: vec![KrpanoImage {
                 baseindex: 1,
+                cubelabels: None,
                 tilesize: None,
                 level: vec![KrpanoLevel::Flat(ShapeDesc {
                     url: TemplateString(vec![str("https://example.com/"), ]),
@@ -336,6 +361,7 @@ mod test {
         assert_eq!(parsed, KrpanoMetadata {
             image: vec![KrpanoImage {
                 baseindex: 1,
+                cubelabels: None,
                 tilesize: None,
                 level: vec![Mobile(vec![Cube(ShapeDesc {
                     url: TemplateString(vec![str("test.jpg")]),
@@ -363,5 +389,21 @@ mod test {
             ])),
             "%00x%%%0y%l".parse()
         );
+        assert_eq!(
+            Ok(TemplateString(vec![
+                str("l"), lvl(1), str("/"), y(1), str("_"), x(4), str(".jpg")
+            ])),
+            "l%l/%v_%000h.jpg".parse()
+        );
+    }
+
+    #[test]
+    fn test_cube_labels() {
+        let template: TemplateString<TemplateVariable> = "%s_%0l.jpg".parse().unwrap();
+        let labels: Vec<String> = "w|n|e|s|up|down".split('|').map(String::from).collect();
+        let sides: Vec<_> = template.all_sides(3, &labels).collect();
+        let lit = |s: &str| -> TemplateStringPart<XY> { Literal(Arc::new(s.to_string())) };
+        assert_eq!(sides[0], ("forward", TemplateString(vec![lit("n"), lit("_"), lit("03"), lit(".jpg")])));
+        assert_eq!(sides[2].1, TemplateString(vec![lit("w"), lit("_"), lit("03"), lit(".jpg")]));
     }
 }
\ No newline at end of file
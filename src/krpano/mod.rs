@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use itertools::Itertools;
@@ -7,6 +8,7 @@ use custom_error::custom_error;
 use krpano_metadata::{KrpanoMetadata, TemplateString, TemplateStringPart, XY};
 
 use crate::dezoomer::*;
+use crate::encoder::equirectangular::CUBE_FACES;
 use crate::krpano::krpano_metadata::LevelDesc;
 use crate::network::{remove_bom, resolve_relative};
 
@@ -45,75 +47,139 @@ fn load_from_properties(url: &str, contents: &[u8])
     let slash_pos = url.rfind('/').unwrap_or(url.len() - 1);
     let base_url = &Arc::new(format!("{}/", &url[0..slash_pos]));
 
-    Ok(image_properties.image.into_iter().flat_map(move |image| {
+    let mut levels = vec![];
+    let mut image_files = vec![];
+    for image in image_properties.image {
         let root_tile_size = image.tilesize.map(Vec2d::square);
         let base_index = image.baseindex;
-        image.level.into_iter().flat_map(move |level| {
-            level.level_descriptions(None).into_iter().flat_map(move |level_desc| {
-                level_desc
-                    .map_err(|err| warn!("bad krpano level: {}", err))
-                    .into_iter()
-                    .flat_map(move |LevelDesc {
-                                        name: shape_name,
-                                        size,
-                                        tilesize,
-                                        url,
-                                        level_index,
-                                    }| {
-                        let level = level_index + base_index as usize;
-                        url.all_sides(level).flat_map(move |(side_name, template)| {
-                            let base_url = Arc::clone(base_url);
-                            tilesize.or(root_tile_size).map(|tile_size|
-                                Level {
-                                    base_url,
-                                    base_index,
-                                    size,
-                                    tile_size,
-                                    template,
-                                    shape_name,
-                                    side_name,
-                                })
+        let labels = image.cube_labels();
+        let level_descs = image.level.into_iter().flat_map(|level| level.level_descriptions("", None));
+        for level_desc in level_descs {
+            let LevelDesc { variant, name: shape_name, size, tilesize, url, level_index } = match level_desc {
+                Ok(desc) => desc,
+                Err(err) => {
+                    warn!("bad krpano level: {}", err);
+                    continue;
+                }
+            };
+            let level = level_index + base_index as usize;
+            for (side_name, template) in url.all_sides(level, &labels) {
+                match size {
+                    Some(size) => {
+                        let tile_size = match tilesize.or(root_tile_size) {
+                            Some(tile_size) => tile_size,
+                            // Urls without tile coordinates point to a single image file
+                            None if !is_tiled(&template) => size,
+                            None => {
+                                warn!("Skipping the krpano level {} {} {}, which has no tile size",
+                                      variant, shape_name, side_name);
+                                continue;
+                            }
+                        };
+                        levels.push(Level {
+                            base_url: Arc::clone(base_url),
+                            base_index,
+                            size,
+                            tile_size,
+                            template,
+                            variant,
+                            shape_name,
+                            side_name,
                         })
-                    })
-            })
-        })
-    }).into_zoom_levels())
+                    }
+                    None => image_files.push(ImageFile {
+                        url: resolve_relative(base_url, &fill_template(&template, base_index, Vec2d::default())),
+                        variant,
+                        shape_name,
+                        side_name,
+                    }),
+                }
+            }
+        }
+    }
+    let cubes = cube_levels(&levels);
+    let mut zoom_levels = levels.into_iter().into_zoom_levels();
+    zoom_levels.extend(cubes.into_iter().into_zoom_levels());
+    zoom_levels.extend(image_files.into_iter().into_zoom_levels());
+    Ok(zoom_levels)
+}
+
+/// Groups the faces of the same size that form a complete cube panorama
+fn cube_levels(levels: &[Level]) -> Vec<CubeLevel> {
+    let keys = levels.iter()
+        .filter(|l| l.face().is_some() && l.size.x == l.size.y)
+        .map(|l| (l.variant, l.size))
+        .unique();
+    keys.filter_map(|(variant, size)| {
+        let faces: Option<Vec<Level>> = (0..CUBE_FACES.len()).map(|face|
+            levels.iter().find(|l| l.variant == variant && l.size == size && l.face() == Some(face)).cloned()
+        ).collect();
+        faces.map(|faces| CubeLevel { faces })
+    }).collect()
+}
+
+/// Whether the urls of a template contain tile coordinates
+fn is_tiled(template: &TemplateString<XY>) -> bool {
+    template.0.iter().any(|part| matches!(part, TemplateStringPart::Variable { .. }))
+}
+
+/// Replaces the tile coordinates in a template, counting from the given first index
+fn fill_template(template: &TemplateString<XY>, first_index: u32, Vec2d { x, y }: Vec2d) -> String {
+    use std::fmt::Write;
+    let mut result = String::new();
+    for part in template.0.iter() {
+        match part {
+            TemplateStringPart::Literal(s) => { result += s }
+            TemplateStringPart::Variable { padding, variable } => {
+                write!(result, "{value:0padding$}",
+                       value = first_index + match variable {
+                           XY::X => x,
+                           XY::Y => y
+                       },
+                       padding = *padding
+                ).unwrap();
+            }
+        }
+    }
+    result
+}
+
+/// Formats the name of a level from its non-empty parts
+fn level_name(f: &mut std::fmt::Formatter, parts: &[&str]) -> std::fmt::Result {
+    write!(f, "{}", parts.iter().filter(|s| !s.is_empty()).join(" "))
 }
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone)]
 struct Level {
     base_url: Arc<String>,
     size: Vec2d,
     tile_size: Vec2d,
     base_index: u32,
     template: TemplateString<XY>,
+    variant: &'static str,
     shape_name: &'static str,
     side_name: &'static str,
 }
 
+impl Level {
+    /// Position of the level in `CUBE_FACES`, if it is a face of a cube
+    fn face(&self) -> Option<usize> {
+        let name = match self.shape_name {
+            "Cube" if self.side_name == "forward" => "front".to_string(),
+            "Cube" => self.side_name.to_string(),
+            shape => shape.to_ascii_lowercase(),
+        };
+        CUBE_FACES.iter().position(|&f| f == name)
+    }
+}
+
 impl TilesRect for Level {
     fn size(&self) -> Vec2d { self.size }
 
     fn tile_size(&self) -> Vec2d { self.tile_size }
 
-    fn tile_url(&self, Vec2d { x, y }: Vec2d) -> String {
-        use std::fmt::Write;
-        let mut result = String::new();
-        for part in self.template.0.iter() {
-            match part {
-                TemplateStringPart::Literal(s) => { result += s }
-                TemplateStringPart::Variable { padding, variable } => {
-                    write!(result, "{value:0padding$}",
-                           value = self.base_index + match variable {
-                               XY::X => x,
-                               XY::Y => y
-                           },
-                           padding = *padding
-                    ).unwrap();
-                }
-            }
-        }
-        resolve_relative(&self.base_url, &result)
+    fn tile_url(&self, pos: Vec2d) -> String {
+        resolve_relative(&self.base_url, &fill_template(&self.template, self.base_index, pos))
     }
 
     fn tile_ref(&self, pos: Vec2d) -> TileReference {
@@ -126,8 +192,73 @@ impl TilesRect for Level {
 
 impl std::fmt::Debug for Level {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        let parts = ["Krpano", self.shape_name, self.side_name];
-        write!(f, "{}", parts.iter().filter(|s| !s.is_empty()).join(" "))
+        level_name(f, &["Krpano", self.variant, self.shape_name, self.side_name])
+    }
+}
+
+/// The six faces of a cube panorama, side by side
+struct CubeLevel {
+    faces: Vec<Level>,
+}
+
+impl TileProvider for CubeLevel {
+    fn next_tiles(&mut self, previous: Option<TileFetchResult>) -> Vec<TileReference> {
+        if previous.is_some() { return vec![]; }
+        self.faces.iter_mut().enumerate().flat_map(|(i, face)| {
+            let offset = Vec2d { x: i as u32 * face.size.x, y: 0 };
+            face.next_tiles(None).into_iter().map(move |TileReference { url, position }|
+                TileReference { url, position: position + offset })
+        }).collect()
+    }
+
+    fn name(&self) -> String {
+        let Vec2d { x, y } = self.faces[0].size;
+        let tiles: u32 = self.faces.iter().map(|f| f.tile_count()).sum();
+        format!("{:?} ({:>5} x {:>5} pixels per face, {:>5} tiles)", self, x, y, tiles)
+    }
+
+    fn size_hint(&self) -> Option<Vec2d> {
+        let Vec2d { x, y } = self.faces[0].size;
+        Some(Vec2d { x: x * self.faces.len() as u32, y })
+    }
+
+    fn http_headers(&self) -> HashMap<String, String> {
+        self.faces[0].http_headers()
+    }
+
+    fn is_cube(&self) -> bool { true }
+}
+
+impl std::fmt::Debug for CubeLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        level_name(f, &["Krpano", self.faces[0].variant, "cube panorama"])
+    }
+}
+
+/// An image that is not tiled, such as the faces of a cube for mobile devices
+struct ImageFile {
+    url: String,
+    variant: &'static str,
+    shape_name: &'static str,
+    side_name: &'static str,
+}
+
+impl TileProvider for ImageFile {
+    fn next_tiles(&mut self, previous: Option<TileFetchResult>) -> Vec<TileReference> {
+        if previous.is_some() { return vec![]; }
+        vec![TileReference { url: self.url.clone(), position: Vec2d::default() }]
+    }
+
+    fn http_headers(&self) -> HashMap<String, String> {
+        let mut headers = HashMap::new();
+        headers.insert("Referer".into(), self.url.clone());
+        headers
+    }
+}
+
+impl std::fmt::Debug for ImageFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        level_name(f, &["Krpano", self.variant, self.shape_name, self.side_name])
     }
 }
 
@@ -143,7 +274,7 @@ fn test_cube() {
         </image>
         </krpano>"#.as_bytes(),
     ).unwrap();
-    assert_eq!(levels.len(), 6);
+    assert_eq!(levels.len(), 6, "faces that are not square do not form a cube panorama");
     assert_eq!(levels[0].size_hint(), Some(Vec2d { x: 1000, y: 100 }));
     assert_eq!(format!("{:?}", levels[0]), "Krpano Cube forward");
     assert_eq!(levels[0].next_tiles(None), vec![
@@ -151,6 +282,105 @@ fn test_cube() {
         TileReference { url: "http://example.com/f/1/2.jpg".to_string(), position: Vec2d { x: 512, y: 0 } }]);
 }
 
+#[test]
+fn test_cube_panorama() {
+    let levels = load_from_properties(
+        "http://test.com/pano.xml",
+        r#"<krpano>
+        <image type="cube" tilesize="512">
+            <level tiledimagewidth="1000" tiledimageheight="1000">
+                <left  url="l/%v_%h.jpg"/> <front url="f/%v_%h.jpg"/> <right url="r/%v_%h.jpg"/>
+                <back  url="b/%v_%h.jpg"/> <up    url="u/%v_%h.jpg"/> <down  url="d/%v_%h.jpg"/>
+            </level>
+        </image>
+        </krpano>"#.as_bytes(),
+    ).unwrap();
+    assert_eq!(levels.len(), 7);
+    let mut cube = levels.into_iter().last().unwrap();
+    assert!(cube.is_cube());
+    assert_eq!(cube.size_hint(), Some(Vec2d { x: 6000, y: 1000 }));
+    let tiles = cube.next_tiles(None);
+    assert_eq!(tiles.len(), 6 * 4);
+    assert_eq!(tiles[0].url, "http://test.com/f/1_1.jpg");
+    assert_eq!(tiles[4], TileReference {
+        url: "http://test.com/r/1_1.jpg".to_string(),
+        position: Vec2d { x: 1000, y: 0 },
+    });
+    assert_eq!(tiles[23].url, "http://test.com/d/2_2.jpg");
+    assert_eq!(tiles[23].position, Vec2d { x: 5512, y: 512 });
+}
+
+#[test]
+fn test_largest_cube_face() {
+    let tour = r#"<krpano>
+        <image type="cube" tilesize="512">
+            <level tiledimagewidth="1000" tiledimageheight="1000"><cube url="%s/%v_%h.jpg"/></level>
+            <level tiledimagewidth="500" tiledimageheight="500"><cube url="small/%s/%v_%h.jpg"/></level>
+        </image>
+        </krpano>"#;
+    let levels = || load_from_properties("http://test.com/pano.xml", tour.as_bytes()).unwrap();
+    let mut args = crate::Arguments::default();
+    args.largest = true;
+    let level = crate::choose_level(crate::cube_levels(levels(), &args).unwrap(), &args).unwrap();
+    assert!(!level.is_cube());
+    assert_eq!(level.size_hint(), Some(Vec2d::square(1000)));
+
+    args.equirectangular = true;
+    let level = crate::choose_level(crate::cube_levels(levels(), &args).unwrap(), &args).unwrap();
+    assert!(level.is_cube());
+    assert_eq!(level.size_hint(), Some(Vec2d { x: 6000, y: 1000 }));
+}
+
+#[test]
+fn test_mobile() {
+    let levels = load_from_properties(
+        "http://test.com/tour.xml",
+        r#"<krpano>
+        <image type="cube" cubelabels="left|front|right|back|top|bottom">
+            <level tiledimagewidth="2000" tiledimageheight="2000">
+                <cube url="tiles/%s/l%l/%v/%0000h.jpg" />
+            </level>
+            <level tiledimagewidth="1000" tiledimageheight="1000">
+                <cube url="preview/%s.jpg" />
+            </level>
+            <mobile><cube url="mobile_%s.jpg" /></mobile>
+            <tablet><cube url="tablet/%s/%v_%h.jpg" multires="256,1024" /></tablet>
+        </image>
+        </krpano>"#.as_bytes(),
+    ).unwrap();
+    let names: Vec<String> = levels.iter().map(|l| format!("{:?}", l)).collect();
+    // The tiled desktop level has no tile size, and is skipped.
+    // Faces and cube panorama for the untiled desktop level and for tablets, and image files for mobile devices
+    assert_eq!(names.len(), (6 + 1) + (6 + 1) + 6);
+    assert!(names.contains(&"Krpano Mobile Cube up".to_string()));
+    assert!(names.contains(&"Krpano Tablet cube panorama".to_string()));
+    let mut desktop = levels.into_iter().filter(|l| format!("{:?}", l) == "Krpano Cube up");
+    let mut up = desktop.next().unwrap();
+    assert!(desktop.next().is_none());
+    // Images without tile coordinates are made of a single tile
+    assert_eq!(up.size_hint(), Some(Vec2d::square(1000)));
+    assert_eq!(up.next_tiles(None), vec![TileReference {
+        url: "http://test.com/preview/top.jpg".to_string(),
+        position: Vec2d::default(),
+    }]);
+}
+
+#[test]
+fn test_mobile_image_file() {
+    let levels = load_from_properties(
+        "http://test.com/tour.xml",
+        r#"<krpano><image><mobile><cube url="mobile_%s.jpg" /></mobile></image></krpano>"#.as_bytes(),
+    ).unwrap();
+    assert_eq!(levels.len(), 6);
+    let mut back = levels.into_iter().nth(1).unwrap();
+    assert_eq!(format!("{:?}", back), "Krpano Mobile Cube back");
+    assert_eq!(back.size_hint(), None);
+    assert_eq!(back.next_tiles(None), vec![TileReference {
+        url: "http://test.com/mobile_b.jpg".to_string(),
+        position: Vec2d::default(),
+    }]);
+}
+
 #[test]
 fn test_flat_multires() {
     let mut levels = load_from_properties(
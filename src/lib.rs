@@ -1,24 +1,34 @@
 use std::{fs, fmt, io};
-use std::io::BufRead;
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
 use std::path::PathBuf;
 use std::time::Duration;
 
-use futures::stream::StreamExt;
-use indicatif::{ProgressBar, ProgressStyle};
+use futures::FutureExt;
+use futures::stream::{FuturesUnordered, StreamExt};
 use itertools::Itertools;
 use log::{debug, info, warn};
-use reqwest::Client;
+use reqwest::StatusCode;
 
-pub use arguments::Arguments;
+pub use arguments::{Arguments, ImageSelection};
+pub use region::Region;
 use dezoomer::{PostProcessFn, TileFetchResult, ZoomLevel, ZoomLevelIter};
 use dezoomer::{Dezoomer, DezoomerError, DezoomerInput, ZoomLevels};
-use dezoomer::TileReference;
+use dezoomer::{ImageReference, TileReference};
 pub use errors::ZoomError;
-use network::{client, fetch_uri};
-use output_file::get_outname;
+use network::{client, fetch_uri_with_headers, HttpClient};
+use output_file::{get_outname, numbered_outfile};
+pub use progress::{NoProgress, ProgressFormat, ProgressObserver};
+pub use batch::{BatchReport, dezoomify_batch};
+pub use builder::{DezoomifyBuilder, LevelInfo};
+use progress::{CliProgress, ErrorReport, OutputReport, ProgressEvent};
 use tile::Tile;
+use tile_cache::TileCache;
+use concurrency::failure_outcome;
+use rate_limit::Outcome;
 pub use vec2d::Vec2d;
 
+use crate::encoder::equirectangular::equirectangular_size;
 use crate::encoder::tile_buffer::TileBuffer;
 use crate::output_file::reserve_output_file;
 use std::error::Error;
@@ -32,6 +42,14 @@ mod vec2d;
 mod errors;
 mod output_file;
 mod network;
+mod tile_cache;
+mod region;
+mod progress;
+mod batch;
+mod builder;
+mod rate_limit;
+mod concurrency;
+mod cookies;
 
 pub mod auto;
 pub mod custom_yaml;
@@ -43,6 +61,11 @@ pub mod pff;
 pub mod zoomify;
 pub mod krpano;
 pub mod iipimage;
+pub mod har;
+pub mod page;
+pub mod xyz;
+pub mod wmts;
+pub mod arcgis;
 
 fn stdin_line() -> Result<String, ZoomError> {
     let stdin = std::io::stdin();
@@ -56,7 +79,7 @@ fn stdin_line() -> Result<String, ZoomError> {
 
 async fn list_tiles(
     dezoomer: &mut dyn Dezoomer,
-    http: &Client,
+    http: &HttpClient,
     uri: &str,
 ) -> Result<ZoomLevels, ZoomError> {
     let mut i = DezoomerInput {
@@ -67,31 +90,82 @@ async fn list_tiles(
         match dezoomer.zoom_levels(&i) {
             Ok(levels) => return Ok(levels),
             Err(DezoomerError::NeedsData { uri }) => {
-                let contents = fetch_uri(&uri, http).await?;
+                let contents = fetch_uri_with_headers(&uri, &dezoomer.http_headers(), http).await?;
                 debug!("Downloaded metadata file {}: '{}'", uri, String::from_utf8_lossy(&contents));
                 i.uri = uri;
                 i.contents = Some(contents);
             }
+            Err(DezoomerError::NeedsTile { uri }) => {
+                let contents = fetch_probed_tile(&uri, &dezoomer.http_headers(), http).await?;
+                i.uri = uri;
+                i.contents = Some(contents);
+            }
             Err(e) => return Err(e.into()),
         }
     }
 }
 
+/// Number of times a tile requested by a dezoomer is requested again when the server is overloaded
+const PROBED_TILE_RETRIES: usize = 5;
+
+/// Fetches a tile that a dezoomer requests to find the size of the image.
+/// Missing tiles are expected, and are returned as empty.
+/// When the server is overloaded, the tile is requested again once the rate limits of the client,
+/// which follow the Retry-After header of the server, allow it.
+async fn fetch_probed_tile(
+    uri: &str,
+    headers: &HashMap<String, String>,
+    http: &HttpClient,
+) -> Result<Vec<u8>, ZoomError> {
+    let mut retries = 0;
+    loop {
+        match fetch_uri_with_headers(uri, headers, http).await {
+            Ok(contents) => return Ok(contents),
+            Err(e) if is_missing(&e) => {
+                debug!("The tile {} does not exist: {}", uri, e);
+                return Ok(vec![]);
+            }
+            Err(e) if retries < PROBED_TILE_RETRIES && failure_outcome(&e) != Outcome::OtherError => {
+                warn!("{}. Requesting the tile {} again.", e, uri);
+                retries += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Whether an error means that the requested file does not exist.
+/// Other client errors, such as 403 or 429, can come from a server that blocks the requests.
+fn is_missing(err: &ZoomError) -> bool {
+    match err {
+        ZoomError::Networking { source } =>
+            matches!(source.status(), Some(StatusCode::NOT_FOUND) | Some(StatusCode::GONE)),
+        ZoomError::Io { source } => source.kind() == io::ErrorKind::NotFound,
+        _ => false,
+    }
+}
+
+/// Where the interactive questions are written.
+/// In json mode, the standard output is reserved for the progress events.
+fn prompt_output(args: &Arguments) -> Box<dyn Write> {
+    if args.progress == ProgressFormat::Json { Box::new(io::stderr()) } else { Box::new(io::stdout()) }
+}
+
 /// An interactive level picker
-fn level_picker(mut levels: Vec<ZoomLevel>) -> Result<ZoomLevel, ZoomError> {
-    println!("Found the following zoom levels:");
+fn level_picker(mut levels: Vec<ZoomLevel>, out: &mut dyn Write) -> Result<ZoomLevel, ZoomError> {
+    writeln!(out, "Found the following zoom levels:")?;
     for (i, level) in levels.iter().enumerate() {
-        println!("{}. {}", i, level.name());
+        writeln!(out, "{}. {}", i, level.name())?;
     }
     loop {
-        println!("Which level do you want to download? ");
+        writeln!(out, "Which level do you want to download? ")?;
         let line = stdin_line()?;
         if let Ok(idx) = line.parse::<usize>() {
             if levels.get(idx).is_some() {
                 return Ok(levels.swap_remove(idx));
             }
         }
-        println!("'{}' is not a valid level number", line);
+        writeln!(out, "'{}' is not a valid level number", line)?;
     }
 }
 
@@ -110,109 +184,334 @@ fn choose_level(mut levels: Vec<ZoomLevel>, args: &Arguments) -> Result<ZoomLeve
             if let Some((i, _)) = pos {
                 Ok(levels.swap_remove(i))
             } else {
-                level_picker(levels)
+                level_picker(levels, &mut prompt_output(args))
             }
         }
     }
 }
 
-fn display_err<T, E: std::fmt::Display>(res: Result<T, E>) -> Option<T> {
-    match res {
-        Ok(value) => Some(value),
-        Err(e) => {
-            eprintln!("{}", e);
-            None
+/// An interactive picker for inputs that contain several images
+fn image_picker(images: &[ImageReference], out: &mut dyn Write) -> Result<ImageSelection, ZoomError> {
+    writeln!(out, "Found the following images:")?;
+    for (i, image) in images.iter().enumerate() {
+        writeln!(out, "{}. {}", i + 1, image.title.as_deref().unwrap_or(&image.uri))?;
+    }
+    loop {
+        writeln!(out, "Which images do you want to download? (for instance: 1,4-6 or all) ")?;
+        let line = stdin_line()?;
+        match line.parse() {
+            Ok(selection) => return Ok(selection),
+            Err(e) => writeln!(out, "{}", e)?,
         }
     }
 }
 
-fn progress_bar(n: usize) -> ProgressBar {
-    let progress = ProgressBar::new(n as u64);
-    progress.set_style(
-        ProgressStyle::default_bar()
-            .template("[ETA:{eta}] {bar:40.cyan/blue} {pos:>4}/{len:4} {msg}")
-            .progress_chars("##-"),
-    );
-    progress
+fn choose_images(images: &[ImageReference], args: &Arguments) -> Result<ImageSelection, ZoomError> {
+    match &args.images {
+        Some(selection) => Ok(selection.clone()),
+        None if images.len() <= 1 => Ok("all".parse().expect("'all' is a valid selection")),
+        None => image_picker(images, &mut prompt_output(args)),
+    }
 }
 
-async fn find_zoomlevel(args: &Arguments) -> Result<ZoomLevel, ZoomError> {
+async fn find_zoomlevel(args: &Arguments, uri: &str) -> Result<ZoomLevel, ZoomError> {
     let mut dezoomer = args.find_dezoomer()?;
-    let uri = args.choose_input_uri()?;
-    let http_client = client(args.headers(), args, Some(&uri))?;
+    let http_client = client(args.headers(), args, Some(uri))?;
     info!("Trying to locate a zoomable image...");
-    let zoom_levels: Vec<ZoomLevel> = list_tiles(dezoomer.as_mut(), &http_client, &uri).await?;
+    let zoom_levels: Vec<ZoomLevel> = list_tiles(dezoomer.as_mut(), &http_client, uri).await?;
     info!("Found {} zoom levels", zoom_levels.len());
-    choose_level(zoom_levels, args)
+    choose_level(cube_levels(zoom_levels, args)?, args)
+}
+
+/// The cube levels, that contain the six faces of a cube panorama side by side,
+/// are only offered when an equirectangular panorama is requested.
+/// They are then the only ones that can be downloaded.
+fn cube_levels(mut levels: Vec<ZoomLevel>, args: &Arguments) -> Result<Vec<ZoomLevel>, ZoomError> {
+    if args.equirectangular && args.region.is_none() {
+        levels.retain(|level| level.is_cube());
+        if levels.is_empty() { return Err(ZoomError::NoCubeLevel); }
+    } else {
+        levels.retain(|level| !level.is_cube());
+    }
+    Ok(levels)
+}
+
+/// Whether the faces of the cube panorama in the zoom level will be reprojected
+fn reprojects_cube(args: &Arguments, zoom_level: &ZoomLevel) -> bool {
+    args.equirectangular && args.region.is_none() && zoom_level.is_cube()
+}
+
+/// An image file created by dezoomify
+struct SavedImage {
+    path: PathBuf,
+    size: Option<Vec2d>,
+}
+
+impl SavedImage {
+    /// The image that is still created when some of its tiles could not be downloaded
+    fn from_partial_download(err: &ZoomError) -> Option<Self> {
+        match err {
+            ZoomError::PartialDownload { destination: Some(path), size, .. } => {
+                Some(SavedImage { path: path.clone(), size: *size })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The image files created for an input, and the error that occurred, if any.
+/// Files are still created when some of the tiles or images could not be downloaded.
+struct InputOutcome {
+    saved: Vec<SavedImage>,
+    error: Option<ZoomError>,
+}
+
+impl InputOutcome {
+    fn from_result(result: Result<SavedImage, ZoomError>) -> Self {
+        match result {
+            Ok(saved) => InputOutcome { saved: vec![saved], error: None },
+            Err(e) => InputOutcome { saved: SavedImage::from_partial_download(&e).into_iter().collect(), error: Some(e) },
+        }
+    }
+
+    /// In json mode, writes the paths of the created files, and the error that occurred, if any
+    fn report(&self, args: &Arguments) {
+        args.progress.emit(&ProgressEvent::Summary {
+            success: self.error.is_none(),
+            outputs: self.saved.iter().map(|s| OutputReport::new(&s.path, s.size)).collect(),
+            error: self.error.as_ref().map(ErrorReport::from),
+        });
+    }
+
+    fn into_result(self) -> Result<Vec<SavedImage>, ZoomError> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self.saved),
+        }
+    }
 }
 
+/// Downloads the image at the input uri, and returns the path of the created file.
+/// Inputs that contain several images are rejected with a `MultipleImages` error
+/// listing them: use `dezoomify_all` to download all of them.
 pub async fn dezoomify(args: &Arguments) -> Result<PathBuf, ZoomError> {
-    let zoom_level = find_zoomlevel(&args).await?;
-    let outname = get_outname(&args.outfile, &zoom_level.title(), zoom_level.size_hint());
+    let outcome = InputOutcome::from_result(dezoomify_single(args).await);
+    outcome.report(args);
+    outcome.into_result().map(|mut saved| saved.remove(0).path)
+}
+
+/// Downloads the image (or images) at the input uri, and returns the paths of the created files
+pub async fn dezoomify_all(args: &Arguments) -> Result<Vec<PathBuf>, ZoomError> {
+    let outcome = dezoomify_input(args).await;
+    outcome.report(args);
+    outcome.into_result().map(|saved| saved.into_iter().map(|s| s.path).collect())
+}
+
+async fn dezoomify_single(args: &Arguments) -> Result<SavedImage, ZoomError> {
+    let uri = args.choose_input_uri()?;
+    let zoom_level = find_zoomlevel(args, &uri).await?;
+    dezoomify_to_file(args, zoom_level, &args.outfile, None).await
+}
+
+async fn dezoomify_input(args: &Arguments) -> InputOutcome {
+    let uri = match args.choose_input_uri() {
+        Ok(uri) => uri,
+        Err(e) => return InputOutcome::from_result(Err(e)),
+    };
+    match find_zoomlevel(args, &uri).await {
+        Err(ZoomError::Dezoomer { source: DezoomerError::MultipleImages { images } }) => {
+            dezoomify_images(args, images).await
+        }
+        Ok(zoom_level) => InputOutcome::from_result(dezoomify_to_file(args, zoom_level, &args.outfile, None).await),
+        Err(e) => InputOutcome::from_result(Err(e)),
+    }
+}
+
+async fn dezoomify_images(args: &Arguments, images: Vec<ImageReference>) -> InputOutcome {
+    let selection = match choose_images(&images, args) {
+        Ok(selection) => selection,
+        Err(e) => return InputOutcome::from_result(Err(e)),
+    };
+    let number_of_images = images.len();
+    let number_width = number_of_images.to_string().len();
+    let mut saved = vec![];
+    let mut total_images = 0;
+    let mut successful_images = 0;
+    for (i, image) in images.into_iter().enumerate() {
+        if !selection.contains(i + 1) { continue; }
+        total_images += 1;
+        let number = format!("{:0width$}", i + 1, width = number_width);
+        info!("Downloading image {}: {}", number, image.uri);
+        let outfile = args.outfile.as_ref().map(|path| numbered_outfile(path, &number));
+        let title = Some(match &image.title {
+            Some(title) => format!("{} {}", number, title),
+            None => number,
+        });
+        let result = match find_zoomlevel(args, &image.uri).await {
+            Ok(zoom_level) => dezoomify_to_file(args, zoom_level, &outfile, title).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(saved_image) => {
+                info!("Saved image {} to {:?}", i + 1, saved_image.path);
+                successful_images += 1;
+                saved.push(saved_image)
+            }
+            Err(e) => {
+                warn!("Unable to download image {} ({}): {}", i + 1, image.uri, e);
+                saved.extend(SavedImage::from_partial_download(&e));
+            }
+        }
+    }
+    let error = if total_images == 0 {
+        Some(ZoomError::EmptyImageSelection { available_images: number_of_images })
+    } else if successful_images < total_images {
+        Some(ZoomError::PartialImageList { successful_images, total_images })
+    } else {
+        None
+    };
+    InputOutcome { saved, error }
+}
+
+async fn dezoomify_to_file(
+    args: &Arguments,
+    zoom_level: ZoomLevel,
+    outfile: &Option<PathBuf>,
+    title: Option<String>,
+) -> Result<SavedImage, ZoomError> {
+    let title = title.or_else(|| zoom_level.title());
+    // The region is checked before the output file is created, so that an invalid region leaves no empty file
+    let size_hint = output_size_hint(args, &zoom_level)?;
+    let outname = get_outname(outfile, &title, size_hint, args.resume);
     let save_as = fs::canonicalize(outname.as_path()).unwrap_or_else(|_e| outname.clone());
-    reserve_output_file(&save_as)?;
-    let tile_buffer: TileBuffer = TileBuffer::new(save_as.clone(), args.compression).await?;
+    reserve_output_file(&save_as, args.resume)?;
+    let tile_buffer: TileBuffer = TileBuffer::new(save_as.clone(), args.compression, args.tiling_options()).await?;
     info!("Dezooming {}", zoom_level.name());
-    dezoomify_level(args, zoom_level, tile_buffer).await?;
-    Ok(save_as)
+    let size = dezoomify_level(args, zoom_level, tile_buffer).await?;
+    Ok(SavedImage { path: save_as, size: Some(size) })
+}
+
+/// The size of the image that will be created from the zoom level, if it is known.
+/// Fails if the region to download cannot be computed.
+fn output_size_hint(args: &Arguments, zoom_level: &ZoomLevel) -> Result<Option<Vec2d>, ZoomError> {
+    let level_size = zoom_level.size_hint();
+    Ok(match &args.region {
+        Some(region) => Some(region.resolve(level_size)?.size),
+        None if reprojects_cube(args, zoom_level) => level_size.map(equirectangular_size),
+        None => level_size,
+    })
 }
 
 pub async fn dezoomify_level(
     args: &Arguments,
-    mut zoom_level: ZoomLevel,
+    zoom_level: ZoomLevel,
     tile_buffer: TileBuffer,
-) -> Result<(), ZoomError> {
+) -> Result<Vec2d, ZoomError> {
     let level_headers = zoom_level.http_headers();
     let http_client = client(level_headers.iter().chain(args.headers()), &args, None)?;
+    let observer = CliProgress::new(args.progress);
+    download_level(args, zoom_level, tile_buffer, &observer, &http_client).await
+}
+
+/// Downloads all the tiles of a zoom level and adds them to the tile buffer.
+/// Returns the size of the resulting image.
+async fn download_level(
+    args: &Arguments,
+    mut zoom_level: ZoomLevel,
+    tile_buffer: TileBuffer,
+    observer: &dyn ProgressObserver,
+    http_client: &HttpClient,
+) -> Result<Vec2d, ZoomError> {
+    observer.level_chosen(&zoom_level.name(), zoom_level.size_hint());
+    let tile_cache = match &args.cache_dir {
+        Some(dir) => {
+            info!("Using the tile cache at {:?}", dir);
+            Some(TileCache::new(dir.clone(), zoom_level.http_headers().iter().chain(args.headers()))?)
+        }
+        None => None,
+    };
 
     info!("Creating canvas");
     let mut canvas = tile_buffer;
+    if reprojects_cube(args, &zoom_level) {
+        info!("Reprojecting the cube faces into an equirectangular panorama");
+        canvas.reproject_cube();
+    }
 
-    let progress = progress_bar(0);
     let mut total_tiles = 0u64;
     let mut successful_tiles = 0u64;
 
     let post_process_fn = zoom_level.post_process_fn();
-
-    progress.set_message("Computing the URLs of the image tiles...");
+    let mut concurrency = args.concurrency_controller();
+    observer.parallelism_changed(concurrency.limit());
 
     let mut zoom_level_iter = ZoomLevelIter::new(&mut zoom_level);
     let mut last_count = 0;
     let mut last_successes = 0;
-    while let Some(tile_refs) = zoom_level_iter.next_tile_references() {
+    while let Some(mut tile_refs) = zoom_level_iter.next_tile_references() {
+        let size_hint = zoom_level_iter.size_hint();
+        let region = match &args.region {
+            Some(region) => Some(region.resolve(size_hint)?),
+            None => None,
+        };
+        // Dezoomers that do not know the size of the level need all the tiles to find it
+        if let (Some(region), Some(_)) = (&region, size_hint) {
+            tile_refs = region.filter_tiles(tile_refs);
+        }
         last_count = tile_refs.len() as u64;
         total_tiles += last_count;
-        progress.set_length(total_tiles);
-
-        progress.set_message("Requesting the tiles...");
+        observer.tiles_listed(last_count, total_tiles);
 
         let &Arguments { retries, retry_delay, .. } = args;
-        let mut stream = futures::stream::iter(tile_refs)
-            .map(|tile_ref: TileReference|
-                download_tile(post_process_fn, tile_ref, &http_client, retries, retry_delay))
-            .buffer_unordered(args.parallelism);
+        let mut pending_tiles = tile_refs.into_iter();
+        let mut in_flight = FuturesUnordered::new();
 
         last_successes = 0;
         let mut tile_size = None;
 
-        if let Some(size) = zoom_level_iter.size_hint() {
-            canvas.set_size(size).await?;
+        if let Some(size) = size_hint {
+            canvas.set_size(region.map_or(size, |r| r.size)).await?;
         }
 
-        while let Some(tile_result) = stream.next().await {
+        loop {
+            while in_flight.len() < concurrency.limit() {
+                let tile_ref = match pending_tiles.next() {
+                    Some(tile_ref) => tile_ref,
+                    None => break,
+                };
+                let url = tile_ref.url.clone();
+                in_flight.push(
+                    download_tile(post_process_fn, tile_ref, http_client, tile_cache.as_ref(), retries, retry_delay)
+                        .map(move |result| (url, result))
+                );
+            }
+            let (url, tile_result) = match in_flight.next().await {
+                Some(result) => result,
+                None => break,
+            };
             debug!("Received tile result: {:?}", tile_result);
-            progress.inc(1);
-            let tile = match tile_result {
+            // Only the time the server took to answer is measured, without retries and rate limits.
+            // Tiles that come from the cache or from local files say nothing about the server.
+            let outcome = match &tile_result {
+                Ok((_, Some(latency))) => Some((Outcome::Success, *latency)),
+                Ok((_, None)) => None,
+                Err(err) => Some((failure_outcome(&err.cause), Duration::default())),
+            };
+            let changed = outcome.and_then(|(outcome, latency)| concurrency.record(outcome, latency));
+            if let Some(parallelism) = changed {
+                observer.parallelism_changed(parallelism);
+            }
+            let tile = match tile_result.map(|(tile, _)| tile) {
                 Ok(tile) => {
-                    progress.set_message(&format!("Downloaded tile at {}", tile.position()));
+                    observer.tile_downloaded(&url, tile.position());
                     tile_size.replace(tile.size());
                     last_successes += 1;
                     Some(tile)
                 }
                 Err(err) => {
                     // If a tile download fails, we replace it with an empty tile
-                    progress.set_message(&err.to_string());
                     let position = err.tile_reference.position;
+                    observer.tile_failed(&url, position, &err.cause);
                     tile_size.and_then(|tile_size| {
                         zoom_level_iter.size_hint().map(|canvas_size| {
                             let size = max_size_in_rect(position, tile_size, canvas_size);
@@ -221,7 +520,13 @@ pub async fn dezoomify_level(
                     })
                 }
             };
-            if let Some(tile) = tile { display_err(canvas.add_tile(tile).await); }
+            let tile = match &region {
+                Some(region) => tile.and_then(|tile| region.crop_tile(tile)),
+                None => tile,
+            };
+            if let Some(tile) = tile {
+                if let Err(e) = canvas.add_tile(tile).await { warn!("Unable to add a tile to the image: {}", e); }
+            }
         }
         successful_tiles += last_successes;
         zoom_level_iter.set_fetch_result(TileFetchResult {
@@ -231,42 +536,49 @@ pub async fn dezoomify_level(
         });
     }
 
-    progress.set_message("Downloaded all tiles. Finalizing the image file.");
+    observer.finalizing();
     canvas.finalize().await?;
 
-    progress.finish_with_message("Finished tile download");
-    if successful_tiles == 0 { return Err(ZoomError::NoTile); }
-
-    if last_successes < last_count {
-        Err(ZoomError::PartialDownload { successful_tiles, total_tiles })
+    let result = if successful_tiles == 0 {
+        Err(ZoomError::NoTile)
+    } else if last_successes < last_count {
+        Err(ZoomError::PartialDownload {
+            successful_tiles,
+            total_tiles,
+            destination: canvas.destination().map(PathBuf::from),
+            size: canvas.size(),
+        })
     } else {
-        Ok(())
-    }
+        Ok(canvas.size().unwrap_or_default())
+    };
+    observer.image_finished(canvas.destination(), canvas.size(), successful_tiles, total_tiles, &result);
+    result
 }
 
 async fn download_tile(
     post_process_fn: PostProcessFn,
     tile_reference: TileReference,
-    client: &reqwest::Client,
+    client: &HttpClient,
+    cache: Option<&TileCache>,
     retries: usize,
     retry_delay: Duration,
-) -> Result<Tile, TileDownloadError> {
-    let mut res = Tile::download(post_process_fn, &tile_reference, client).await;
+) -> Result<(Tile, Option<Duration>), TileDownloadError> {
+    let mut res = Tile::download(post_process_fn, &tile_reference, client, cache).await;
     // The initial delay after which a failed request is retried depends on the position of the tile
-    // in order to avoid sending repeated "bursts" of requests to a server that is struggling
+    // in order to avoid sending repeated "bursts" of requests to a server that is struggling.
+    // Servers that keep failing are slowed down further by the rate limits of the http client,
+    // which apply in addition to this delay.
     let n = 100;
     let idx: f64 = ((tile_reference.position.x + tile_reference.position.y) % n).into();
     let mut wait_time = retry_delay + Duration::from_secs_f64(idx * retry_delay.as_secs_f64() / n as f64);
     for _ in 0..retries {
-        res = Tile::download(post_process_fn, &tile_reference, client).await;
         match &res {
-            Ok(_) => { break; },
-            Err(e) => {
-                warn!("{}. Retrying tile download in {:?}.", e, wait_time);
-                tokio::time::delay_for(wait_time).await;
-                wait_time *= 2;
-            }
+            Ok(_) => break,
+            Err(e) => warn!("{}. Retrying tile download in {:?}.", e, wait_time),
         }
+        tokio::time::delay_for(wait_time).await;
+        wait_time *= 2;
+        res = Tile::download(post_process_fn, &tile_reference, client, cache).await;
     }
     res.map_err(|cause| TileDownloadError { tile_reference, cause })
 }
@@ -2,7 +2,7 @@ use colour::{green_ln, red_ln};
 use human_panic::setup_panic;
 use structopt::StructOpt;
 
-use dezoomify_rs::{Arguments, dezoomify, ZoomError};
+use dezoomify_rs::{Arguments, dezoomify_all, dezoomify_batch, ZoomError};
 
 #[tokio::main]
 async fn main() {
@@ -12,10 +12,29 @@ async fn main() {
     let args: Arguments = Arguments::from_args();
     init_log(&args);
 
-    loop {
-        match dezoomify(&args).await {
+    if let Some(batch_file) = &args.batch {
+        match dezoomify_batch(&args, batch_file).await {
+            Ok(report) if report.is_success() => {
+                if !args.progress.is_json() { green_ln!("{}", report.to_string().trim_end()); }
+            }
+            Ok(report) => {
+                if !args.progress.is_json() { red_ln!("{}", report.to_string().trim_end()); }
+                std::process::exit(1);
+            }
             Err(err) => {
                 red_ln!("ERROR {}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    loop {
+        // In JSON mode, the result is reported by dezoomify itself
+        let json = args.progress.is_json();
+        match dezoomify_all(&args).await {
+            Err(err) => {
+                if !json { red_ln!("ERROR {}", err); }
                 has_errors = true;
                 // If we have reached the end of stdin, we exit
                 if let ZoomError::Io { source } = err {
@@ -24,13 +43,16 @@ async fn main() {
                     }
                 }
             },
-            Ok(saved_as) => {
-                green_ln!("Image successfully saved to '{}' (current working directory: {})",
-                         saved_as.to_string_lossy(),
-                         std::env::current_dir()
-                             .map(|p| p.to_string_lossy().to_string())
-                             .unwrap_or_else(|_e| "unknown".into())
-                );
+            Ok(_) if json => {}
+            Ok(saved) => {
+                for saved_as in saved {
+                    green_ln!("Image successfully saved to '{}' (current working directory: {})",
+                             saved_as.to_string_lossy(),
+                             std::env::current_dir()
+                                 .map(|p| p.to_string_lossy().to_string())
+                                 .unwrap_or_else(|_e| "unknown".into())
+                    );
+                }
             }
         }
         if has_args {
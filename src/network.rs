@@ -1,55 +1,345 @@
 use std::collections::HashMap;
+use std::env;
+use std::io::SeekFrom;
 use std::iter::once;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use log::debug;
-use reqwest::{Client, header};
+use reqwest::{Certificate, Client, header, redirect, StatusCode};
 use tokio::fs;
+use tokio::io::AsyncReadExt;
 use url::Url;
 
 use crate::arguments::Arguments;
+use crate::cookies::CookieJar;
+use crate::rate_limit::{Outcome, RateLimiter};
 use crate::ZoomError;
 
 /// Fetch data, either from an URL or a path to a local file.
 /// If uri doesnt start with "http(s)://", it is considered to be a path
-/// to a local file
-// TODO: return Bytes
-pub async fn fetch_uri(uri: &str, http: &Client) -> Result<Vec<u8>, ZoomError> {
+/// to a local file.
+/// An uri ending with `#bytes=first-last` designates only the bytes
+/// from `first` to `last` (included) of the file.
+/// The given http headers are added to the request.
+pub async fn fetch_uri_with_headers(
+    uri: &str,
+    headers: &HashMap<String, String>,
+    http: &HttpClient,
+) -> Result<Vec<u8>, ZoomError> {
+    fetch_uri_timed(uri, headers, http).await.map(|(contents, _)| contents)
+}
+
+/// Fetch data like `fetch_uri_with_headers`, and return the time the server took to send it,
+/// without the time spent waiting for the rate limits. The time is None for local files.
+pub async fn fetch_uri_timed(
+    uri: &str,
+    headers: &HashMap<String, String>,
+    http: &HttpClient,
+) -> Result<(Vec<u8>, Option<Duration>), ZoomError> {
+    let (uri, range) = split_byte_range(uri);
     if uri.starts_with("http://") || uri.starts_with("https://") {
         debug!("Loading url: '{}'", uri);
-        let response = http.get(uri).send().await?.error_for_status()?;
+        let origin = Url::parse(uri).map_err(|e| ZoomError::InvalidUrl { url: uri.to_string(), message: e.to_string() })?;
+        let mut url = origin.clone();
+        // Redirects are followed here rather than by reqwest, to keep the cookies set by each of them
+        let mut redirects = 0;
+        let mut latency = Duration::default();
+        let (response, start) = loop {
+            let host = url.host_str().unwrap_or_default().to_string();
+            http.rate_limiter.wait(&host).await;
+            let start = Instant::now();
+            let response = match build_request(http, &url, &origin, headers, range).send().await {
+                Ok(response) => response,
+                Err(e) => {
+                    http.rate_limiter.record(&host, Outcome::Overloaded { retry_after: None });
+                    return Err(e.into());
+                }
+            };
+            http.rate_limiter.record(&host, Outcome::from_response(response.status(), response.headers()));
+            match follow_redirect(http, &url, response.status(), response.headers()) {
+                Some(_) if redirects >= MAX_REDIRECTS => {
+                    return Err(ZoomError::TooManyRedirects { url: uri.to_string() });
+                }
+                Some(next) => {
+                    debug!("Following the redirect from '{}' to '{}'", url, next);
+                    url = next;
+                    redirects += 1;
+                    latency += start.elapsed();
+                }
+                None => break (response, start),
+            }
+        };
+        let response = response.error_for_status()?;
+        let partial = response.status() == reqwest::StatusCode::PARTIAL_CONTENT;
         let mut contents = Vec::new();
         contents.extend(response.bytes().await?);
+        latency += start.elapsed();
         debug!("Loaded url: '{}'", uri);
-        Ok(contents)
+        match range {
+            // The server ignored the range request and sent the whole file
+            Some((first, last)) if !partial => Ok((slice_range(contents, first, last), Some(latency))),
+            _ => Ok((contents, Some(latency))),
+        }
+    } else if let Some((first, last)) = range {
+        debug!("Loading bytes {} to {} of file: '{}'", first, last, uri);
+        let mut file = fs::File::open(uri).await?;
+        file.seek(SeekFrom::Start(first)).await?;
+        let mut result = Vec::new();
+        file.take(last + 1 - first).read_to_end(&mut result).await?;
+        Ok((result, None))
     } else {
         debug!("Loading file: '{}'", uri);
         let result = fs::read(uri).await?;
         debug!("Loaded file: '{}'", uri);
-        Ok(result)
+        Ok((result, None))
+    }
+}
+
+/// Maximum number of redirects followed for a single request, as in the default policy of reqwest
+const MAX_REDIRECTS: usize = 10;
+
+/// Keeps the cookies set by a response, and returns the url it redirects to, if any
+fn follow_redirect(http: &HttpClient, url: &Url, status: StatusCode, headers: &header::HeaderMap) -> Option<Url> {
+    http.cookies.store_response(url, headers);
+    match status {
+        StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND | StatusCode::SEE_OTHER |
+        StatusCode::TEMPORARY_REDIRECT | StatusCode::PERMANENT_REDIRECT => {
+            let location = headers.get(header::LOCATION)?.to_str().ok()?;
+            url.join(location).ok()
+        }
+        _ => None,
+    }
+}
+
+/// The request for an http url, with the headers of the client, the given headers,
+/// and the cookies of the jar.
+/// When a redirect leads from the requested url (`origin`) to another server,
+/// the credentials given in the headers are not sent to it: only the cookies of the jar that match it are.
+fn build_request(
+    http: &HttpClient,
+    url: &Url,
+    origin: &Url,
+    headers: &HashMap<String, String>,
+    range: Option<(u64, u64)>,
+) -> reqwest::RequestBuilder {
+    let mut request = http.client.get(url.clone());
+    if let Some((first, last)) = range {
+        request = request.header(header::RANGE, format!("bytes={}-{}", first, last));
+    }
+    let same_origin = url.origin() == origin.origin();
+    let mut default_cookie = http.default_cookie.as_deref().filter(|_| same_origin);
+    let client_headers = http.headers.iter()
+        .filter(|(name, _)| !headers.keys().any(|n| n.eq_ignore_ascii_case(name)));
+    for (name, value) in client_headers.chain(headers) {
+        if !same_origin && is_credential(name) {
+            debug!("Not sending the {} header to {}", name, url);
+        } else if name.eq_ignore_ascii_case("cookie") {
+            default_cookie = Some(value);
+        } else {
+            request = request.header(name.as_str(), value.as_str());
+        }
+    }
+    if let Some(cookie) = http.cookie_header(url, default_cookie) {
+        request = request.header(header::COOKIE, cookie);
+    }
+    request
+}
+
+/// Whether a header may carry the credentials of the user, such as Authorization, Cookie or X-Api-Key.
+/// These headers are not given to reqwest as default headers, so that they can be left out
+/// of the requests that are redirected to other servers.
+fn is_credential(name: &str) -> bool {
+    const PARTS: [&str; 6] = ["auth", "cookie", "token", "key", "secret", "session"];
+    let name = name.to_ascii_lowercase();
+    PARTS.iter().any(|part| name.contains(part))
+}
+
+/// The uri of a part of a file, as understood by `fetch_uri_with_headers`
+pub fn byte_range_uri(uri: &str, first: u64, last: u64) -> String {
+    format!("{}#bytes={}-{}", uri, first, last)
+}
+
+/// Separates the uri of a file from the range of bytes requested in it, if any
+fn split_byte_range(uri: &str) -> (&str, Option<(u64, u64)>) {
+    let mut parts = uri.rsplitn(2, "#bytes=");
+    let range = parts.next().unwrap_or_default();
+    match (parts.next(), range_bounds(range)) {
+        (Some(file), Some((first, last))) if first <= last => (file, Some((first, last))),
+        _ => (uri, None),
     }
 }
 
+fn range_bounds(range: &str) -> Option<(u64, u64)> {
+    let mut bounds = range.splitn(2, '-');
+    let first = bounds.next()?.parse().ok()?;
+    let last = bounds.next()?.parse().ok()?;
+    Some((first, last))
+}
+
+fn slice_range(mut contents: Vec<u8>, first: u64, last: u64) -> Vec<u8> {
+    let len = contents.len() as u64;
+    contents.truncate((last + 1).min(len) as usize);
+    contents.drain(..first.min(len) as usize);
+    contents
+}
+
+/// An http client that limits the rate of the requests it sends to each server,
+/// and keeps the cookies they set
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    pub client: Client,
+    pub rate_limiter: RateLimiter,
+    pub cookies: Arc<CookieJar>,
+    /// The Cookie header given by the user, sent with the cookies of the jar
+    pub default_cookie: Option<String>,
+    /// Headers added to each request, when the client was not created with them
+    pub headers: HashMap<String, String>,
+}
+
+impl HttpClient {
+    pub fn new(client: Client, args: &Arguments) -> Result<Self, ZoomError> {
+        if let Some(path) = &args.cookies {
+            args.cookie_jar.load_file(path)?;
+        }
+        Ok(HttpClient {
+            client,
+            rate_limiter: RateLimiter::new(args.rate_limits(), Arc::clone(&args.host_states)),
+            cookies: Arc::clone(&args.cookie_jar),
+            default_cookie: None,
+            headers: HashMap::new(),
+        })
+    }
+
+    /// Sends these headers with each request. Later headers replace the previous ones with the same name.
+    pub fn with_headers<'a, I: Iterator<Item=(&'a String, &'a String)>>(mut self, headers: I) -> Self {
+        for (name, value) in headers {
+            if name.eq_ignore_ascii_case("cookie") {
+                self.default_cookie = Some(value.clone());
+            } else {
+                self.headers.retain(|n, _| !n.eq_ignore_ascii_case(name));
+                self.headers.insert(name.clone(), value.clone());
+            }
+        }
+        self
+    }
+
+    /// The Cookie header to send with a request to the given url:
+    /// the given cookies, followed by the ones of the jar
+    fn cookie_header(&self, url: &Url, default_cookie: Option<&str>) -> Option<String> {
+        match (default_cookie, self.cookies.cookie_header(url)) {
+            (Some(default), Some(cookies)) => Some(format!("{}; {}", default, cookies)),
+            (default, cookies) => cookies.or_else(|| default.map(String::from)),
+        }
+    }
+}
 
 pub fn client<'a, I: Iterator<Item=(&'a String, &'a String)>>(
     headers: I,
     args: &Arguments,
     uri: Option<&str>,
-) -> Result<reqwest::Client, ZoomError> {
+) -> Result<HttpClient, ZoomError> {
+    let referer_name = "Referer".to_string();
     let referer = uri.or_else(|| args.input_uri.as_deref()).unwrap_or("").to_string();
-    let header_map = default_headers()
-        .iter()
-        .chain(once((&"Referer".to_string(), &referer)))
-        .chain(headers.map(|(k, v)| (k, v)))
-        .map(|(name, value)| Ok((name.parse()?, value.parse()?)))
-        .collect::<Result<header::HeaderMap, ZoomError>>()?;
+    let defaults = default_headers();
+    let all_headers = defaults.iter()
+        .chain(once((&referer_name, &referer)))
+        .chain(headers.map(|(k, v)| (k, v)));
+    let mut header_map = header::HeaderMap::new();
+    let mut credentials = vec![];
+    // Later headers replace the previous ones with the same name
+    for (name, value) in all_headers {
+        let header_name = name.parse::<header::HeaderName>()?;
+        let header_value = value.parse::<header::HeaderValue>()?;
+        if is_credential(name) {
+            credentials.push((name, value));
+        } else {
+            header_map.insert(header_name, header_value);
+        }
+    }
     debug!("Creating an http client with the following headers: {:?}", header_map);
-    let client = reqwest::Client::builder()
-        .default_headers(header_map)
+    let mut builder = reqwest::Client::builder()
+        .default_headers(header_map.clone())
         .pool_max_idle_per_host(args.max_idle_per_host)
         .danger_accept_invalid_certs(args.accept_invalid_certs)
-        .timeout(args.timeout)
-        .build()?;
-    Ok(client)
+        .redirect(redirect::Policy::none())
+        .timeout(args.timeout);
+    if let Some(proxy_url) = &args.proxy {
+        builder = builder.proxy(proxy(proxy_url)?);
+    }
+    for path in &args.ca_certs {
+        for certificate in load_certificates(path)? {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    let http_client = HttpClient::new(builder.build()?, args)?;
+    Ok(http_client.with_headers(credentials.into_iter()))
+}
+
+/// A proxy for all the requests, except the ones to the hosts listed in the NO_PROXY environment variable.
+/// When no proxy is given, reqwest uses the HTTP_PROXY and HTTPS_PROXY environment variables itself.
+fn proxy(proxy_url: &str) -> Result<reqwest::Proxy, ZoomError> {
+    let invalid = |message: String| ZoomError::InvalidProxy { proxy: proxy_url.to_string(), message };
+    // Fail early on invalid proxies: errors in custom proxies are silently ignored by reqwest
+    let proxy = reqwest::Proxy::all(proxy_url).map_err(|e| invalid(e.to_string()))?;
+    let no_proxy = match env::var("NO_PROXY").or_else(|_| env::var("no_proxy")) {
+        Ok(no_proxy) if !no_proxy.trim().is_empty() => no_proxy,
+        _ => return Ok(proxy),
+    };
+    let url = Url::parse(proxy_url).map_err(|e| invalid(e.to_string()))?;
+    debug!("Using the proxy {}, except for the hosts in {:?}", proxy_url, no_proxy);
+    Ok(reqwest::Proxy::custom(move |target| match target.host_str() {
+        Some(host) if no_proxy_matches(&no_proxy, host) => None,
+        _ => Some(url.clone()),
+    }))
+}
+
+/// Whether a host is listed in a NO_PROXY value,
+/// which is a comma-separated list of domain names, or "*" for all hosts
+fn no_proxy_matches(no_proxy: &str, host: &str) -> bool {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    no_proxy.split(',')
+        .map(|entry| entry.trim().trim_start_matches('.'))
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| {
+            entry == "*" || host.eq_ignore_ascii_case(entry) || (
+                host.len() > entry.len()
+                    && host.as_bytes()[host.len() - entry.len() - 1] == b'.'
+                    && host[host.len() - entry.len()..].eq_ignore_ascii_case(entry)
+            )
+        })
+}
+
+/// Reads the certificates from a PEM file, which can contain several of them, or from a DER file
+fn load_certificates(path: &Path) -> Result<Vec<Certificate>, ZoomError> {
+    let contents = std::fs::read(path)?;
+    let invalid = |e: reqwest::Error| ZoomError::InvalidCertificate {
+        path: path.to_string_lossy().to_string(),
+        message: e.to_string(),
+    };
+    let text = String::from_utf8_lossy(&contents);
+    let blocks = pem_blocks(&text);
+    if blocks.is_empty() {
+        return Ok(vec![Certificate::from_der(&contents).map_err(invalid)?]);
+    }
+    blocks.into_iter().map(|block| Certificate::from_pem(block.as_bytes()).map_err(invalid)).collect()
+}
+
+fn pem_blocks(text: &str) -> Vec<&str> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+    let mut blocks = vec![];
+    let mut rest = text;
+    while let Some(start) = rest.find(BEGIN) {
+        let end = match rest[start..].find(END) {
+            Some(end) => start + end + END.len(),
+            None => break,
+        };
+        blocks.push(&rest[start..end]);
+        rest = &rest[end..];
+    }
+    blocks
 }
 
 pub fn default_headers() -> HashMap<String, String> {
@@ -62,7 +352,14 @@ pub fn resolve_relative(base: &str, path: &str) -> String {
             return r.to_string()
         }
     }
-    base.rsplit('/').next().unwrap_or_default().to_string() + path
+    // Local files
+    if base.contains("://") || path.starts_with('/') || Url::parse(path).is_ok() {
+        return path.to_string();
+    }
+    match base.rfind('/') {
+        Some(i) => base[..=i].to_string() + path,
+        None => path.to_string(),
+    }
 }
 
 pub fn remove_bom(contents: &[u8]) -> &[u8] {
@@ -73,3 +370,111 @@ pub fn remove_bom(contents: &[u8]) -> &[u8] {
         &contents[BOM.len()..]
     } else { contents }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_headers() {
+        let headers = [
+            ("Referer".to_string(), "http://viewer/".to_string()),
+            ("X-Token".to_string(), "client".to_string()),
+        ];
+        let http = HttpClient::new(Client::new(), &Arguments::default()).unwrap()
+            .with_headers(headers.iter().map(|(k, v)| (k, v)));
+        let url = Url::parse("http://example.com/tile.jpg").unwrap();
+        let mut request_headers = HashMap::new();
+        request_headers.insert("x-token".to_string(), "request".to_string());
+        let request = build_request(&http, &url, &url, &request_headers, None).build().unwrap();
+        assert_eq!(request.headers()["Referer"], "http://viewer/");
+        assert_eq!(request.headers().get_all("X-Token").iter().collect::<Vec<_>>(), vec!["request"]);
+    }
+
+    #[test]
+    fn test_redirect_cookies() {
+        let http = HttpClient::new(Client::new(), &Arguments::default()).unwrap();
+        let url = Url::parse("http://example.com/viewer/tile.jpg").unwrap();
+        let mut headers = header::HeaderMap::new();
+        headers.insert(header::SET_COOKIE, "session=abc; Path=/".parse().unwrap());
+        headers.insert(header::LOCATION, "/tiles/tile.jpg?session=1".parse().unwrap());
+        let next = follow_redirect(&http, &url, StatusCode::FOUND, &headers).unwrap();
+        assert_eq!(next.as_str(), "http://example.com/tiles/tile.jpg?session=1");
+        let request = build_request(&http, &next, &url, &HashMap::new(), None).build().unwrap();
+        assert_eq!(request.headers()[header::COOKIE], "session=abc");
+        assert_eq!(follow_redirect(&http, &next, StatusCode::OK, &header::HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn test_cross_host_redirect() {
+        let user_headers = [
+            ("Authorization".to_string(), "Bearer secret".to_string()),
+            ("Cookie".to_string(), "login=me".to_string()),
+            ("X-Api-Key".to_string(), "key".to_string()),
+            ("Referer".to_string(), "http://example.com/viewer".to_string()),
+        ];
+        let http = client(user_headers.iter().map(|(k, v)| (k, v)), &Arguments::default(), None).unwrap();
+        let url = Url::parse("http://example.com/tile.jpg").unwrap();
+        let request = build_request(&http, &url, &url, &HashMap::new(), None).build().unwrap();
+        assert_eq!(request.headers()[header::AUTHORIZATION], "Bearer secret");
+        assert_eq!(request.headers()[header::COOKIE], "login=me");
+
+        let mut headers = header::HeaderMap::new();
+        headers.insert(header::SET_COOKIE, "cdn=1; Path=/".parse().unwrap());
+        headers.insert(header::LOCATION, "https://cdn.example.net/tile.jpg".parse().unwrap());
+        let cdn = Url::parse("https://cdn.example.net/").unwrap();
+        follow_redirect(&http, &cdn, StatusCode::FOUND, &headers).unwrap();
+        let mut request_headers = HashMap::new();
+        request_headers.insert("x-auth-token".to_string(), "level token".to_string());
+        for next in &["https://cdn.example.net/tile.jpg", "http://example.com:8080/tile.jpg"] {
+            let next = Url::parse(next).unwrap();
+            let request = build_request(&http, &next, &url, &request_headers, None).build().unwrap();
+            for name in &["Authorization", "X-Api-Key", "X-Auth-Token"] {
+                assert!(request.headers().get(*name).is_none(), "{} was sent to {}", name, next);
+            }
+            let expected_cookie = if next.host_str() == Some("cdn.example.net") { Some("cdn=1") } else { None };
+            assert_eq!(request.headers().get(header::COOKIE).map(|c| c.to_str().unwrap()), expected_cookie);
+        }
+    }
+
+    #[test]
+    fn test_byte_ranges() {
+        assert_eq!(split_byte_range("a.pff#bytes=10-19"), ("a.pff", Some((10, 19))));
+        assert_eq!(split_byte_range("http://x.com/a.pff?k=v#bytes=0-0"), ("http://x.com/a.pff?k=v", Some((0, 0))));
+        assert_eq!(split_byte_range("a.pff#bytes=x"), ("a.pff#bytes=x", None));
+        assert_eq!(split_byte_range("a.pff"), ("a.pff", None));
+        assert_eq!(slice_range(b"0123456789".to_vec(), 2, 4), b"234");
+        assert_eq!(slice_range(b"0123".to_vec(), 2, 40), b"23");
+    }
+
+    #[test]
+    fn test_resolve_relative() {
+        assert_eq!(resolve_relative("http://a.com/b/c.html", "d/e.dzi"), "http://a.com/b/d/e.dzi");
+        assert_eq!(resolve_relative("testdata/page/viewer.html", "../x.xml"), "testdata/page/../x.xml");
+        assert_eq!(resolve_relative("testdata/page/viewer.html", "http://a.com/x.xml"), "http://a.com/x.xml");
+        assert_eq!(resolve_relative("viewer.html", "x.xml"), "x.xml");
+    }
+
+    #[test]
+    fn test_no_proxy_matches() {
+        let no_proxy = "localhost, .internal.example.com,10.0.0.1";
+        assert!(no_proxy_matches(no_proxy, "localhost"));
+        assert!(no_proxy_matches(no_proxy, "internal.example.com"));
+        assert!(no_proxy_matches(no_proxy, "images.Internal.example.com"));
+        assert!(no_proxy_matches(no_proxy, "10.0.0.1"));
+        assert!(!no_proxy_matches(no_proxy, "notinternal.example.com"));
+        assert!(!no_proxy_matches(no_proxy, "example.com"));
+        assert!(no_proxy_matches("*", "example.com"));
+    }
+
+    #[test]
+    fn test_pem_blocks() {
+        let pem = "Root CA\n-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n\
+                   -----BEGIN CERTIFICATE-----\nBBBB\n-----END CERTIFICATE-----\n";
+        assert_eq!(pem_blocks(pem), vec![
+            "-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----",
+            "-----BEGIN CERTIFICATE-----\nBBBB\n-----END CERTIFICATE-----",
+        ]);
+        assert!(pem_blocks("not a certificate").is_empty());
+    }
+}
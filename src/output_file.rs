@@ -1,19 +1,34 @@
 use std::convert::TryFrom;
 use std::ffi::OsString;
 use std::fs::OpenOptions;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use log::info;
 use sanitize_filename_reader_friendly::sanitize;
 
 use crate::{Vec2d, ZoomError};
 
-pub fn reserve_output_file(path: &PathBuf) -> Result<(), ZoomError> {
-    OpenOptions::new().write(true).create_new(true).open(path)?;
+/// Creates the output file, failing if it already exists, unless `reuse` is set
+pub fn reserve_output_file(path: &PathBuf, reuse: bool) -> Result<(), ZoomError> {
+    OpenOptions::new().write(true).truncate(true).create(true).create_new(!reuse).open(path)?;
     Ok(())
 }
 
-pub fn get_outname(outfile: &Option<PathBuf>, zoom_name: &Option<String>, size: Option<Vec2d>) -> PathBuf {
+/// Adds a number at the end of the name of a file, before its extension
+pub fn numbered_outfile(path: &Path, number: &str) -> PathBuf {
+    let mut name = path.file_stem().map(OsString::from).unwrap_or_default();
+    name.push("_");
+    name.push(number);
+    if let Some(ext) = path.extension() {
+        name.push(".");
+        name.push(ext);
+    }
+    path.with_file_name(name)
+}
+
+/// Returns the output file name. If `reuse` is set and no name was given by the user,
+/// an existing file with the default name is reused instead of choosing a new name.
+pub fn get_outname(outfile: &Option<PathBuf>, zoom_name: &Option<String>, size: Option<Vec2d>, reuse: bool) -> PathBuf {
     // An image can be encoded as JPEG only if both its dimensions can be encoded as u16
     let fits_in_jpg = size
         .map(|Vec2d { x, y }| u16::try_from(x.max(y)).is_ok());
@@ -21,7 +36,7 @@ pub fn get_outname(outfile: &Option<PathBuf>, zoom_name: &Option<String>, size:
     if let Some(path) = outfile {
         if let Some(forced_extension) = path.extension() {
             if fits_in_jpg == Some(false) && (forced_extension == "jpg" || forced_extension == "jpeg") {
-                log::error!("This file is too large to be saved as JPEG")
+                log::error!("This file is too large to be saved as JPEG. Use a .png or .tiff output file instead.")
             }
             path.into()
         } else {
@@ -38,7 +53,7 @@ pub fn get_outname(outfile: &Option<PathBuf>, zoom_name: &Option<String>, size:
         let filename = path.file_stem().map(OsString::from).unwrap_or_default();
         let ext = path.extension().map(OsString::from).unwrap_or_default();
         for i in 1.. {
-            if !path.exists() { break; }
+            if reuse || !path.exists() { break; }
             info!("File {:?} already exists. Trying another file name...", &path);
             let mut name = OsString::from(&filename);
             name.push(&format!("_{}.", i));
@@ -65,7 +80,7 @@ mod tests {
     }
 
     fn assert_filename_ok(filename: &str) -> Result<(), Box<dyn Error>> {
-        let outname = get_outname(&None, &Some(filename.to_string()), None);
+        let outname = get_outname(&None, &Some(filename.to_string()), None, false);
         assert_eq!(false, outname.exists(), "get_outname cannot overwrite an existing file");
         File::create(&outname)
             .expect(&format!("Could not to create a file named {:?} for input {:?}", outname, filename));
@@ -97,31 +112,50 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_reuse_existing_file() -> Result<(), Box<dyn Error>> {
+        move_to_tmp()?;
+        let name = "reused";
+        let path = PathBuf::from("reused.png");
+        File::create(&path)?;
+        assert_eq!(get_outname(&None, &Some(name.to_string()), None, true), path);
+        reserve_output_file(&path, true)?;
+        assert!(reserve_output_file(&path, false).is_err());
+        remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_numbered_outfile() {
+        assert_eq!(numbered_outfile(Path::new("dir/book.png"), "01"), PathBuf::from("dir/book_01.png"));
+        assert_eq!(numbered_outfile(Path::new("book"), "12"), PathBuf::from("book_12"));
+    }
+
     #[test]
     fn switch_to_png_for_large_files() {
         move_to_tmp().unwrap();
         assert_eq!(
-            get_outname(&None, &Some("hello".to_string()), None),
+            get_outname(&None, &Some("hello".to_string()), None, false),
             PathBuf::from("hello.png")
         );
         assert_eq!(
-            get_outname(&None, &Some("hello".to_string()), Some(Vec2d { x: 1000, y: 1000 })),
+            get_outname(&None, &Some("hello".to_string()), Some(Vec2d { x: 1000, y: 1000 }), false),
             PathBuf::from("hello.jpg")
         );
         assert_eq!(
-            get_outname(&None, &Some(String::new()), None),
+            get_outname(&None, &Some(String::new()), None, false),
             PathBuf::from("dezoomified.png")
         );
         assert_eq!(
-            get_outname(&None, &None, None),
+            get_outname(&None, &None, None, false),
             PathBuf::from("dezoomified.png")
         );
         assert_eq!(
-            get_outname(&None, &None, Some(Vec2d { x: 1000, y: 1000 })),
+            get_outname(&None, &None, Some(Vec2d { x: 1000, y: 1000 }), false),
             PathBuf::from("dezoomified.jpg")
         );
         assert_eq!(
-            get_outname(&Some("test.tiff".into()), &Some("hello".to_string()), Some(Vec2d { x: 1000, y: 1000 })),
+            get_outname(&Some("test.tiff".into()), &Some("hello".to_string()), Some(Vec2d { x: 1000, y: 1000 }), false),
             PathBuf::from("test.tiff")
         );
     }
@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+
+use custom_error::custom_error;
+use lazy_static::lazy_static;
+use log::{debug, info};
+use regex::Regex;
+
+use crate::auto::AutoDezoomer;
+use crate::dezoomer::*;
+use crate::network::resolve_relative;
+
+/// A dezoomer for the web pages that contain a zoomable image viewer.
+/// It looks for the address of the image in the html and the inline scripts of the page,
+/// and then lets the other dezoomers read it.
+#[derive(Default)]
+pub struct PageDezoomer {
+    /// Reads the image that was found in the page
+    inner: Option<AutoDezoomer>,
+    /// Number of pages that embed this one, such as the pages of iframes
+    depth: usize,
+}
+
+/// Number of nested pages that are read to find an image,
+/// so that pages that embed themselves or each other are not fetched forever
+const MAX_DEPTH: usize = 2;
+
+impl PageDezoomer {
+    /// A dezoomer for a page that is embedded `depth` pages deep
+    pub fn nested(depth: usize) -> Self {
+        PageDezoomer { inner: None, depth }
+    }
+}
+
+custom_error! {pub PageError
+    NoImage = "No zoomable image was found in the web page",
+}
+
+impl From<PageError> for DezoomerError {
+    fn from(err: PageError) -> Self {
+        DezoomerError::Other { source: err.into() }
+    }
+}
+
+lazy_static! {
+    /// Expressions that match the addresses of images in a page, in the order in which they are tried
+    static ref IMAGE_PATTERNS: Vec<(&'static str, Regex)> = vec![
+        // OpenSeadragon: tileSources: "image.dzi" or tileSources: ["page1.json", ...]
+        ("tileSources", Regex::new(r#"(?i)tileSources["']?\s*[:=]\s*\[?\s*["']([^"']+)["']"#).unwrap()),
+        // krpano: embedpano({xml: "tour.xml", ...})
+        ("krpano", Regex::new(r#"(?i)embedpano\s*\(\s*\{[^}]*?\bxml\s*:\s*["']([^"']+)["']"#).unwrap()),
+        // Zoomify: zoomifyImagePath: "path/to/image",
+        // or Z.showImage("viewer", "", "zoomifyImagePath=path/to/image&zoomifyNavigator=0")
+        ("zoomify", Regex::new(r#"(?i)zoomifyImagePath["']?\s*[:=]\s*["']?([^"'&\s,;)}]+)"#).unwrap()),
+        // Links to metadata files in the html or in scripts.
+        // Other manifests, such as the ones of web applications, are not IIIF manifests.
+        ("link", Regex::new(
+            r#"(?i)[^"'\s<>()=,]+(?:/info\.json|/ImageProperties\.xml|\.dz[ic]|/iiif/[^"'\s<>()=,]*/manifest(?:\.json)?)(?:\?[^"'\s<>()]*)?\b"#
+        ).unwrap()),
+        // IIIF identifiers in inline json-ld
+        ("iiif", Regex::new(r#""@id"\s*:\s*"([^"]*iiif[^"]*)""#).unwrap()),
+        // Other links to manifests, in pages that use IIIF
+        ("manifest", Regex::new(r#"(?i)[^"'\s<>()=,]+/manifest(?:\.json)?(?:\?[^"'\s<>()]*)?\b"#).unwrap()),
+    ];
+    static ref IIIF_CONTEXT: Regex = Regex::new(r#"(?i)iiif\.io/api/"#).unwrap();
+    static ref IFRAME: Regex = Regex::new(r#"(?i)<iframe[^>]+?\bsrc\s*=\s*["']([^"']+)["']"#).unwrap();
+    static ref HTML: Regex = Regex::new(r#"(?i)<(!doctype html|html|body|script|iframe)\b"#).unwrap();
+}
+
+impl Dezoomer for PageDezoomer {
+    fn name(&self) -> &'static str {
+        "page"
+    }
+
+    fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
+        if let Some(inner) = &mut self.inner {
+            return inner.zoom_levels(data);
+        }
+        let DezoomerInputWithContents { uri, contents } = data.with_contents()?;
+        let html = String::from_utf8_lossy(contents);
+        self.assert(html.trim_start().starts_with('<') && HTML.is_match(&html))?;
+        let mut images = find_images(uri, &html);
+        if images.is_empty() {
+            images = find_iframes(uri, &html);
+        }
+        match images.len() {
+            0 => Err(PageError::NoImage.into()),
+            1 if self.depth >= MAX_DEPTH => {
+                info!("Not following {}: the page is embedded {} pages deep", images[0], self.depth);
+                Err(PageError::NoImage.into())
+            }
+            1 => {
+                let uri = images.swap_remove(0);
+                info!("Found a zoomable image in the page: {}", uri);
+                self.inner = Some(AutoDezoomer::nested(self.depth + 1));
+                Err(DezoomerError::NeedsData { uri })
+            }
+            _ => {
+                let images = images.into_iter().map(|uri| ImageReference { uri, title: None }).collect();
+                Err(DezoomerError::MultipleImages { images })
+            }
+        }
+    }
+
+    fn http_headers(&self) -> HashMap<String, String> {
+        self.inner.as_ref().map(|inner| inner.http_headers()).unwrap_or_default()
+    }
+}
+
+/// Decodes the escape sequences of html attributes and javascript strings that can appear in urls
+fn unescape(url: &str) -> String {
+    url.replace("\\/", "/").replace("&amp;", "&")
+}
+
+fn add_unique(urls: &mut Vec<String>, url: String) {
+    if !urls.contains(&url) { urls.push(url) }
+}
+
+/// The addresses of the metadata files of the images referenced in the page
+fn find_images(page_uri: &str, html: &str) -> Vec<String> {
+    let mut images = vec![];
+    for (kind, pattern) in IMAGE_PATTERNS.iter() {
+        // IIIF identifiers are often repeated for each part of a document, use them only as a last resort
+        if *kind == "iiif" && !images.is_empty() { break; }
+        if *kind == "manifest" && !IIIF_CONTEXT.is_match(html) { break; }
+        for captures in pattern.captures_iter(html) {
+            let matched = captures.get(1).or_else(|| captures.get(0)).map_or("", |m| m.as_str());
+            let url = unescape(matched.trim());
+            // Inline tile sources are not links
+            if url.is_empty() || url.starts_with('<') || url.starts_with('{') { continue; }
+            let url = match *kind {
+                "zoomify" if !url.contains("ImageProperties.xml") =>
+                    format!("{}/ImageProperties.xml", url.trim_end_matches('/')),
+                "iiif" if !url.contains("manifest") && !url.ends_with("info.json") =>
+                    format!("{}/info.json", url.trim_end_matches('/')),
+                _ => url,
+            };
+            debug!("Found a {} image reference in the page: {}", kind, url);
+            add_unique(&mut images, resolve_relative(page_uri, &url));
+        }
+    }
+    images
+}
+
+/// The pages embedded in the page, that may contain the image viewer
+fn find_iframes(page_uri: &str, html: &str) -> Vec<String> {
+    let mut frames = vec![];
+    for captures in IFRAME.captures_iter(html) {
+        let url = unescape(&captures[1]);
+        if url.starts_with("about:") || url.starts_with("javascript:") { continue; }
+        add_unique(&mut frames, resolve_relative(page_uri, &url));
+    }
+    frames
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: &str = "http://museum.example.com/collection/object.html";
+
+    fn page_images(html: &str) -> Result<Vec<String>, DezoomerError> {
+        let input = DezoomerInput { uri: PAGE.into(), contents: Some(html.as_bytes().to_vec()) };
+        match PageDezoomer::default().zoom_levels(&input) {
+            Err(DezoomerError::NeedsData { uri }) => Ok(vec![uri]),
+            Err(DezoomerError::MultipleImages { images }) => Ok(images.into_iter().map(|i| i.uri).collect()),
+            Err(e) => Err(e),
+            Ok(_) => panic!("The page dezoomer should not return zoom levels"),
+        }
+    }
+
+    #[test]
+    fn test_openseadragon() {
+        let html = r#"<html><script>
+            var viewer = OpenSeadragon({ id: "viewer", tileSources: "/tiles/object.dzi" });
+        </script></html>"#;
+        assert_eq!(page_images(html).unwrap(), vec!["http://museum.example.com/tiles/object.dzi"]);
+    }
+
+    #[test]
+    fn test_escaped_links() {
+        let html = r#"<!DOCTYPE html><body><script>
+            window.data = {"image": "https:\/\/iiif.example.com\/iiif\/2\/a%2Fb\/info.json"};
+        </script><a href="zoom/ImageProperties.xml?v=1&amp;x=2">zoom</a></body>"#;
+        assert_eq!(page_images(html).unwrap(), vec![
+            "https://iiif.example.com/iiif/2/a%2Fb/info.json",
+            "http://museum.example.com/collection/zoom/ImageProperties.xml?v=1&x=2",
+        ]);
+    }
+
+    #[test]
+    fn test_krpano_and_zoomify() {
+        let html = r#"<html><script>embedpano({swf: "tour.swf", xml: "tour.xml", target: "pano"});</script>
+            <script>Z.showImage("viewer", "", "zoomifyImagePath=images/object/&zoomifyNavigator=0");
+            var config = {zoomifyImagePath: "images/object2"};</script></html>"#;
+        assert_eq!(page_images(html).unwrap(), vec![
+            "http://museum.example.com/collection/tour.xml",
+            "http://museum.example.com/collection/images/object/ImageProperties.xml",
+            "http://museum.example.com/collection/images/object2/ImageProperties.xml",
+        ]);
+    }
+
+    #[test]
+    fn test_manifests() {
+        let web_app = r#"<html><head><link rel="manifest" href="/static/manifest.json"></head>
+            <body><script>tileSources: "/tiles/object.dzi"</script></body></html>"#;
+        assert_eq!(page_images(web_app).unwrap(), vec!["http://museum.example.com/tiles/object.dzi"]);
+        let web_app_only = r#"<html><head><link rel="manifest" href="/static/manifest.json"></head></html>"#;
+        assert!(page_images(web_app_only).is_err());
+
+        let iiif_path = r#"<html><link rel="manifest" href="/static/manifest.json">
+            <a href="https://example.com/iiif/42/manifest">IIIF</a></html>"#;
+        assert_eq!(page_images(iiif_path).unwrap(), vec!["https://example.com/iiif/42/manifest"]);
+
+        let iiif_context = r#"<html><script type="application/ld+json">
+            {"@context": "http://iiif.io/api/presentation/2/context.json"}
+        </script><a href="https://example.com/objects/42/manifest.json">IIIF</a></html>"#;
+        assert_eq!(page_images(iiif_context).unwrap(), vec!["https://example.com/objects/42/manifest.json"]);
+    }
+
+    #[test]
+    fn test_iiif_ids() {
+        let html = r#"<html><script type="application/ld+json">
+            {"@id": "https://example.com/iiif/image/42", "profile": "level2"}
+        </script></html>"#;
+        assert_eq!(page_images(html).unwrap(), vec!["https://example.com/iiif/image/42/info.json"]);
+    }
+
+    #[test]
+    fn test_iframe() {
+        let html = r#"<html><iframe width="800" src="https://viewer.example.com/embed?id=42"></iframe></html>"#;
+        assert_eq!(page_images(html).unwrap(), vec!["https://viewer.example.com/embed?id=42"]);
+    }
+
+    #[test]
+    fn test_self_referencing_iframe() {
+        let html = format!(r#"<html><iframe src="{}"></iframe></html>"#, PAGE);
+        let input = DezoomerInput { uri: PAGE.into(), contents: Some(html.into_bytes()) };
+        let mut dezoomer = PageDezoomer::default();
+        let mut requests = 0;
+        let err = loop {
+            match dezoomer.zoom_levels(&input) {
+                Err(DezoomerError::NeedsData { uri }) => {
+                    assert_eq!(uri, PAGE);
+                    requests += 1;
+                    assert!(requests <= MAX_DEPTH, "the page was requested {} times", requests);
+                }
+                Err(e) => break e,
+                Ok(_) => panic!("The page contains no image"),
+            }
+        };
+        assert_eq!(requests, MAX_DEPTH);
+        assert!(err.to_string().contains("No zoomable image"), "{}", err);
+    }
+
+    #[test]
+    fn test_not_a_page() {
+        assert!(page_images(r#"<?xml version="1.0"?><Image TileSize="256"/>"#).is_err());
+        assert!(page_images(r#"{"text": "<html><a href='ImageProperties.xml'></a></html>"}"#).is_err());
+        assert!(page_images("<html><p>Nothing to see here</p></html>").is_err());
+    }
+}
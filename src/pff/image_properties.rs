@@ -6,6 +6,11 @@ use serde::export::fmt::Debug;
 
 use custom_error::custom_error;
 
+use crate::network::byte_range_uri;
+
+/// Size of the part of the file that precedes the JFIF headers table, and contains the xml header
+pub const XML_HEADER_END: u64 = 0x424;
+
 #[derive(Debug, Deserialize, PartialEq)]
 pub struct Reply<T: FromStr> where <T as FromStr>::Err: ToString {
     #[serde(deserialize_with = "deserialize_from_string")]
@@ -44,23 +49,46 @@ impl FromStr for PffHeader {
     }
 }
 
+impl PffHeader {
+    /// Finds the xml header in the first bytes of a pff file
+    pub fn from_file_start(contents: &[u8]) -> Option<Result<Self, serde_xml_rs::Error>> {
+        let text = String::from_utf8_lossy(contents);
+        let start = text.find("<PFFHEADER")?;
+        let end = start + text[start..].find("/>")? + 2;
+        Some(text[start..end].parse())
+    }
+}
+
+/// Where the pff file is read from
+#[derive(Debug, Clone)]
+pub enum PffSource {
+    /// A zoomify servlet, that reads the file given as a parameter
+    Servlet { base_url: String, file: String },
+    /// The file itself, read with byte range requests
+    File(String),
+}
+
 #[derive(Debug, Clone)]
 pub struct HeaderInfo {
-    pub base_url: String,
-    pub file: String,
+    pub source: PffSource,
     pub header: PffHeader,
 }
 
 impl HeaderInfo {
     fn request_url(&self, params: ServletRequestParams) -> String {
-        let params = params.with_file(&self.file);
-        let params_str = serde_urlencoded::to_string(params).expect("parameters are encodable");
-        format!("{}?{}", self.base_url, params_str)
+        match &self.source {
+            PffSource::Servlet { base_url, file } => {
+                let params = params.with_file(file);
+                let params_str = serde_urlencoded::to_string(params).expect("parameters are encodable");
+                format!("{}?{}", base_url, params_str)
+            }
+            PffSource::File(uri) => byte_range_uri(uri, params.begin, params.end - 1),
+        }
     }
 
     pub fn tiles_index_url(&self) -> String {
         let header = &self.header;
-        let begin = 0x424 + header.header_size;
+        let begin = XML_HEADER_END + header.header_size;
         let end = begin + 8 * u64::from(header.num_tiles);
         self.request_url(ServletRequestParams {
             vers: header.version,
@@ -79,13 +107,30 @@ pub struct ImageInfo {
 }
 
 impl ImageInfo {
+    /// Checks that the tile index gives a non-empty range of bytes for every tile of the header
+    pub fn new(header_info: HeaderInfo, tiles: TileIndices) -> Result<Self, ParseTileIndicesError> {
+        let header = &header_info.header;
+        let count = tiles.indices.len();
+        if count < header.num_tiles as usize {
+            return Err(ParseTileIndicesError::MissingTiles { count, expected: header.num_tiles });
+        }
+        let mut begin = XML_HEADER_END + header.header_size + 8 * u64::from(header.num_tiles);
+        for (tile, &end) in tiles.indices.iter().enumerate() {
+            if end <= begin {
+                return Err(ParseTileIndicesError::EmptyTile { tile, begin, end });
+            }
+            begin = end;
+        }
+        Ok(ImageInfo { header_info, tiles })
+    }
+
     pub fn tile_url(&self, tile_number: usize) -> String {
         let header = &self.header_info.header;
         let tiles = &self.tiles;
         let begin = if let Some(i) = tile_number.checked_sub(1) {
             tiles.indices[i]
         } else {
-            0x424 + header.header_size + 8 * u64::from(header.num_tiles)
+            XML_HEADER_END + header.header_size + 8 * u64::from(header.num_tiles)
         };
         self.header_info.request_url(ServletRequestParams {
             vers: header.version,
@@ -105,6 +150,27 @@ pub struct TileIndices {
 custom_error! {#[derive(PartialEq, Eq)] pub ParseTileIndicesError
     TooShort = "Missing a part of tile indices string",
     BadNum{source: ParseIntError} = "Invalid tile index: {}",
+    BadLength{length: usize} = "The tile index of the file has an invalid length of {length} bytes",
+    MissingTiles{count: usize, expected: u32} = "The tile index contains {count} tiles instead of {expected}",
+    EmptyTile{tile: usize, begin: u64, end: u64} =
+        "Tile {tile} has an empty byte range in the file, from {begin} to {end}",
+}
+
+impl TileIndices {
+    /// Reads the tile index of a pff file: the offset of the end of each tile,
+    /// as a big-endian 64 bits integer
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseTileIndicesError> {
+        let entries = bytes.chunks_exact(8);
+        if bytes.is_empty() || !entries.remainder().is_empty() {
+            return Err(ParseTileIndicesError::BadLength { length: bytes.len() });
+        }
+        let indices = entries.map(|chunk| {
+            let mut entry = [0u8; 8];
+            entry.copy_from_slice(chunk);
+            u64::from_be_bytes(entry)
+        }).collect();
+        Ok(TileIndices { indices })
+    }
 }
 
 impl FromStr for TileIndices {
@@ -197,13 +263,52 @@ mod test {
         assert_eq!(header.num_tiles, 5541);
         assert_eq!(header.header_size, 15331);
         assert_eq!(header.version, 106);
-        let header_info = HeaderInfo { header, file: "x".into(), base_url: "http://x.com/".into() };
+        let source = PffSource::Servlet { file: "x".into(), base_url: "http://x.com/".into() };
+        let header_info = HeaderInfo { header, source };
         assert_eq!(
             header_info.tiles_index_url(),
             "http://x.com/?file=x&vers=106&head=15331&begin=16391&end=60719&requestType=2"
         );
     }
 
+    #[test]
+    fn test_read_file() {
+        let mut start = vec![0u8; 0x24];
+        start.extend(br#"<PFFHEADER WIDTH="600" HEIGHT="400" NUMTILES="2" HEADERSIZE="20" VERSION="106" TILESIZE="256" />"#);
+        let header = PffHeader::from_file_start(&start).unwrap().unwrap();
+        assert_eq!((header.width, header.num_tiles, header.header_size), (600, 2, 20));
+        let header_info = HeaderInfo { header, source: PffSource::File("a.pff".into()) };
+        assert_eq!(header_info.tiles_index_url(), "a.pff#bytes=1080-1095");
+        let tiles = TileIndices::from_bytes(&[0, 0, 0, 0, 0, 0, 0x04, 0x80, 0, 0, 0, 0, 0, 0, 0x05, 0]).unwrap();
+        let info = ImageInfo::new(header_info, tiles).unwrap();
+        assert_eq!(info.tile_url(0), "a.pff#bytes=1096-1151");
+        assert_eq!(info.tile_url(1), "a.pff#bytes=1152-1279");
+        assert!(PffHeader::from_file_start(b"not a pff file").is_none());
+        assert!(TileIndices::from_bytes(&[0; 7]).is_err());
+    }
+
+    #[test]
+    fn test_corrupt_tile_index() {
+        let mut start = vec![0u8; 0x24];
+        start.extend(br#"<PFFHEADER WIDTH="600" HEIGHT="400" NUMTILES="2" HEADERSIZE="20" VERSION="106" TILESIZE="256" />"#);
+        let header = PffHeader::from_file_start(&start).unwrap().unwrap();
+        let header_info = HeaderInfo { header, source: PffSource::File("a.pff".into()) };
+        let image_info = |indices: Vec<u64>| ImageInfo::new(header_info.clone(), TileIndices { indices });
+        assert_eq!(
+            image_info(vec![0, 1200]).unwrap_err(),
+            ParseTileIndicesError::EmptyTile { tile: 0, begin: 1096, end: 0 }
+        );
+        assert_eq!(
+            image_info(vec![1100, 1100]).unwrap_err(),
+            ParseTileIndicesError::EmptyTile { tile: 1, begin: 1100, end: 1100 }
+        );
+        assert_eq!(
+            image_info(vec![1100]).unwrap_err(),
+            ParseTileIndicesError::MissingTiles { count: 1, expected: 2 }
+        );
+        assert!(image_info(vec![1100, 1200]).is_ok());
+    }
+
     #[test]
     fn test_deserialize_indices_reply() {
         let src = "Error=0&newSize=126&reply_data=1,  0  1  2";
@@ -10,12 +10,17 @@ use image_properties::PffHeader;
 use image_properties::Reply;
 
 use crate::dezoomer::*;
-use crate::pff::image_properties::{HeaderInfo, ImageInfo, InitialServletRequestParams, RequestType, TileIndices};
+use crate::network::byte_range_uri;
+use crate::pff::image_properties::{
+    HeaderInfo, ImageInfo, InitialServletRequestParams, ParseTileIndicesError, PffSource,
+    RequestType, TileIndices, XML_HEADER_END,
+};
 
 mod image_properties;
 
 /// Dezoomer for Zoomify PFF.
-/// Takes an URL to a pff file
+/// Takes an URL to a zoomify servlet that serves a pff file,
+/// or the URL or path of a pff file, that is then read with byte range requests
 pub enum PFF {
     Init,
     WithHeader(HeaderInfo),
@@ -26,6 +31,9 @@ impl Default for PFF { fn default() -> Self { PFF::Init } }
 custom_error! {pub PffError
     DecodeError{source: serde_urlencoded::de::Error} = "Invalid meta information file: {source}",
     EncodeError{source: serde_urlencoded::ser::Error} = "Unable to generate URL: {source}",
+    MissingHeader = "The file does not start with a PFF header",
+    HeaderError{source: serde_xml_rs::Error} = "Invalid PFF header: {source}",
+    TileIndicesError{source: ParseTileIndicesError} = "Invalid tile index: {source}",
 }
 
 impl From<PffError> for DezoomerError {
@@ -40,39 +48,72 @@ impl Dezoomer for PFF {
     }
 
     fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
-        let mut parts = data.uri.splitn(2, '?');
-        let base_url = parts.next().ok_or_else(|| self.wrong_dezoomer())?.to_string();
-        let params_str = parts.next().ok_or_else(|| self.wrong_dezoomer())?;
         match self {
             PFF::Init => {
-                let init_params: InitialServletRequestParams =
-                    urlencoded::from_str(params_str).map_err(PffError::from)?;
-                let file = init_params.file;
-                if init_params.request_type != RequestType::Metadata as u8 {
-                    let uri = format!("{}?file={}&requestType={}", base_url, file, RequestType::Metadata as u8);
-                    return Err(DezoomerError::NeedsData { uri });
-                }
-                let DezoomerInputWithContents { contents, .. } = data.with_contents()?;
-                let reply: Reply<PffHeader> =
-                    serde_urlencoded::from_bytes(contents).map_err(PffError::from)?;
-                let header_info = HeaderInfo { base_url, file, header: reply.reply_data };
+                let header_info = match pff_file(&data.uri) {
+                    Some(file) => file_header(file, data)?,
+                    None => servlet_header(self, data)?,
+                };
                 let uri = header_info.tiles_index_url();
                 *self = PFF::WithHeader(header_info);
                 Err(DezoomerError::NeedsData { uri })
             },
             PFF::WithHeader(header_info) => {
                 let DezoomerInputWithContents { contents, .. } = data.with_contents()?;
-                let reply: Reply<TileIndices> =
-                    urlencoded::from_bytes(contents).map_err(PffError::from)?;
-                Ok(zoom_levels(ImageInfo {
-                    header_info: header_info.clone(),
-                    tiles: reply.reply_data,
-                }))
+                let tiles = match header_info.source {
+                    PffSource::Servlet { .. } => {
+                        let reply: Reply<TileIndices> =
+                            urlencoded::from_bytes(contents).map_err(PffError::from)?;
+                        reply.reply_data
+                    }
+                    PffSource::File(_) => TileIndices::from_bytes(contents).map_err(PffError::from)?,
+                };
+                let info = ImageInfo::new(header_info.clone(), tiles).map_err(PffError::from)?;
+                Ok(zoom_levels(info))
             }
         }
     }
 }
 
+/// The uri of a pff file that is not served by a zoomify servlet, without the requested byte range
+fn pff_file(uri: &str) -> Option<&str> {
+    let file = uri.split('#').next().unwrap_or_default();
+    let path = file.split('?').next().unwrap_or_default();
+    let is_pff = path.to_ascii_lowercase().ends_with(".pff") && !file.contains("requestType=");
+    if is_pff { Some(file) } else { None }
+}
+
+/// Reads the header at the start of a pff file
+fn file_header(file: &str, data: &DezoomerInput) -> Result<HeaderInfo, DezoomerError> {
+    let uri = byte_range_uri(file, 0, XML_HEADER_END - 1);
+    if data.uri != uri {
+        return Err(DezoomerError::NeedsData { uri });
+    }
+    let DezoomerInputWithContents { contents, .. } = data.with_contents()?;
+    let header = PffHeader::from_file_start(contents)
+        .ok_or(PffError::MissingHeader)?
+        .map_err(PffError::from)?;
+    Ok(HeaderInfo { source: PffSource::File(file.to_string()), header })
+}
+
+/// Requests the header of a pff file from a zoomify servlet
+fn servlet_header(dezoomer: &PFF, data: &DezoomerInput) -> Result<HeaderInfo, DezoomerError> {
+    let mut parts = data.uri.splitn(2, '?');
+    let base_url = parts.next().ok_or_else(|| dezoomer.wrong_dezoomer())?.to_string();
+    let params_str = parts.next().ok_or_else(|| dezoomer.wrong_dezoomer())?;
+    let init_params: InitialServletRequestParams =
+        urlencoded::from_str(params_str).map_err(PffError::from)?;
+    let file = init_params.file;
+    if init_params.request_type != RequestType::Metadata as u8 {
+        let uri = format!("{}?file={}&requestType={}", base_url, file, RequestType::Metadata as u8);
+        return Err(DezoomerError::NeedsData { uri });
+    }
+    let DezoomerInputWithContents { contents, .. } = data.with_contents()?;
+    let reply: Reply<PffHeader> =
+        serde_urlencoded::from_bytes(contents).map_err(PffError::from)?;
+    Ok(HeaderInfo { source: PffSource::Servlet { base_url, file }, header: reply.reply_data })
+}
+
 fn zoom_levels(info: ImageInfo) -> ZoomLevels {
     let info = Arc::new(info);
     let header = &info.header_info.header;
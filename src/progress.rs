@@ -0,0 +1,304 @@
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
+
+use crate::{Vec2d, ZoomError};
+
+/// How the progress of a download is reported
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProgressFormat {
+    /// A human-readable progress bar
+    Bar,
+    /// One JSON object per line on the standard output
+    Json,
+    /// One JSON object per line on the standard error
+    JsonStderr,
+}
+
+impl FromStr for ProgressFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bar" => Ok(ProgressFormat::Bar),
+            "json" => Ok(ProgressFormat::Json),
+            "json-stderr" => Ok(ProgressFormat::JsonStderr),
+            _ => Err(format!("Invalid progress format '{}'. Expected 'bar', 'json' or 'json-stderr'", s)),
+        }
+    }
+}
+
+impl ProgressFormat {
+    pub fn is_json(self) -> bool {
+        self != ProgressFormat::Bar
+    }
+
+    /// Writes the event as a single line of JSON, if this is a JSON format
+    pub fn emit(self, event: &ProgressEvent) {
+        if !self.is_json() { return; }
+        let line = match serde_json::to_string(event) {
+            Ok(line) => line,
+            Err(e) => {
+                log::error!("Unable to serialize the progress event {:?}: {}", event, e);
+                return;
+            }
+        };
+        let result = match self {
+            ProgressFormat::Bar => Ok(()),
+            ProgressFormat::Json => writeln!(std::io::stdout().lock(), "{}", line),
+            ProgressFormat::JsonStderr => writeln!(std::io::stderr().lock(), "{}", line),
+        };
+        if let Err(e) = result {
+            log::error!("Unable to write the progress event: {}", e);
+        }
+    }
+}
+
+/// Receives notifications about the progress of the download of a zoom level.
+/// All the methods do nothing by default.
+pub trait ProgressObserver: Send + Sync {
+    /// The zoom level that is going to be downloaded
+    fn level_chosen(&self, _name: &str, _size: Option<Vec2d>) {}
+    /// `count` new tiles are going to be downloaded, out of `total_tiles` tiles listed so far
+    fn tiles_listed(&self, _count: u64, _total_tiles: u64) {}
+    /// The maximum number of tiles downloaded at the same time changed
+    fn parallelism_changed(&self, _parallelism: usize) {}
+    fn tile_downloaded(&self, _url: &str, _position: Vec2d) {}
+    /// A tile could not be downloaded. It will be left blank in the resulting image.
+    fn tile_failed(&self, _url: &str, _position: Vec2d, _error: &ZoomError) {}
+    /// All the tiles were downloaded, and the image is being written
+    fn finalizing(&self) {}
+    /// The image was written, possibly with missing tiles
+    fn image_finished(
+        &self,
+        _destination: Option<&Path>,
+        _size: Option<Vec2d>,
+        _successful_tiles: u64,
+        _total_tiles: u64,
+        _result: &Result<Vec2d, ZoomError>,
+    ) {}
+}
+
+/// Allows keeping a reference to an observer given to the library, to read its state afterwards
+impl<T: ProgressObserver + ?Sized> ProgressObserver for std::sync::Arc<T> {
+    fn level_chosen(&self, name: &str, size: Option<Vec2d>) { (**self).level_chosen(name, size) }
+    fn tiles_listed(&self, count: u64, total_tiles: u64) { (**self).tiles_listed(count, total_tiles) }
+    fn parallelism_changed(&self, parallelism: usize) { (**self).parallelism_changed(parallelism) }
+    fn tile_downloaded(&self, url: &str, position: Vec2d) { (**self).tile_downloaded(url, position) }
+    fn tile_failed(&self, url: &str, position: Vec2d, error: &ZoomError) {
+        (**self).tile_failed(url, position, error)
+    }
+    fn finalizing(&self) { (**self).finalizing() }
+    fn image_finished(
+        &self,
+        destination: Option<&Path>,
+        size: Option<Vec2d>,
+        successful_tiles: u64,
+        total_tiles: u64,
+        result: &Result<Vec2d, ZoomError>,
+    ) {
+        (**self).image_finished(destination, size, successful_tiles, total_tiles, result)
+    }
+}
+
+/// An observer that ignores all notifications
+pub struct NoProgress;
+
+impl ProgressObserver for NoProgress {}
+
+/// The progress reporting of the command line interface:
+/// a progress bar, or json events
+pub struct CliProgress {
+    format: ProgressFormat,
+    bar: ProgressBar,
+}
+
+impl CliProgress {
+    pub fn new(format: ProgressFormat) -> Self {
+        let bar = if format.is_json() { ProgressBar::hidden() } else { ProgressBar::new(0) };
+        bar.set_style(
+            ProgressStyle::default_bar()
+                .template("[ETA:{eta}] {bar:40.cyan/blue} {pos:>4}/{len:4} [{prefix}] {msg}")
+                .progress_chars("##-"),
+        );
+        CliProgress { format, bar }
+    }
+}
+
+impl ProgressObserver for CliProgress {
+    fn level_chosen(&self, name: &str, size: Option<Vec2d>) {
+        self.bar.set_message("Computing the URLs of the image tiles...");
+        self.format.emit(&ProgressEvent::LevelChosen {
+            name: name.to_string(),
+            width: size.map(|s| s.x),
+            height: size.map(|s| s.y),
+        });
+    }
+
+    fn tiles_listed(&self, count: u64, total_tiles: u64) {
+        self.bar.set_length(total_tiles);
+        self.bar.set_message("Requesting the tiles...");
+        self.format.emit(&ProgressEvent::TilesListed { count, total_tiles });
+    }
+
+    fn parallelism_changed(&self, parallelism: usize) {
+        self.bar.set_prefix(&format!("{} parallel", parallelism));
+        self.format.emit(&ProgressEvent::ParallelismChanged { parallelism });
+    }
+
+    fn tile_downloaded(&self, url: &str, position: Vec2d) {
+        self.bar.inc(1);
+        self.bar.set_message(&format!("Downloaded tile at {}", position));
+        self.format.emit(&ProgressEvent::TileSuccess { url, x: position.x, y: position.y });
+    }
+
+    fn tile_failed(&self, url: &str, position: Vec2d, error: &ZoomError) {
+        self.bar.inc(1);
+        self.bar.set_message(&format!("Unable to download tile '{}'. Cause: {}", url, error));
+        self.format.emit(&ProgressEvent::TileFailure { url, x: position.x, y: position.y, error: error.into() });
+    }
+
+    fn finalizing(&self) {
+        self.bar.set_message("Downloaded all tiles. Finalizing the image file.");
+    }
+
+    fn image_finished(
+        &self,
+        destination: Option<&Path>,
+        size: Option<Vec2d>,
+        successful_tiles: u64,
+        total_tiles: u64,
+        result: &Result<Vec2d, ZoomError>,
+    ) {
+        self.bar.finish_with_message("Finished tile download");
+        self.format.emit(&ProgressEvent::ImageFinished {
+            path: destination,
+            width: size.map(|s| s.x),
+            height: size.map(|s| s.y),
+            successful_tiles,
+            total_tiles,
+            error: result.as_ref().err().map(ErrorReport::from),
+        });
+    }
+}
+
+/// A machine-readable progress event
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressEvent<'a> {
+    /// The zoom level that is going to be downloaded
+    LevelChosen {
+        name: String,
+        width: Option<u32>,
+        height: Option<u32>,
+    },
+    /// New tiles are going to be downloaded
+    TilesListed {
+        count: u64,
+        total_tiles: u64,
+    },
+    /// The maximum number of tiles downloaded at the same time
+    ParallelismChanged {
+        parallelism: usize,
+    },
+    TileSuccess {
+        url: &'a str,
+        x: u32,
+        y: u32,
+    },
+    TileFailure {
+        url: &'a str,
+        x: u32,
+        y: u32,
+        error: ErrorReport,
+    },
+    /// All the tiles of an image were processed.
+    /// When some tiles are missing, the image is still written, and `error` is set.
+    ImageFinished {
+        /// None when the image is created in memory
+        path: Option<&'a Path>,
+        width: Option<u32>,
+        height: Option<u32>,
+        successful_tiles: u64,
+        total_tiles: u64,
+        error: Option<ErrorReport>,
+    },
+    /// The end of the processing of an input
+    Summary {
+        success: bool,
+        outputs: Vec<OutputReport<'a>>,
+        error: Option<ErrorReport>,
+    },
+    /// The end of a batch
+    BatchSummary {
+        successes: usize,
+        partial_downloads: Vec<InputError<'a>>,
+        failures: Vec<InputError<'a>>,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct OutputReport<'a> {
+    pub path: &'a Path,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+impl<'a> OutputReport<'a> {
+    pub fn new(path: &'a Path, size: Option<Vec2d>) -> Self {
+        OutputReport { path, width: size.map(|s| s.x), height: size.map(|s| s.y) }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct InputError<'a> {
+    pub input: &'a str,
+    pub error: ErrorReport,
+}
+
+impl<'a> InputError<'a> {
+    pub fn new(input: &'a str, error: &ZoomError) -> Self {
+        InputError { input, error: error.into() }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorReport {
+    /// Name of the ZoomError variant
+    pub kind: &'static str,
+    pub message: String,
+}
+
+impl From<&ZoomError> for ErrorReport {
+    fn from(err: &ZoomError) -> Self {
+        ErrorReport { kind: err.kind(), message: err.to_string() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serialize_events() {
+        let err = ZoomError::PartialDownload { successful_tiles: 3, total_tiles: 4, destination: None, size: None };
+        let event = ProgressEvent::TileFailure { url: "http://x/0.jpg", x: 0, y: 256, error: (&err).into() };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"tile_failure","url":"http://x/0.jpg","x":0,"y":256,"error":{"kind":"PartialDownload","message":"Only 3 tiles out of 4 could be downloaded. The resulting image was still created."}}"#
+        );
+        let path = Path::new("out.png");
+        let event = ProgressEvent::Summary {
+            success: true,
+            outputs: vec![OutputReport::new(path, Some(Vec2d { x: 10, y: 20 }))],
+            error: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"summary","success":true,"outputs":[{"path":"out.png","width":10,"height":20}],"error":null}"#
+        );
+    }
+}
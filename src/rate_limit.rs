@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+use reqwest::{header, StatusCode};
+
+/// Longest time to wait between two requests to a server that keeps failing
+const MAX_BACKOFF: Duration = Duration::from_secs(120);
+
+/// The state of the servers that the http clients of a download are talking to
+#[derive(Debug, Default)]
+pub struct HostStates(Mutex<HashMap<String, HostState>>);
+
+#[derive(Debug)]
+struct HostState {
+    /// Earliest time at which the next request to the host can be sent
+    next_request: Instant,
+    /// Additional time to wait between two requests, after the host returned errors
+    backoff: Duration,
+}
+
+/// Limits on the rate of requests sent to a single host.
+/// This is a token bucket that holds a single token,
+/// so that requests are evenly spaced and never sent in bursts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimits {
+    /// Maximum number of requests per second
+    pub max_rps: Option<f64>,
+    /// Minimum time between two requests
+    pub min_delay: Duration,
+    /// Time to wait after the first failure, doubled after each new failure
+    pub base_backoff: Duration,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits { max_rps: None, min_delay: Duration::from_secs(0), base_backoff: Duration::from_secs(2) }
+    }
+}
+
+/// How a server responded to a request
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Success,
+    /// An error that says nothing about the load of the server, such as 404
+    OtherError,
+    /// The server is overloaded (429, 503, or another server error), or unreachable.
+    /// Only Retry-After headers given as a number of seconds are supported.
+    Overloaded { retry_after: Option<Duration> },
+}
+
+impl Outcome {
+    pub fn from_response(status: StatusCode, headers: &header::HeaderMap) -> Self {
+        if status.is_success() {
+            Outcome::Success
+        } else if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+            let retry_after = headers.get(header::RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse().ok())
+                .map(Duration::from_secs);
+            Outcome::Overloaded { retry_after }
+        } else {
+            Outcome::OtherError
+        }
+    }
+}
+
+impl RateLimits {
+    fn interval(&self) -> Duration {
+        let rps_interval = self.max_rps
+            .filter(|&rps| rps > 0.)
+            .map(|rps| Duration::from_secs_f64(1. / rps))
+            .unwrap_or_default();
+        rps_interval.max(self.min_delay)
+    }
+}
+
+/// Applies rate limits to the requests of an http client.
+/// The state of the servers is shared only with the clients created with the same `HostStates`,
+/// which are the clients of a single download, that have the same limits.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    pub limits: RateLimits,
+    hosts: Arc<HostStates>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits, hosts: Arc<HostStates>) -> Self {
+        RateLimiter { limits, hosts }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, HostState>> {
+        self.hosts.0.lock().expect("the rate limiter lock is poisoned")
+    }
+
+    /// Reserves the next slot to send a request to the given host,
+    /// and returns how long to wait before sending it
+    fn reserve(&self, host: &str, now: Instant) -> Duration {
+        let mut hosts = self.lock();
+        let state = hosts.entry(host.to_string())
+            .or_insert(HostState { next_request: now, backoff: Duration::default() });
+        let slot = state.next_request.max(now);
+        state.next_request = slot + self.limits.interval().max(state.backoff);
+        slot - now
+    }
+
+    /// Waits until a request can be sent to the given host
+    pub async fn wait(&self, host: &str) {
+        let delay = self.reserve(host, Instant::now());
+        if delay > Duration::default() {
+            debug!("Waiting {:?} before sending a request to {}", delay, host);
+            tokio::time::delay_for(delay).await;
+        }
+    }
+
+    /// Adapts the rate of requests to the given host to the way it responded
+    pub fn record(&self, host: &str, outcome: Outcome) {
+        self.record_at(host, outcome, Instant::now())
+    }
+
+    fn record_at(&self, host: &str, outcome: Outcome, now: Instant) {
+        let mut hosts = self.lock();
+        let state = match hosts.get_mut(host) {
+            Some(state) => state,
+            None => return,
+        };
+        match outcome {
+            Outcome::Success => {
+                state.backoff /= 2;
+                if state.backoff < self.limits.base_backoff / 4 { state.backoff = Duration::default() }
+            }
+            Outcome::OtherError => {}
+            Outcome::Overloaded { retry_after } => {
+                state.backoff = (state.backoff * 2).max(self.limits.base_backoff).min(MAX_BACKOFF);
+                let pause = retry_after.unwrap_or(state.backoff);
+                info!("The server {} seems overloaded. Slowing down: waiting {:?}", host, pause);
+                state.next_request = state.next_request.max(now + pause);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_min_delay() {
+        let limits = RateLimits { max_rps: Some(4.), min_delay: Duration::from_millis(100), ..Default::default() };
+        let limits = RateLimiter::new(limits, Arc::default());
+        let now = Instant::now();
+        let host = "example.com";
+        assert_eq!(limits.reserve(host, now), Duration::from_millis(0));
+        assert_eq!(limits.reserve(host, now), Duration::from_millis(250));
+        assert_eq!(limits.reserve(host, now), Duration::from_millis(500));
+        // Unused slots are not accumulated: there are no bursts after a pause
+        let later = now + Duration::from_secs(10);
+        assert_eq!(limits.reserve(host, later), Duration::from_millis(0));
+        assert_eq!(limits.reserve(host, later), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let limits = RateLimits { base_backoff: Duration::from_secs(1), ..Default::default() };
+        let limits = RateLimiter::new(limits, Arc::default());
+        let now = Instant::now();
+        let host = "example.com";
+        assert_eq!(limits.reserve(host, now), Duration::from_secs(0));
+        let overloaded = Outcome::Overloaded { retry_after: None };
+        limits.record_at(host, overloaded, now);
+        assert_eq!(limits.reserve(host, now), Duration::from_secs(1));
+        limits.record_at(host, overloaded, now);
+        assert_eq!(limits.reserve(host, now), Duration::from_secs(2));
+        limits.record_at(host, Outcome::Overloaded { retry_after: Some(Duration::from_secs(30)) }, now);
+        assert_eq!(limits.reserve(host, now), Duration::from_secs(30));
+        for _ in 0..5 { limits.record_at(host, Outcome::Success, now); }
+        let later = now + Duration::from_secs(60);
+        assert_eq!(limits.reserve(host, later), Duration::from_secs(0));
+        assert_eq!(limits.reserve(host, later), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_separate_clients() {
+        let slow_limits = RateLimits { min_delay: Duration::from_secs(1), ..Default::default() };
+        let slow = RateLimiter::new(slow_limits, Arc::default());
+        let fast = RateLimiter::new(RateLimits::default(), Arc::default());
+        let now = Instant::now();
+        assert_eq!(slow.reserve("example.com", now), Duration::from_secs(0));
+        assert_eq!(slow.reserve("example.com", now), Duration::from_secs(1));
+        assert_eq!(fast.reserve("example.com", now), Duration::from_secs(0));
+        assert_eq!(fast.reserve("example.com", now), Duration::from_secs(0));
+        // The clients of the same download share the state of the servers
+        let same_download = RateLimiter::new(slow_limits, Arc::clone(&slow.hosts));
+        assert_eq!(same_download.reserve("example.com", now), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_outcome() {
+        let mut headers = header::HeaderMap::new();
+        assert_eq!(Outcome::from_response(StatusCode::OK, &headers), Outcome::Success);
+        assert_eq!(Outcome::from_response(StatusCode::NOT_FOUND, &headers), Outcome::OtherError);
+        headers.insert(header::RETRY_AFTER, "120".parse().unwrap());
+        assert_eq!(
+            Outcome::from_response(StatusCode::TOO_MANY_REQUESTS, &headers),
+            Outcome::Overloaded { retry_after: Some(Duration::from_secs(120)) }
+        );
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::BTreeSet;
+use std::str::FromStr;
+
+use crate::dezoomer::TileReference;
+use crate::tile::Tile;
+use crate::{Vec2d, ZoomError};
+
+/// A rectangular part of the image to download,
+/// in pixels or in percentages of the size of the zoom level
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    x: Coordinate,
+    y: Coordinate,
+    width: Coordinate,
+    height: Coordinate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Coordinate {
+    Pixels(u32),
+    Percent(f64),
+}
+
+impl Coordinate {
+    fn resolve(self, level_size: Option<u32>) -> Option<u32> {
+        match self {
+            Coordinate::Pixels(n) => Some(n),
+            Coordinate::Percent(p) => level_size.map(|size| (f64::from(size) * p / 100.).round() as u32),
+        }
+    }
+}
+
+impl FromStr for Coordinate {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(percent) = s.strip_suffix('%') {
+            let p: f64 = percent.trim().parse().map_err(|_| "invalid percentage")?;
+            if !(0. ..=100.).contains(&p) { return Err("percentages must be between 0 and 100"); }
+            Ok(Coordinate::Percent(p))
+        } else {
+            s.parse().map(Coordinate::Pixels).map_err(|_| "invalid number of pixels")
+        }
+    }
+}
+
+impl FromStr for Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s.split(',')
+            .map(Coordinate::from_str)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Invalid region '{}': {}", s, e))?;
+        match parts.as_slice() {
+            &[x, y, width, height] => Ok(Region { x, y, width, height }),
+            _ => Err(format!("Invalid region '{}'. \
+                Expected 'x,y,width,height', such as '100,200,1000,500' or '10%,10%,50%,50%'", s)),
+        }
+    }
+}
+
+impl Region {
+    /// Computes the position and size of the region in pixels,
+    /// for a zoom level of the given size, if it is known
+    pub fn resolve(&self, level_size: Option<Vec2d>) -> Result<RegionFilter, ZoomError> {
+        let (level_x, level_y) = (level_size.map(|s| s.x), level_size.map(|s| s.y));
+        let resolved = (
+            self.x.resolve(level_x),
+            self.y.resolve(level_y),
+            self.width.resolve(level_x),
+            self.height.resolve(level_y),
+        );
+        let (x, y, width, height) = match resolved {
+            (Some(x), Some(y), Some(w), Some(h)) => (x, y, w, h),
+            _ => return Err(ZoomError::RegionNeedsSize),
+        };
+        let position = Vec2d { x, y };
+        let mut size = Vec2d { x: width, y: height };
+        if let Some(level_size) = level_size {
+            size = (position + size).min(level_size) - position;
+        }
+        if size.area() == 0 {
+            return Err(ZoomError::EmptyRegion { region: self.to_string() });
+        }
+        Ok(RegionFilter { position, size })
+    }
+}
+
+impl std::fmt::Display for Region {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let coords = [self.x, self.y, self.width, self.height];
+        let strs: Vec<String> = coords.iter().map(|c| match c {
+            Coordinate::Pixels(n) => n.to_string(),
+            Coordinate::Percent(p) => format!("{}%", p),
+        }).collect();
+        write!(f, "{}", strs.join(","))
+    }
+}
+
+/// A region of a zoom level, in pixels.
+/// Used to download only the tiles that intersect with the region,
+/// and to crop them so that the region becomes the whole image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RegionFilter {
+    pub position: Vec2d,
+    pub size: Vec2d,
+}
+
+impl RegionFilter {
+    fn bottom_right(&self) -> Vec2d {
+        self.position + self.size
+    }
+
+    /// Keeps only the tiles that may intersect with the region.
+    /// The size of the tiles is not known before they are downloaded,
+    /// so each tile is assumed to extend until the position of the next tile in the batch.
+    pub fn filter_tiles(&self, tiles: Vec<TileReference>) -> Vec<TileReference> {
+        let xs: BTreeSet<u32> = tiles.iter().map(|t| t.position.x).collect();
+        let ys: BTreeSet<u32> = tiles.iter().map(|t| t.position.y).collect();
+        let next = |set: &BTreeSet<u32>, v: u32| set.range(v + 1..).next().copied().unwrap_or(u32::MAX);
+        let top_left = self.position;
+        let bottom_right = self.bottom_right();
+        tiles.into_iter().filter(|t| {
+            let Vec2d { x, y } = t.position;
+            x < bottom_right.x && y < bottom_right.y
+                && next(&xs, x) > top_left.x && next(&ys, y) > top_left.y
+        }).collect()
+    }
+
+    /// Crops a tile to the part that is inside the region,
+    /// and moves it relatively to the top left corner of the region
+    pub fn crop_tile(&self, tile: Tile) -> Option<Tile> {
+        let top_left = tile.position.max(self.position);
+        let bottom_right = tile.bottom_right().min(self.bottom_right());
+        let size = bottom_right - top_left;
+        if size.area() == 0 { return None; }
+        let crop_position = top_left - tile.position;
+        Some(Tile {
+            image: tile.image.crop_imm(crop_position.x, crop_position.y, size.x, size.y),
+            position: top_left - self.position,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, GenericImageView, ImageBuffer};
+
+    use super::*;
+
+    fn tile_ref(x: u32, y: u32) -> TileReference {
+        TileReference { url: format!("{}_{}", x, y), position: Vec2d { x, y } }
+    }
+
+    #[test]
+    fn test_parse_region() {
+        let region: Region = "10,20,300,400".parse().unwrap();
+        let filter = region.resolve(None).unwrap();
+        assert_eq!(filter, RegionFilter { position: Vec2d { x: 10, y: 20 }, size: Vec2d { x: 300, y: 400 } });
+
+        let region: Region = "50%,0%,100%,25%".parse().unwrap();
+        assert_eq!(region.to_string(), "50%,0%,100%,25%");
+        assert!(region.resolve(None).is_err());
+        let filter = region.resolve(Some(Vec2d { x: 1000, y: 200 })).unwrap();
+        assert_eq!(filter, RegionFilter { position: Vec2d { x: 500, y: 0 }, size: Vec2d { x: 500, y: 50 } });
+
+        assert!("10,20,300".parse::<Region>().is_err());
+        assert!("10,20,300,120%".parse::<Region>().is_err());
+        assert!("1000,0,10,10".parse::<Region>().unwrap().resolve(Some(Vec2d { x: 100, y: 100 })).is_err());
+    }
+
+    #[test]
+    fn test_filter_tiles() {
+        let filter = RegionFilter { position: Vec2d { x: 150, y: 50 }, size: Vec2d { x: 100, y: 10 } };
+        let tiles: Vec<TileReference> = (0..4)
+            .flat_map(|y| (0..4).map(move |x| tile_ref(x * 100, y * 100)))
+            .collect();
+        let kept: Vec<Vec2d> = filter.filter_tiles(tiles).into_iter().map(|t| t.position).collect();
+        assert_eq!(kept, vec![Vec2d { x: 100, y: 0 }, Vec2d { x: 200, y: 0 }]);
+    }
+
+    #[test]
+    fn test_crop_tile() {
+        let filter = RegionFilter { position: Vec2d { x: 1, y: 1 }, size: Vec2d { x: 2, y: 2 } };
+        let image = DynamicImage::ImageLuma8(ImageBuffer::from_raw(2, 2, vec![1, 2, 3, 4]).unwrap());
+        let tile = filter.crop_tile(Tile { image, position: Vec2d { x: 0, y: 0 } }).unwrap();
+        assert_eq!(tile.position, Vec2d { x: 0, y: 0 });
+        assert_eq!(tile.image.dimensions(), (1, 1));
+        assert_eq!(tile.image.to_luma().into_raw(), vec![4]);
+
+        let image = DynamicImage::ImageLuma8(ImageBuffer::from_raw(1, 1, vec![1]).unwrap());
+        assert!(filter.crop_tile(Tile { image, position: Vec2d { x: 3, y: 0 } }).is_none());
+    }
+}
@@ -1,9 +1,14 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
 use image::{GenericImageView, DynamicImage};
+use log::warn;
 
 use crate::{Vec2d, ZoomError};
 use crate::dezoomer::{PostProcessFn, TileReference};
 use crate::errors::BufferToImageError;
-use crate::network::fetch_uri;
+use crate::network::{fetch_uri_timed, HttpClient};
+use crate::tile_cache::TileCache;
 
 #[derive(Clone)]
 pub struct Tile {
@@ -16,12 +21,27 @@ impl Tile {
     pub fn bottom_right(&self) -> Vec2d {
         self.size() + self.position
     }
+    /// Downloads and decodes a tile. Also returns the time the server took to send it,
+    /// or None when the tile was not requested from a server.
     pub async fn download(
         post_process_fn: PostProcessFn,
         tile_reference: &TileReference,
-        client: &reqwest::Client,
-    ) -> Result<Tile, ZoomError> {
-        let bytes = fetch_uri(&tile_reference.url, client).await?;
+        client: &HttpClient,
+        cache: Option<&TileCache>,
+    ) -> Result<(Tile, Option<Duration>), ZoomError> {
+        let url = &tile_reference.url;
+        let cached = match cache {
+            Some(cache) => cache.get(url).await,
+            None => None,
+        };
+        let (bytes, to_cache, latency) = match cached {
+            Some(bytes) => (bytes, None, None),
+            None => {
+                let (bytes, latency) = fetch_uri_timed(url, &HashMap::new(), client).await?;
+                let to_cache = cache.map(|_| bytes.clone());
+                (bytes, to_cache, latency)
+            }
+        };
         let tile_reference = tile_reference.clone();
 
         let tile: Result<Tile, BufferToImageError> = tokio::spawn(async move {
@@ -42,7 +62,14 @@ impl Tile {
                 })
             })
         }).await?;
-        Ok(tile?)
+        let tile = tile?;
+        // Only cache tiles that could be decoded, so that a resumed download retries invalid tiles
+        if let (Some(cache), Some(bytes)) = (cache, to_cache) {
+            if let Err(e) = cache.put(url, &bytes).await {
+                warn!("Unable to save tile '{}' to the cache: {}", url, e);
+            }
+        }
+        Ok((tile, latency))
     }
     pub fn empty(position: Vec2d, size: Vec2d) -> Tile {
         Tile { image: DynamicImage::new_rgba8(size.x, size.y), position }
@@ -0,0 +1,117 @@
+use std::io;
+use std::path::PathBuf;
+
+use log::debug;
+use sha1::{Digest, Sha1};
+use tokio::fs;
+
+/// Headers that can change the contents of a tile, and are thus part of the cache key.
+/// Other headers, such as cookies and session tokens, often change between two runs
+/// and would prevent resuming a download.
+const KEY_HEADERS: [&str; 2] = ["accept", "range"];
+
+/// An on-disk store for the raw bytes of downloaded tiles.
+/// Tiles are keyed by their URL and by the HTTP headers that change their contents,
+/// so that an interrupted download can be resumed without fetching the same tiles again.
+#[derive(Debug, Clone)]
+pub struct TileCache {
+    dir: PathBuf,
+    headers_key: String,
+}
+
+impl TileCache {
+    pub fn new<'a, I: Iterator<Item=(&'a String, &'a String)>>(
+        dir: PathBuf,
+        headers: I,
+    ) -> io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let mut headers: Vec<String> = headers
+            .filter(|(k, _)| KEY_HEADERS.contains(&k.to_ascii_lowercase().as_str()))
+            .map(|(k, v)| format!("{}: {}\n", k.to_ascii_lowercase(), v))
+            .collect();
+        headers.sort();
+        headers.dedup();
+        Ok(TileCache { dir, headers_key: headers.concat() })
+    }
+
+    fn path(&self, url: &str) -> PathBuf {
+        let mut hasher = Sha1::new();
+        hasher.update(self.headers_key.as_bytes());
+        hasher.update(url.as_bytes());
+        self.dir.join(format!("{:x}.tile", hasher.finalize()))
+    }
+
+    /// Returns the bytes of the tile at the given url, if it has already been downloaded
+    pub async fn get(&self, url: &str) -> Option<Vec<u8>> {
+        let path = self.path(url);
+        let contents = fs::read(&path).await.ok()?;
+        debug!("Loaded tile '{}' from the cache at {:?}", url, path);
+        Some(contents)
+    }
+
+    /// Saves the bytes of a tile. The file is written under a temporary name first,
+    /// so that a download interrupted while writing does not leave a truncated tile in the cache
+    pub async fn put(&self, url: &str, contents: &[u8]) -> io::Result<()> {
+        let path = self.path(url);
+        let tmp_path = path.with_extension("part");
+        fs::write(&tmp_path, contents).await?;
+        fs::rename(&tmp_path, &path).await?;
+        debug!("Saved tile '{}' to the cache at {:?}", url, path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(name: &str, headers: &[(String, String)]) -> TileCache {
+        let dir = std::env::temp_dir().join(format!("dezoomify-rs-cache-test-{}", name));
+        let _ = std::fs::remove_dir_all(&dir);
+        TileCache::new(dir, headers.iter().map(|(k, v)| (k, v))).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_get_put() {
+        let cache = cache("get_put", &[]);
+        assert_eq!(cache.get("http://x.com/0.jpg").await, None);
+        cache.put("http://x.com/0.jpg", b"tile0").await.unwrap();
+        assert_eq!(cache.get("http://x.com/0.jpg").await, Some(b"tile0".to_vec()));
+        assert_eq!(cache.get("http://x.com/1.jpg").await, None);
+    }
+
+    #[test]
+    fn test_headers_key() {
+        let referer = ("Referer".to_string(), "http://a.com".to_string());
+        let other_referer = ("Referer".to_string(), "http://b.com".to_string());
+        let cookie = ("Cookie".to_string(), "a=b".to_string());
+        let range = ("Range".to_string(), "bytes=0-99".to_string());
+        let url = "http://x.com/0.jpg";
+        assert_eq!(
+            cache("referer_a", &[referer]).path(url).file_name(),
+            cache("referer_b", &[other_referer]).path(url).file_name(),
+            "The referer should not change the cache key"
+        );
+        assert_eq!(
+            cache("no_cookie", &[]).path(url).file_name(),
+            cache("cookie", &[cookie]).path(url).file_name(),
+            "Cookies should not change the cache key"
+        );
+        assert_ne!(
+            cache("no_range", &[]).path(url).file_name(),
+            cache("range", &[range]).path(url).file_name(),
+            "The range should change the cache key"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_new_session_cookie() {
+        let dir = std::env::temp_dir().join("dezoomify-rs-cache-test-session");
+        let _ = std::fs::remove_dir_all(&dir);
+        let session = |value: &str| [("Cookie".to_string(), format!("session={}", value))];
+        let first_run = TileCache::new(dir.clone(), session("1").iter().map(|(k, v)| (k, v))).unwrap();
+        first_run.put("http://x.com/0.jpg", b"tile0").await.unwrap();
+        let second_run = TileCache::new(dir, session("2").iter().map(|(k, v)| (k, v))).unwrap();
+        assert_eq!(second_run.get("http://x.com/0.jpg").await, Some(b"tile0".to_vec()));
+    }
+}
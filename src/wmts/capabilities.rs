@@ -0,0 +1,222 @@
+use serde::Deserialize;
+
+use crate::Vec2d;
+
+/// The parts of a WMTS GetCapabilities document that describe the tiles of the layers.
+/// See: https://www.ogc.org/standards/wmts
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct Capabilities {
+    #[serde(rename = "OperationsMetadata")]
+    pub operations: Option<OperationsMetadata>,
+    #[serde(rename = "Contents")]
+    pub contents: Contents,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct OperationsMetadata {
+    #[serde(rename = "Operation", default)]
+    pub operations: Vec<Operation>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct Operation {
+    pub name: String,
+    #[serde(rename = "DCP", default)]
+    pub dcp: Vec<Dcp>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct Dcp {
+    #[serde(rename = "HTTP")]
+    pub http: Http,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct Http {
+    #[serde(rename = "Get", default)]
+    pub get: Vec<Get>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct Get {
+    pub href: String,
+    #[serde(rename = "Constraint", default)]
+    pub constraints: Vec<Constraint>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct Constraint {
+    pub name: String,
+    #[serde(rename = "AllowedValues")]
+    pub allowed_values: Option<AllowedValues>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct AllowedValues {
+    #[serde(rename = "Value", default)]
+    pub values: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct Contents {
+    #[serde(rename = "Layer", default)]
+    pub layers: Vec<Layer>,
+    #[serde(rename = "TileMatrixSet", default)]
+    pub tile_matrix_sets: Vec<TileMatrixSet>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct Layer {
+    #[serde(rename = "Title")]
+    pub title: Option<String>,
+    #[serde(rename = "Identifier")]
+    pub identifier: String,
+    #[serde(rename = "Style", default)]
+    pub styles: Vec<Style>,
+    #[serde(rename = "Format", default)]
+    pub formats: Vec<String>,
+    #[serde(rename = "Dimension", default)]
+    pub dimensions: Vec<Dimension>,
+    #[serde(rename = "TileMatrixSetLink", default)]
+    pub tile_matrix_set_links: Vec<TileMatrixSetLink>,
+    #[serde(rename = "ResourceURL", default)]
+    pub resource_urls: Vec<ResourceUrl>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct Style {
+    #[serde(rename = "isDefault", default)]
+    pub is_default: bool,
+    #[serde(rename = "Identifier")]
+    pub identifier: String,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct Dimension {
+    #[serde(rename = "Identifier")]
+    pub identifier: String,
+    #[serde(rename = "Default")]
+    pub default: String,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct TileMatrixSetLink {
+    #[serde(rename = "TileMatrixSet")]
+    pub tile_matrix_set: String,
+    #[serde(rename = "TileMatrixSetLimits")]
+    pub limits: Option<TileMatrixSetLimits>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct TileMatrixSetLimits {
+    #[serde(rename = "TileMatrixLimits", default)]
+    pub limits: Vec<TileMatrixLimits>,
+}
+
+/// The tiles of a tile matrix that contain data
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct TileMatrixLimits {
+    #[serde(rename = "TileMatrix")]
+    pub tile_matrix: String,
+    #[serde(rename = "MinTileRow")]
+    pub min_row: u32,
+    #[serde(rename = "MaxTileRow")]
+    pub max_row: u32,
+    #[serde(rename = "MinTileCol")]
+    pub min_col: u32,
+    #[serde(rename = "MaxTileCol")]
+    pub max_col: u32,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct ResourceUrl {
+    pub format: Option<String>,
+    #[serde(rename = "resourceType")]
+    pub resource_type: String,
+    pub template: String,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct TileMatrixSet {
+    #[serde(rename = "Identifier")]
+    pub identifier: String,
+    #[serde(rename = "TileMatrix", default)]
+    pub tile_matrices: Vec<TileMatrix>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct TileMatrix {
+    #[serde(rename = "Identifier")]
+    pub identifier: String,
+    #[serde(rename = "TileWidth")]
+    pub tile_width: u32,
+    #[serde(rename = "TileHeight")]
+    pub tile_height: u32,
+    #[serde(rename = "MatrixWidth")]
+    pub matrix_width: u32,
+    #[serde(rename = "MatrixHeight")]
+    pub matrix_height: u32,
+}
+
+impl TileMatrix {
+    pub fn tile_size(&self) -> Vec2d {
+        Vec2d { x: self.tile_width, y: self.tile_height }
+    }
+
+    /// The first tile (column and row) and the number of tiles to download
+    pub fn tiles(&self, limits: Option<&TileMatrixLimits>) -> (Vec2d, Vec2d) {
+        let matrix = Vec2d { x: self.matrix_width, y: self.matrix_height };
+        match limits {
+            Some(l) if l.min_col <= l.max_col && l.min_row <= l.max_row => {
+                let first = Vec2d { x: l.min_col, y: l.min_row }.min(matrix);
+                let end = Vec2d { x: l.max_col + 1, y: l.max_row + 1 }.min(matrix);
+                (first, end - first)
+            }
+            _ => (Vec2d::default(), matrix),
+        }
+    }
+}
+
+impl Capabilities {
+    /// The address to which KVP GetTile requests are sent, if the server supports them
+    pub fn kvp_get_tile_url(&self) -> Option<&str> {
+        let operations = &self.operations.as_ref()?.operations;
+        let get_tile = operations.iter().find(|o| o.name == "GetTile")?;
+        get_tile.dcp.iter()
+            .flat_map(|dcp| dcp.http.get.iter())
+            .find(|get| get.constraints.iter()
+                .filter(|c| c.name == "GetEncoding")
+                .flat_map(|c| c.allowed_values.iter().flat_map(|v| v.values.iter()))
+                .all(|encoding| encoding == "KVP"))
+            .map(|get| get.href.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deserialize() {
+        let src = include_str!("../../testdata/wmts/WMTSCapabilities.xml");
+        let capabilities: Capabilities = serde_xml_rs::from_str(src).unwrap();
+        let layer = &capabilities.contents.layers[0];
+        assert_eq!(layer.identifier, "map_sheets");
+        assert_eq!(layer.title.as_deref(), Some("Historical map sheets"));
+        assert!(layer.styles[0].is_default);
+        assert_eq!(layer.dimensions[0].default, "1900");
+        assert_eq!(layer.resource_urls.len(), 2);
+        let set = &capabilities.contents.tile_matrix_sets[0];
+        assert_eq!(set.tile_matrices.len(), 3);
+        assert_eq!(set.tile_matrices[2].matrix_width, 4);
+        assert_eq!(capabilities.kvp_get_tile_url(), Some("https://maps.example.com/wmts?"));
+    }
+
+    #[test]
+    fn test_limits() {
+        let matrix = TileMatrix { identifier: "2".into(), tile_width: 256, tile_height: 256, matrix_width: 4, matrix_height: 4 };
+        let limits = TileMatrixLimits { tile_matrix: "2".into(), min_row: 1, max_row: 2, min_col: 3, max_col: 9 };
+        assert_eq!(matrix.tiles(Some(&limits)), (Vec2d { x: 3, y: 1 }, Vec2d { x: 1, y: 2 }));
+        assert_eq!(matrix.tiles(None), (Vec2d { x: 0, y: 0 }, Vec2d { x: 4, y: 4 }));
+    }
+}
@@ -0,0 +1,193 @@
+use std::fmt::Debug;
+
+use custom_error::custom_error;
+use serde::export::Formatter;
+
+use capabilities::{Capabilities, Layer, TileMatrix, TileMatrixSetLink};
+
+use crate::dezoomer::*;
+
+pub mod capabilities;
+
+/// A dezoomer for the tiled maps of Web Map Tile Services.
+/// It reads the GetCapabilities document of the service.
+/// See: https://www.ogc.org/standards/wmts
+#[derive(Default)]
+pub struct WmtsDezoomer;
+
+impl Dezoomer for WmtsDezoomer {
+    fn name(&self) -> &'static str {
+        "wmts"
+    }
+
+    fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
+        let lowercase_uri = data.uri.to_lowercase();
+        self.assert(lowercase_uri.contains("wmts") || lowercase_uri.contains("getcapabilities"))?;
+        let DezoomerInputWithContents { uri, contents } = data.with_contents()?;
+        let levels = load_from_capabilities(uri, contents)?;
+        Ok(levels)
+    }
+}
+
+custom_error! {pub WmtsError
+    XmlError{source: serde_xml_rs::Error} = "Unable to parse the WMTS capabilities: {source}",
+    NoTiles = "The WMTS capabilities do not describe any tiled layer",
+}
+
+impl From<WmtsError> for DezoomerError {
+    fn from(err: WmtsError) -> Self {
+        DezoomerError::Other { source: err.into() }
+    }
+}
+
+fn load_from_capabilities(uri: &str, contents: &[u8]) -> Result<ZoomLevels, WmtsError> {
+    let capabilities: Capabilities = serde_xml_rs::from_reader(contents)?;
+    let kvp_url = capabilities.kvp_get_tile_url()
+        .map(String::from)
+        .unwrap_or_else(|| uri.split('?').next().unwrap_or_default().to_string());
+    let mut levels = vec![];
+    for layer in &capabilities.contents.layers {
+        for link in &layer.tile_matrix_set_links {
+            let set = capabilities.contents.tile_matrix_sets.iter()
+                .find(|set| set.identifier == link.tile_matrix_set);
+            for matrix in set.iter().flat_map(|set| set.tile_matrices.iter()) {
+                levels.push(WmtsLevel::new(&kvp_url, layer, link, matrix));
+            }
+        }
+    }
+    if levels.is_empty() { return Err(WmtsError::NoTiles); }
+    Ok(levels.into_iter().into_zoom_levels())
+}
+
+struct WmtsLevel {
+    /// The url of the tiles, with `{TileRow}` and `{TileCol}` placeholders
+    url_template: String,
+    title: Option<String>,
+    name: String,
+    tile_size: Vec2d,
+    first_tile: Vec2d,
+    tiles: Vec2d,
+}
+
+impl WmtsLevel {
+    fn new(kvp_url: &str, layer: &Layer, link: &TileMatrixSetLink, matrix: &TileMatrix) -> Self {
+        let style = layer.styles.iter().find(|s| s.is_default).or_else(|| layer.styles.first())
+            .map_or("default", |s| s.identifier.as_str());
+        let rest_template = layer.resource_urls.iter()
+            .find(|r| r.resource_type.eq_ignore_ascii_case("tile"))
+            .map(|r| r.template.as_str());
+        let url_template = match rest_template {
+            Some(template) => {
+                let mut url = template
+                    .replace("{TileMatrixSet}", &link.tile_matrix_set)
+                    .replace("{TileMatrix}", &matrix.identifier)
+                    .replace("{Style}", style);
+                for dimension in &layer.dimensions {
+                    url = url.replace(&format!("{{{}}}", dimension.identifier), &dimension.default);
+                }
+                url
+            }
+            None => {
+                let separator = match kvp_url.chars().last() {
+                    Some('?') | Some('&') => "",
+                    _ if kvp_url.contains('?') => "&",
+                    _ => "?",
+                };
+                let format = layer.formats.first().map_or("image/png", String::as_str);
+                let mut params = vec![
+                    ("SERVICE", "WMTS"),
+                    ("REQUEST", "GetTile"),
+                    ("VERSION", "1.0.0"),
+                    ("LAYER", layer.identifier.as_str()),
+                    ("STYLE", style),
+                    ("FORMAT", format),
+                    ("TILEMATRIXSET", link.tile_matrix_set.as_str()),
+                    ("TILEMATRIX", matrix.identifier.as_str()),
+                ];
+                for dimension in &layer.dimensions {
+                    params.push((dimension.identifier.as_str(), dimension.default.as_str()));
+                }
+                let query = serde_urlencoded::to_string(params).expect("parameters are encodable");
+                format!("{}{}{}&TILEROW={{TileRow}}&TILECOL={{TileCol}}", kvp_url, separator, query)
+            }
+        };
+        let limits = link.limits.iter()
+            .flat_map(|l| l.limits.iter())
+            .find(|l| l.tile_matrix == matrix.identifier);
+        let (first_tile, tiles) = matrix.tiles(limits);
+        WmtsLevel {
+            url_template,
+            title: layer.title.clone(),
+            name: format!("{} {} {}", layer.identifier, link.tile_matrix_set, matrix.identifier),
+            tile_size: matrix.tile_size(),
+            first_tile,
+            tiles,
+        }
+    }
+}
+
+impl TilesRect for WmtsLevel {
+    fn size(&self) -> Vec2d {
+        self.tiles * self.tile_size
+    }
+
+    fn tile_size(&self) -> Vec2d {
+        self.tile_size
+    }
+
+    fn tile_url(&self, pos: Vec2d) -> String {
+        let Vec2d { x: col, y: row } = self.first_tile + pos;
+        self.url_template
+            .replace("{TileRow}", &row.to_string())
+            .replace("{TileCol}", &col.to_string())
+    }
+
+    fn title(&self) -> Option<String> {
+        self.title.clone()
+    }
+}
+
+impl Debug for WmtsLevel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "WMTS {}", self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn levels() -> ZoomLevels {
+        let contents = include_bytes!("../../testdata/wmts/WMTSCapabilities.xml");
+        let uri = "https://maps.example.com/wmts?SERVICE=WMTS&REQUEST=GetCapabilities";
+        let input = DezoomerInput { uri: uri.into(), contents: Some(contents.to_vec()) };
+        WmtsDezoomer.zoom_levels(&input).unwrap()
+    }
+
+    #[test]
+    fn test_restful() {
+        let mut levels = levels();
+        assert_eq!(levels.len(), 6);
+        let sizes: Vec<_> = levels[..3].iter().map(|l| l.size_hint().unwrap()).collect();
+        assert_eq!(sizes, vec![Vec2d::square(256), Vec2d { x: 256, y: 512 }, Vec2d::square(512)]);
+        assert_eq!(levels[2].title().as_deref(), Some("Historical map sheets"));
+        let tiles: Vec<String> = levels[2].next_tiles(None).into_iter().map(|t| t.url).collect();
+        assert_eq!(tiles[..2], [
+            "https://maps.example.com/wmts/rest/map_sheets/default/1900/GoogleMapsCompatible/2/1/2.jpg",
+            "https://maps.example.com/wmts/rest/map_sheets/default/1900/GoogleMapsCompatible/2/1/3.jpg",
+        ]);
+        assert_eq!(tiles[3], "https://maps.example.com/wmts/rest/map_sheets/default/1900/GoogleMapsCompatible/2/2/3.jpg");
+    }
+
+    #[test]
+    fn test_kvp() {
+        let mut levels = levels();
+        let level = &mut levels[4];
+        assert_eq!(level.size_hint(), Some(Vec2d::square(512)));
+        let tile = level.next_tiles(None).into_iter().last().unwrap();
+        assert_eq!(tile.url, "https://maps.example.com/wmts?SERVICE=WMTS&REQUEST=GetTile&VERSION=1.0.0\
+            &LAYER=ortho&STYLE=normal+%26+relief&FORMAT=image%2Fpng&TILEMATRIXSET=GoogleMapsCompatible&TILEMATRIX=1\
+            &TILEROW=1&TILECOL=1");
+        assert_eq!(tile.position, Vec2d::square(256));
+    }
+}
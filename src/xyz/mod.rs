@@ -0,0 +1,308 @@
+use std::f64::consts::PI;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use custom_error::custom_error;
+use log::warn;
+use serde::export::Formatter;
+
+use crate::dezoomer::{Dezoomer, DezoomerError, DezoomerInput, IntoZoomLevels, TilesRect, ZoomLevels};
+use crate::Vec2d;
+
+/// A dezoomer for the slippy map tiles used by Leaflet, OpenLayers and most online maps,
+/// with tile URL templates like `https://{s}.tiles.example.com/{z}/{x}/{y}.png`.
+/// Options are given in the fragment of the template,
+/// for instance `https://tiles.example.com/{z}/{x}/{y}.png#zoom=10-14&bbox=2.2,48.8,2.4,48.9`
+#[derive(Default)]
+pub struct XyzDezoomer;
+
+const DEFAULT_SUBDOMAINS: &str = "abc";
+const DEFAULT_ZOOMS: (u32, u32) = (0, 18);
+const MAX_ZOOM: u32 = 30;
+
+impl Dezoomer for XyzDezoomer {
+    fn name(&self) -> &'static str {
+        "xyz"
+    }
+
+    fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
+        self.assert(is_template(&data.uri))?;
+        let map = TileMap::parse(&data.uri).map_err(DezoomerError::wrap)?;
+        let levels: Vec<Level> = map.levels().collect();
+        if levels.is_empty() { return Err(DezoomerError::wrap(XyzError::TooLarge)); }
+        Ok(levels.into_iter().into_zoom_levels())
+    }
+}
+
+fn is_template(uri: &str) -> bool {
+    uri.contains("{z}") && uri.contains("{x}") && (uri.contains("{y}") || uri.contains("{-y}"))
+}
+
+custom_error! {pub XyzError
+    InvalidOption{option: String, message: &'static str} =
+        "Invalid option '{option}' in the fragment of the tile URL: {message}",
+    TooLarge = "All the zoom levels of the map are too large to be downloaded. \
+                Use a smaller zoom range or a smaller bbox.",
+}
+
+/// A rectangle on the map, in web mercator coordinates between 0 and 1,
+/// with the origin at the top left corner (north-west) of the world
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Extent {
+    left: f64,
+    top: f64,
+    right: f64,
+    bottom: f64,
+}
+
+impl Extent {
+    const WORLD: Extent = Extent { left: 0., top: 0., right: 1., bottom: 1. };
+
+    /// From a bounding box in degrees: west, south, east, north
+    fn from_lon_lat(west: f64, south: f64, east: f64, north: f64) -> Self {
+        let (left, bottom) = mercator(west, south);
+        let (right, top) = mercator(east, north);
+        Extent { left, top, right, bottom }
+    }
+
+    /// From the indices of the first and last tiles at a zoom level, in the xyz numbering
+    fn from_tiles(zoom: u32, x0: u32, y0: u32, x1: u32, y1: u32) -> Self {
+        let n = f64::from(1u32 << zoom);
+        Extent {
+            left: f64::from(x0.min(x1)) / n,
+            top: f64::from(y0.min(y1)) / n,
+            right: f64::from(x0.max(x1) + 1) / n,
+            bottom: f64::from(y0.max(y1) + 1) / n,
+        }
+    }
+
+    /// The indices of the first tile and the number of tiles covering the extent at a zoom level
+    fn tiles(&self, zoom: u32) -> (Vec2d, Vec2d) {
+        let n = f64::from(1u32 << zoom);
+        let index = |v: f64| (v * n).max(0.).min(n) as u32;
+        let end = |v: f64| (v * n).ceil().max(1.).min(n) as u32;
+        let first = Vec2d { x: index(self.left).min(end(self.right) - 1), y: index(self.top).min(end(self.bottom) - 1) };
+        let last = Vec2d { x: end(self.right), y: end(self.bottom) };
+        (first, last - first)
+    }
+}
+
+/// Projects longitude and latitude in degrees to web mercator coordinates between 0 and 1
+fn mercator(lon: f64, lat: f64) -> (f64, f64) {
+    let x = (lon + 180.) / 360.;
+    let lat = lat.clamp(-85.051_128_78, 85.051_128_78).to_radians();
+    let y = (1. - (lat.tan() + 1. / lat.cos()).ln() / PI) / 2.;
+    (x, y)
+}
+
+#[derive(Debug, PartialEq)]
+struct TileMap {
+    template: String,
+    subdomains: Vec<String>,
+    zooms: (u32, u32),
+    extent: Extent,
+    tile_size: u32,
+}
+
+impl TileMap {
+    fn parse(uri: &str) -> Result<Self, XyzError> {
+        let mut parts = uri.splitn(2, '#');
+        let template = parts.next().unwrap_or_default().to_string();
+        let mut map = TileMap {
+            template,
+            subdomains: DEFAULT_SUBDOMAINS.chars().map(String::from).collect(),
+            zooms: DEFAULT_ZOOMS,
+            extent: Extent::WORLD,
+            tile_size: 256,
+        };
+        let tms = map.template.contains("{-y}");
+        for option in parts.next().unwrap_or_default().split('&').filter(|o| !o.is_empty()) {
+            let invalid = |message| XyzError::InvalidOption { option: option.to_string(), message };
+            let mut key_value = option.splitn(2, '=');
+            let key = key_value.next().unwrap_or_default();
+            let value = key_value.next().ok_or_else(|| invalid("expected 'name=value'"))?;
+            match key {
+                "zoom" => {
+                    let mut range = value.splitn(2, '-').map(|z| z.trim().parse::<u32>());
+                    let min = range.next().and_then(Result::ok).ok_or_else(|| invalid("expected 'zoom=12' or 'zoom=10-14'"))?;
+                    let max = match range.next() {
+                        Some(max) => max.map_err(|_| invalid("expected 'zoom=12' or 'zoom=10-14'"))?,
+                        None => min,
+                    };
+                    if min > max || max > MAX_ZOOM { return Err(invalid("invalid zoom range")); }
+                    map.zooms = (min, max);
+                }
+                "bbox" => match numbers(value)[..] {
+                    [Some(west), Some(south), Some(east), Some(north)] if west < east && south < north =>
+                        map.extent = Extent::from_lon_lat(west, south, east, north),
+                    _ => return Err(invalid("expected 'bbox=west,south,east,north' in degrees")),
+                },
+                "tiles" => {
+                    let mut at = value.splitn(2, '@');
+                    let indices = numbers(at.next().unwrap_or_default());
+                    let zoom = at.next().and_then(|z| z.trim().parse::<u32>().ok()).filter(|&z| z <= MAX_ZOOM);
+                    let index = |i: usize| indices.get(i).copied().flatten()
+                        .filter(|v| v.fract() == 0. && *v >= 0.)
+                        .map(|v| v as u32);
+                    match (zoom, index(0), index(1), index(2), index(3), indices.len()) {
+                        (Some(z), Some(x0), Some(y0), Some(x1), Some(y1), 4) => {
+                            if x0.max(x1).max(y0).max(y1) >= 1u32 << z {
+                                return Err(invalid("tile indices must be smaller than 2^zoom"));
+                            }
+                            let flip = |y: u32| if tms { ((1u32 << z) - 1).saturating_sub(y) } else { y };
+                            map.extent = Extent::from_tiles(z, x0, flip(y0), x1, flip(y1))
+                        }
+                        _ => return Err(invalid("expected 'tiles=x0,y0,x1,y1@zoom'")),
+                    }
+                }
+                "subdomains" => {
+                    map.subdomains = if value.contains(',') {
+                        value.split(',').map(String::from).collect()
+                    } else {
+                        value.chars().map(String::from).collect()
+                    };
+                    if map.subdomains.is_empty() { return Err(invalid("no subdomain")); }
+                }
+                "tilesize" => {
+                    map.tile_size = value.parse().ok().filter(|&s| s > 0)
+                        .ok_or_else(|| invalid("expected a number of pixels"))?;
+                }
+                _ => return Err(invalid("unknown option")),
+            }
+        }
+        Ok(map)
+    }
+
+    /// The zoom levels of the map, without the ones whose size in pixels does not fit in an image
+    fn levels(self) -> impl Iterator<Item=Level> {
+        let map = Arc::new(self);
+        let (min, max) = map.zooms;
+        (min..=max).filter_map(move |zoom| {
+            let (first_tile, tiles) = map.extent.tiles(zoom);
+            match (tiles.x.checked_mul(map.tile_size), tiles.y.checked_mul(map.tile_size)) {
+                (Some(x), Some(y)) => Some(Level { map: Arc::clone(&map), zoom, first_tile, size: Vec2d { x, y } }),
+                _ => {
+                    warn!("Ignoring the zoom level {}, which is too large: {} tiles", zoom, tiles);
+                    None
+                }
+            }
+        })
+    }
+}
+
+fn numbers(s: &str) -> Vec<Option<f64>> {
+    s.split(',').map(|n| n.trim().parse().ok()).collect()
+}
+
+struct Level {
+    map: Arc<TileMap>,
+    zoom: u32,
+    first_tile: Vec2d,
+    /// Size of the level in pixels
+    size: Vec2d,
+}
+
+impl Debug for Level {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Map tiles at zoom {}", self.zoom)
+    }
+}
+
+impl TilesRect for Level {
+    fn size(&self) -> Vec2d {
+        self.size
+    }
+
+    fn tile_size(&self) -> Vec2d {
+        Vec2d::square(self.map.tile_size)
+    }
+
+    fn tile_url(&self, pos: Vec2d) -> String {
+        let Vec2d { x, y } = self.first_tile + pos;
+        let tms_y = (1u32 << self.zoom) - 1 - y;
+        let subdomains = &self.map.subdomains;
+        let subdomain = &subdomains[((x + y) as usize) % subdomains.len()];
+        self.map.template
+            .replace("{z}", &self.zoom.to_string())
+            .replace("{x}", &x.to_string())
+            .replace("{-y}", &tms_y.to_string())
+            .replace("{y}", &y.to_string())
+            .replace("{s}", subdomain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dezoomer::TileProvider;
+
+    fn urls(uri: &str) -> Vec<(u32, Vec2d, Vec<String>)> {
+        TileMap::parse(uri).unwrap().levels().map(|mut level| {
+            let tiles = level.next_tiles(None).into_iter().map(|t| t.url).collect();
+            (level.zoom, level.size(), tiles)
+        }).collect()
+    }
+
+    #[test]
+    fn test_tile_indices() {
+        let levels = urls("https://{s}.tiles.example.com/{z}/{x}/{y}.png#zoom=3-4&tiles=4,2,5,2@3");
+        assert_eq!(levels, vec![
+            (3, Vec2d { x: 512, y: 256 }, vec![
+                "https://a.tiles.example.com/3/4/2.png".to_string(),
+                "https://b.tiles.example.com/3/5/2.png".to_string(),
+            ]),
+            (4, Vec2d { x: 1024, y: 512 }, (4..6).flat_map(|y| (8..12).map(move |x|
+                format!("https://{}.tiles.example.com/4/{}/{}.png", ["a", "b", "c"][((x + y) % 3) as usize], x, y)
+            )).collect()),
+        ]);
+    }
+
+    #[test]
+    fn test_tms() {
+        // In TMS, the y axis points north, and the tile indices are given in this numbering
+        let levels = urls("http://example.com/{z}/{x}/{-y}.jpg#zoom=2&tiles=1,3,1,3@2");
+        assert_eq!(levels, vec![(2, Vec2d::square(256), vec!["http://example.com/2/1/3.jpg".to_string()])]);
+    }
+
+    #[test]
+    fn test_lon_lat() {
+        // Paris, at zoom 10
+        let map = TileMap::parse("http://t/{z}/{x}/{y}.png#zoom=10&bbox=2.25,48.81,2.42,48.90&subdomains=ab&tilesize=512").unwrap();
+        assert_eq!(map.subdomains, vec!["a", "b"]);
+        let level = map.levels().next().unwrap();
+        assert_eq!(level.first_tile, Vec2d { x: 518, y: 352 });
+        assert_eq!(level.size(), Vec2d::square(512));
+    }
+
+    #[test]
+    fn test_world() {
+        let map = TileMap::parse("http://t/{z}/{x}/{y}.png").unwrap();
+        assert_eq!(map.zooms, DEFAULT_ZOOMS);
+        let sizes: Vec<Vec2d> = map.levels().take(3).map(|l| l.size()).collect();
+        assert_eq!(sizes, vec![Vec2d::square(256), Vec2d::square(512), Vec2d::square(1024)]);
+    }
+
+    #[test]
+    fn test_too_large() {
+        let map = TileMap::parse("http://t/{z}/{x}/{y}.png#zoom=0-24").unwrap();
+        let zooms: Vec<u32> = map.levels().map(|l| l.zoom).collect();
+        // 2^24 tiles of 256 pixels do not fit in 32 bits
+        assert_eq!(zooms, (0..=23).collect::<Vec<_>>());
+        assert_eq!(TileMap::parse("http://t/{z}/{x}/{y}.png#zoom=24").unwrap().levels().count(), 0);
+        let input = DezoomerInput { uri: "http://t/{z}/{x}/{y}.png#zoom=25-30".into(), contents: None };
+        assert!(XyzDezoomer.zoom_levels(&input).is_err());
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        let fragments = [
+            "zoom=5-2", "zoom=x", "bbox=1,2,3", "tiles=1,2,3,4", "tilesize=0", "color=red", "zoom",
+            "tiles=8,0,8,0@3", "tiles=0,0,4294967295,0@3", "tiles=0,0,1e12,0@30",
+        ];
+        for fragment in &fragments {
+            let uri = format!("http://t/{{z}}/{{x}}/{{y}}.png#{}", fragment);
+            assert!(TileMap::parse(&uri).is_err(), "{} should be invalid", fragment);
+        }
+        assert!(!is_template("http://t/{{X}}/{{Y}}.png"));
+    }
+}
@@ -30,46 +30,32 @@ impl ImageProperties {
         }
     }
 
+    /// Properties of an image in the layout created by the official zoomify converter
+    pub fn new(size: Vec2d, tile_size: u32) -> Self {
+        let mut props = ImageProperties { width: size.x, height: size.y, tile_size, num_tiles: 0 };
+        let (_, tiles_before) = props.converter_levels();
+        props.num_tiles = tiles_before.iter().sum();
+        props
+    }
+
+    pub fn to_xml(&self) -> String {
+        format!(
+            r#"<IMAGE_PROPERTIES WIDTH="{}" HEIGHT="{}" NUMTILES="{}" NUMIMAGES="1" VERSION="1.8" TILESIZE="{}" />"#,
+            self.width, self.height, self.num_tiles, self.tile_size
+        )
+    }
+
     pub fn levels(&self) -> Vec<ZoomLevelInfo> {
-        // Reimplementation of the algorithm of zoomify.js
-        let tile_size = self.tile_size();
-        let mut width = self.width as f64;
-        let mut height = self.height as f64;
-        let mut level_tiles = Vec::new();
-        let mut tiles_before = Vec::new();
-        let tile_width = tile_size.x as f64;
-        let tile_height = tile_size.y as f64;
-        while width > tile_width || height > tile_height {
-            let tiles = (width / tile_width).ceil() * (height / tile_height).ceil();
-            tiles_before.push(tiles as u32);
-            level_tiles.push(ZoomLevelInfo {
-                size: Vec2d { x: width as u32, y: height as u32 },
-                tile_size,
-                tiles_before: 0, // Will be replaced in the end
-            });
-            width /= 2.;
-            height /= 2.;
-        }
+        let (mut level_tiles, mut tiles_before) = self.zoomify_js_levels();
         let computed_tile_count = tiles_before.iter().sum::<u32>();
         if computed_tile_count != self.num_tiles {
             info!("The computed number of tiles ({}) does not match \
             the number of tiles specified in ImageProperties.xml ({}). \
             Trying the second computation method..."
                   , computed_tile_count, self.num_tiles);
-            level_tiles.clear();
-            tiles_before.clear();
-            let mut size = self.size();
-            let mut level_size_ratio = Vec2d { x: 2, y: 2 };
-            loop {
-                let size_in_tiles = size.ceil_div(tile_size);
-                tiles_before.push(size_in_tiles.area().try_into().unwrap());
-                level_tiles.push(ZoomLevelInfo { size, tile_size, tiles_before: 0 });
-                if size.x <= tile_size.x && size.y <= tile_size.y { break }
-                size = self.size() / level_size_ratio;
-                if size.x % 2 != 0 { size.x += 1 }
-                if size.y % 2 != 0 { size.y += 1 }
-                level_size_ratio = level_size_ratio * Vec2d { x: 2, y: 2 };
-            }
+            let (converter_level_tiles, converter_tiles_before) = self.converter_levels();
+            level_tiles = converter_level_tiles;
+            tiles_before = converter_tiles_before;
         }
         if log::log_enabled!(log::Level::Warn) {
             let computed_tile_count = tiles_before.iter().sum::<u32>();
@@ -88,6 +74,51 @@ impl ImageProperties {
         }
         level_tiles
     }
+
+    /// Reimplementation of the algorithm of zoomify.js.
+    /// Returns the levels from the largest to the smallest, and the number of tiles in each level
+    fn zoomify_js_levels(&self) -> (Vec<ZoomLevelInfo>, Vec<u32>) {
+        let tile_size = self.tile_size();
+        let mut width = self.width as f64;
+        let mut height = self.height as f64;
+        let mut level_tiles = Vec::new();
+        let mut tiles_before = Vec::new();
+        let tile_width = tile_size.x as f64;
+        let tile_height = tile_size.y as f64;
+        while width > tile_width || height > tile_height {
+            let tiles = (width / tile_width).ceil() * (height / tile_height).ceil();
+            tiles_before.push(tiles as u32);
+            level_tiles.push(ZoomLevelInfo {
+                size: Vec2d { x: width as u32, y: height as u32 },
+                tile_size,
+                tiles_before: 0, // Will be replaced in the end
+            });
+            width /= 2.;
+            height /= 2.;
+        }
+        (level_tiles, tiles_before)
+    }
+
+    /// The levels created by the official zoomify converter, down to a single tile.
+    /// Returns the levels from the largest to the smallest, and the number of tiles in each level
+    fn converter_levels(&self) -> (Vec<ZoomLevelInfo>, Vec<u32>) {
+        let tile_size = self.tile_size();
+        let mut level_tiles = Vec::new();
+        let mut tiles_before = Vec::new();
+        let mut size = self.size();
+        let mut level_size_ratio = Vec2d { x: 2, y: 2 };
+        loop {
+            let size_in_tiles = size.ceil_div(tile_size);
+            tiles_before.push(size_in_tiles.area().try_into().unwrap());
+            level_tiles.push(ZoomLevelInfo { size, tile_size, tiles_before: 0 });
+            if size.x <= tile_size.x && size.y <= tile_size.y { break }
+            size = self.size() / level_size_ratio;
+            if size.x % 2 != 0 { size.x += 1 }
+            if size.y % 2 != 0 { size.y += 1 }
+            level_size_ratio = level_size_ratio * Vec2d { x: 2, y: 2 };
+        }
+        (level_tiles, tiles_before)
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -120,6 +151,14 @@ fn test_deserialize() {
     assert_eq!(props.num_tiles, 217);
 }
 
+#[test]
+fn test_new_round_trip() {
+    let props = ImageProperties::new(Vec2d { x: 2052, y: 3185 }, 256);
+    assert_eq!(props.num_tiles, 1 + 2 + 12 + 35 + 117);
+    let parsed: ImageProperties = serde_xml_rs::from_str(&props.to_xml()).unwrap();
+    assert_eq!(parsed, props);
+}
+
 #[test]
 fn test_real_num_tiles() {
     // An image with 3 levels: 10x5 6x2 and 2x2
@@ -1,16 +1,22 @@
+use std::io::Cursor;
 use std::sync::Arc;
 
 use custom_error::custom_error;
 use image_properties::{ImageProperties, ZoomLevelInfo};
+use probe::ZoomifyProbe;
 
 use crate::dezoomer::*;
 
-mod image_properties;
+pub mod image_properties;
+mod probe;
 
 /// Dezoomer for the zoomify image format.
 /// See: http://zoomify.com/
 #[derive(Default)]
-pub struct ZoomifyDezoomer;
+pub struct ZoomifyDezoomer {
+    /// Finds the levels of an image whose ImageProperties.xml is not available
+    probe: Option<ZoomifyProbe>,
+}
 
 impl Dezoomer for ZoomifyDezoomer {
     fn name(&self) -> &'static str {
@@ -18,6 +24,19 @@ impl Dezoomer for ZoomifyDezoomer {
     }
 
     fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
+        if self.probe.is_none() {
+            self.probe = ZoomifyProbe::from_uri(&data.uri);
+        }
+        if let Some(probe) = &mut self.probe {
+            if let (Some(contents), Some(uri)) = (&data.contents, probe.next_tile()) {
+                if uri == data.uri { probe.record(tile_size(contents)); }
+            }
+            return match (probe.next_tile(), probe.largest_level()) {
+                (Some(uri), _) => Err(DezoomerError::NeedsTile { uri }),
+                (None, Some(level)) => single_level(level),
+                (None, None) => Err(ZoomifyError::NoProbedTile.into()),
+            };
+        }
         self.assert(data.uri.contains("/ImageProperties.xml"))?;
         let DezoomerInputWithContents { uri, contents } = data.with_contents()?;
         let levels = load_from_properties(uri, contents)?;
@@ -26,7 +45,8 @@ impl Dezoomer for ZoomifyDezoomer {
 }
 
 custom_error! {pub ZoomifyError
-    XmlError{source: serde_xml_rs::Error} = "Unable to parse ImageProperties.xml: {source}"
+    XmlError{source: serde_xml_rs::Error} = "Unable to parse ImageProperties.xml: {source}",
+    NoProbedTile = "No tile of the zoomify image could be found",
 }
 
 impl From<ZoomifyError> for DezoomerError {
@@ -35,6 +55,14 @@ impl From<ZoomifyError> for DezoomerError {
     }
 }
 
+/// The size of a tile, from the header of its image file.
+/// Missing tiles are given as empty files.
+fn tile_size(contents: &[u8]) -> Option<Vec2d> {
+    if contents.is_empty() { return None; }
+    let reader = image::io::Reader::new(Cursor::new(contents)).with_guessed_format().ok()?;
+    reader.into_dimensions().ok().map(Vec2d::from)
+}
+
 fn load_from_properties(url: &str, contents: &[u8]) -> Result<ZoomLevels, ZoomifyError> {
     let image_properties: ImageProperties = serde_xml_rs::from_reader(contents)?;
     let base_url = &Arc::new(url.split("/ImageProperties.xml").next().unwrap().into());
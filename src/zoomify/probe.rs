@@ -0,0 +1,300 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use log::warn;
+
+use crate::dezoomer::TilesRect;
+use crate::Vec2d;
+
+lazy_static! {
+    static ref TILE_RE: Regex = Regex::new(
+        r"^(?P<base>.*)/TileGroup\d+/\d+-\d+-\d+\.(?P<extension>\w+)(?P<query>\?[^#]*)?$"
+    ).unwrap();
+}
+
+/// Marks the url of the directory of a zoomify image whose ImageProperties.xml cannot be loaded
+const PROBE_FRAGMENT: &str = "#zoomify";
+
+/// A zoomify image whose ImageProperties.xml is not available.
+/// The tile groups are numbered from the tile counts of all the levels,
+/// so the grid of each level is found by requesting the tiles one by one,
+/// from the smallest level to the first one that does not exist.
+/// Each level has between 2n-1 and 2n+1 columns, where n is the number of columns of the previous level,
+/// and the same goes for rows: this takes at most a few requests per level,
+/// where bisecting the largest level with `generic::dichotomy_2d` would not give the tile group numbers.
+/// The probing requests are made while listing the zoom levels, before the download starts.
+pub struct ZoomifyProbe {
+    base_url: String,
+    extension: String,
+    query: String,
+    /// Number of columns and rows of the levels found so far
+    levels: Vec<Vec2d>,
+    /// Size of the first tile of the last level found
+    first_tile_size: Option<Vec2d>,
+    state: ProbeState,
+    image_size: Option<Vec2d>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ProbeState {
+    /// Requesting the first tile of the next level
+    Level,
+    /// Requesting the tile with this x coordinate in the first row of the last level
+    Columns { candidate: u32 },
+    /// Requesting the tile with this y coordinate in the first column of the last level
+    Rows { candidate: u32 },
+    /// Requesting the bottom right tile of the largest level, to find the size of the image
+    LastTile,
+    Done,
+}
+
+impl ZoomifyProbe {
+    /// Recognizes the url of a tile, or the url of the image directory followed by `#zoomify`
+    pub fn from_uri(uri: &str) -> Option<Self> {
+        let (base_url, extension, query) = if let Some(caps) = TILE_RE.captures(uri) {
+            let query = caps.name("query").map(|m| m.as_str()).unwrap_or_default();
+            (caps["base"].to_string(), caps["extension"].to_string(), query.to_string())
+        } else if let Some(directory) = uri.strip_suffix(PROBE_FRAGMENT) {
+            let base = directory.trim_end_matches("ImageProperties.xml");
+            (base.trim_end_matches('/').to_string(), "jpg".to_string(), String::new())
+        } else {
+            return None;
+        };
+        Some(ZoomifyProbe {
+            base_url,
+            extension,
+            query,
+            levels: vec![],
+            first_tile_size: None,
+            state: ProbeState::Level,
+            image_size: None,
+        })
+    }
+
+    fn tile_url(&self, level: usize, pos: Vec2d) -> String {
+        let grid = self.levels.get(level).copied().unwrap_or_else(|| Vec2d::square(1));
+        let tiles_before: u32 = self.levels[..level.min(self.levels.len())].iter().map(|l| l.area() as u32).sum();
+        let group = (tiles_before + pos.x + pos.y * grid.x) / 256;
+        format!(
+            "{base}/TileGroup{group}/{z}-{x}-{y}.{extension}{query}",
+            base = self.base_url, group = group, z = level, x = pos.x, y = pos.y,
+            extension = self.extension, query = self.query
+        )
+    }
+
+    /// The url of the tile to request next, or None when the probing is finished
+    pub fn next_tile(&self) -> Option<String> {
+        let last_level = self.levels.len().saturating_sub(1);
+        let last_grid = self.levels.last().copied().unwrap_or_default();
+        match self.state {
+            ProbeState::Level => Some(self.tile_url(self.levels.len(), Vec2d::default())),
+            ProbeState::Columns { candidate } => Some(self.tile_url(last_level, Vec2d { x: candidate, y: 0 })),
+            ProbeState::Rows { candidate } => Some(self.tile_url(last_level, Vec2d { x: 0, y: candidate })),
+            ProbeState::LastTile => Some(self.tile_url(last_level, last_grid - Vec2d::square(1))),
+            ProbeState::Done => None,
+        }
+    }
+
+    /// The size of the tiles of the largest level
+    fn tile_size(&self) -> Vec2d {
+        let Vec2d { x, y } = self.first_tile_size.unwrap_or_else(|| Vec2d::square(256));
+        Vec2d::square(x.max(y))
+    }
+
+    /// Updates the state of the probe with the size of the tile returned by `next_tile`,
+    /// or None if it does not exist
+    pub fn record(&mut self, tile: Option<Vec2d>) {
+        let success = tile.is_some();
+        // The grid of the level before the one being probed
+        let previous = self.levels.len().checked_sub(2).map(|i| self.levels[i]).unwrap_or_default();
+        self.state = match self.state {
+            ProbeState::Level if success => {
+                let last = self.levels.last().copied();
+                // The grid of the new level is not known yet, and only its first tile is requested
+                self.levels.push(Vec2d::square(1));
+                self.first_tile_size = tile;
+                match last {
+                    // The smallest level is always made of a single tile
+                    None => ProbeState::Level,
+                    Some(last) => ProbeState::Columns { candidate: 2 * last.x },
+                }
+            }
+            ProbeState::Level if self.levels.is_empty() => ProbeState::Done,
+            ProbeState::Level => ProbeState::LastTile,
+            ProbeState::Columns { candidate } => {
+                let columns = if success { candidate + 1 } else { candidate };
+                if !success && candidate >= 2 * previous.x {
+                    ProbeState::Columns { candidate: candidate - 1 }
+                } else {
+                    self.levels.last_mut().expect("a level was found").x = columns;
+                    ProbeState::Rows { candidate: 2 * previous.y }
+                }
+            }
+            ProbeState::Rows { candidate } => {
+                let rows = if success { candidate + 1 } else { candidate };
+                if !success && candidate >= 2 * previous.y {
+                    ProbeState::Rows { candidate: candidate - 1 }
+                } else {
+                    self.levels.last_mut().expect("a level was found").y = rows;
+                    ProbeState::Level
+                }
+            }
+            ProbeState::LastTile => {
+                let grid = *self.levels.last().expect("a level was found");
+                let tile_size = self.tile_size();
+                self.image_size = tile.map(|last_tile| tile_size * (grid - Vec2d::square(1)) + last_tile);
+                ProbeState::Done
+            }
+            ProbeState::Done => ProbeState::Done,
+        };
+    }
+
+    /// The largest level found, once the probing is finished
+    pub fn largest_level(&self) -> Option<ProbedLevel> {
+        let level = self.levels.len().checked_sub(1).filter(|_| self.state == ProbeState::Done)?;
+        let grid = self.levels[level];
+        let tile_size = self.tile_size();
+        let size = self.image_size.unwrap_or_else(|| {
+            warn!("The last tile of the zoomify image is missing. The image may have blank margins.");
+            grid * tile_size
+        });
+        Some(ProbedLevel {
+            base_url: self.base_url.clone(),
+            extension: self.extension.clone(),
+            query: self.query.clone(),
+            level,
+            tiles_before: self.levels[..level].iter().map(|l| l.area() as u32).sum(),
+            grid,
+            tile_size,
+            size,
+        })
+    }
+}
+
+/// The largest level of a zoomify image found by a `ZoomifyProbe`
+pub struct ProbedLevel {
+    base_url: String,
+    extension: String,
+    query: String,
+    level: usize,
+    /// Number of tiles in the smaller levels, from which the tile groups are numbered
+    tiles_before: u32,
+    grid: Vec2d,
+    tile_size: Vec2d,
+    size: Vec2d,
+}
+
+impl TilesRect for ProbedLevel {
+    fn size(&self) -> Vec2d {
+        self.size
+    }
+
+    fn tile_size(&self) -> Vec2d {
+        self.tile_size
+    }
+
+    fn tile_url(&self, pos: Vec2d) -> String {
+        let group = (self.tiles_before + pos.x + pos.y * self.grid.x) / 256;
+        format!(
+            "{base}/TileGroup{group}/{z}-{x}-{y}.{extension}{query}",
+            base = self.base_url, group = group, z = self.level, x = pos.x, y = pos.y,
+            extension = self.extension, query = self.query
+        )
+    }
+}
+
+impl std::fmt::Debug for ProbedLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Zoomify image probed from the tiles in {}", self.base_url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::dezoomer::{Dezoomer, DezoomerError, DezoomerInput};
+    use crate::zoomify::image_properties::ImageProperties;
+    use crate::zoomify::ZoomifyDezoomer;
+
+    use super::*;
+
+    /// The urls and sizes of all the tiles of an image created by the zoomify converter
+    fn converter_tiles(size: Vec2d) -> HashMap<String, Vec2d> {
+        let props = ImageProperties::new(size, 256);
+        let levels = props.levels();
+        let mut tiles = HashMap::new();
+        for (z, level) in levels.iter().enumerate() {
+            let grid = level.size.ceil_div(level.tile_size);
+            for y in 0..grid.y {
+                for x in 0..grid.x {
+                    let pos = Vec2d { x, y };
+                    let url = format!("http://x.fr/y/TileGroup{}/{}-{}-{}.jpg", level.tile_group(pos), z, x, y);
+                    let start = pos * level.tile_size;
+                    let end = (start + level.tile_size).min(level.size);
+                    tiles.insert(url, end - start);
+                }
+            }
+        }
+        tiles
+    }
+
+    #[test]
+    fn test_from_uri() {
+        let probe = ZoomifyProbe::from_uri("http://x.fr/y/TileGroup3/4-5-6.png?t=1").unwrap();
+        assert_eq!((probe.base_url.as_str(), probe.extension.as_str(), probe.query.as_str()),
+                   ("http://x.fr/y", "png", "?t=1"));
+        let probe = ZoomifyProbe::from_uri("http://x.fr/y/#zoomify").unwrap();
+        assert_eq!(probe.base_url, "http://x.fr/y");
+        let probe = ZoomifyProbe::from_uri("http://x.fr/y/ImageProperties.xml#zoomify").unwrap();
+        assert_eq!(probe.base_url, "http://x.fr/y");
+        assert!(ZoomifyProbe::from_uri("http://x.fr/y/ImageProperties.xml").is_none());
+    }
+
+    /// An image file of the given size
+    fn image_file(size: Vec2d) -> Vec<u8> {
+        let mut bytes = vec![];
+        image::DynamicImage::new_rgb8(size.x, size.y).write_to(&mut bytes, image::ImageOutputFormat::Png).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_probe() {
+        for &size in &[Vec2d { x: 2052, y: 3185 }, Vec2d { x: 12000, y: 9788 }, Vec2d { x: 200, y: 100 }] {
+            let tiles = converter_tiles(size);
+            let mut dezoomer = ZoomifyDezoomer::default();
+            let mut input = DezoomerInput { uri: "http://x.fr/y/#zoomify".into(), contents: None };
+            let mut requests = 0;
+            let mut levels = loop {
+                match dezoomer.zoom_levels(&input) {
+                    Ok(levels) => break levels,
+                    Err(DezoomerError::NeedsTile { uri }) => {
+                        requests += 1;
+                        // Missing tiles are given as empty files
+                        let contents = tiles.get(&uri).map(|&s| image_file(s)).unwrap_or_default();
+                        input = DezoomerInput { uri, contents: Some(contents) };
+                    }
+                    Err(e) => panic!("Unexpected error for {}: {}", size, e),
+                }
+            };
+            assert!(requests < 60, "{} requests for {}", requests, size);
+            assert_eq!(levels.len(), 1);
+            let level = &mut levels[0];
+            assert_eq!(level.size_hint(), Some(size));
+            let tile_refs = level.next_tiles(None);
+            let expected = size.ceil_div(Vec2d::square(256)).area() as usize;
+            assert_eq!(tile_refs.len(), expected);
+            for tile in tile_refs {
+                assert_eq!(tiles.get(&tile.url).map(|&s| tile.position + s).map(|end| end.fits_inside(size)),
+                           Some(true), "wrong tile {:?} for {}", tile, size);
+            }
+        }
+    }
+
+    #[test]
+    fn test_probe_without_tiles() {
+        let mut dezoomer = ZoomifyDezoomer::default();
+        let input = DezoomerInput { uri: "http://x.fr/y/TileGroup0/0-0-0.jpg".into(), contents: Some(vec![]) };
+        assert!(dezoomer.zoom_levels(&input).is_err());
+    }
+}
@@ -0,0 +1,180 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use image::GenericImageView;
+
+use dezoomify_rs::{DezoomifyBuilder, ProgressObserver, Vec2d, ZoomError};
+
+#[derive(Default)]
+struct TileCounter {
+    downloaded: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl ProgressObserver for TileCounter {
+    fn tile_downloaded(&self, _url: &str, _position: Vec2d) {
+        self.downloaded.fetch_add(1, Ordering::SeqCst);
+    }
+    fn tile_failed(&self, _url: &str, _position: Vec2d, _error: &ZoomError) {
+        self.failed.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Download an image in memory, without touching the terminal
+#[tokio::test(threaded_scheduler)]
+pub async fn builder_in_memory() {
+    let counter = Arc::new(TileCounter::default());
+    let image = DezoomifyBuilder::new("testdata/generic/map_{{X}}_{{Y}}.jpg")
+        .retries(0, Duration::from_secs(0))
+        .observer(Arc::clone(&counter))
+        .download_image()
+        .await
+        .expect("Downloading the image in memory failed");
+    let expected = image::open("testdata/generic/map_expected.png").unwrap();
+    assert_eq!(image.dimensions(), expected.dimensions());
+    assert!(counter.downloaded.load(Ordering::SeqCst) >= 4);
+    // The generic dezoomer finds the size of the image by requesting tiles that do not exist
+    assert!(counter.failed.load(Ordering::SeqCst) > 0);
+}
+
+#[tokio::test(threaded_scheduler)]
+pub async fn builder_level_selection() {
+    let result = DezoomifyBuilder::new("testdata/generic/map_{{X}}_{{Y}}.jpg")
+        .select_level(|_levels| None)
+        .download_image()
+        .await;
+    assert_eq!(result.err().map(|e| e.kind()), Some("NoLevelSelected"));
+}
+
+/// Find the metadata request in a HAR file, and send it again
+#[tokio::test(threaded_scheduler)]
+pub async fn har_replay() {
+    let sizes = Arc::new(std::sync::Mutex::new(vec![]));
+    let found_sizes = Arc::clone(&sizes);
+    let result = DezoomifyBuilder::new("testdata/har/zoomify.har")
+        .select_level(move |levels| {
+            found_sizes.lock().unwrap().extend(levels.iter().filter_map(|l| l.size));
+            None
+        })
+        .download_image()
+        .await;
+    assert_eq!(result.err().map(|e| e.kind()), Some("NoLevelSelected"));
+    assert!(sizes.lock().unwrap().contains(&Vec2d { x: 1702, y: 2052 }));
+}
+
+/// Find the image in the html page of its viewer
+#[tokio::test(threaded_scheduler)]
+pub async fn page_viewer() {
+    let sizes = Arc::new(std::sync::Mutex::new(vec![]));
+    let found_sizes = Arc::clone(&sizes);
+    let result = DezoomifyBuilder::new("testdata/page/viewer.html")
+        .select_level(move |levels| {
+            found_sizes.lock().unwrap().extend(levels.iter().filter_map(|l| l.size));
+            None
+        })
+        .download_image()
+        .await;
+    assert_eq!(result.err().map(|e| e.kind()), Some("NoLevelSelected"));
+    assert!(sizes.lock().unwrap().contains(&Vec2d { x: 1702, y: 2052 }));
+}
+
+/// Writes a pff file containing the tiles of the given image, without any zoomify servlet
+fn write_pff(path: &std::path::Path, image: &image::DynamicImage, tile_size: u32) {
+    let (width, height) = image.dimensions();
+    let mut tiles = vec![];
+    for y in (0..height).step_by(tile_size as usize) {
+        for x in (0..width).step_by(tile_size as usize) {
+            let tile = image.crop_imm(x, y, tile_size.min(width - x), tile_size.min(height - y));
+            let mut bytes = vec![];
+            tile.write_to(&mut bytes, image::ImageOutputFormat::Png).unwrap();
+            tiles.push(bytes);
+        }
+    }
+    let jfif_headers = vec![0xFF; 16];
+    let header = format!(
+        r#"<PFFHEADER WIDTH="{}" HEIGHT="{}" NUMTILES="{}" NUMIMAGES="1" HEADERSIZE="{}" VERSION="106" TILESIZE="{}" />"#,
+        width, height, tiles.len(), jfif_headers.len(), tile_size
+    );
+    let mut file = vec![0u8; 0x424];
+    file[0x24..0x24 + header.len()].copy_from_slice(header.as_bytes());
+    file.extend(&jfif_headers);
+    let mut end = (file.len() + 8 * tiles.len()) as u64;
+    for tile in &tiles {
+        end += tile.len() as u64;
+        file.extend(&end.to_be_bytes());
+    }
+    for tile in &tiles {
+        file.extend(tile);
+    }
+    std::fs::write(path, file).unwrap();
+}
+
+/// Read the tiles of a pff file directly, with byte ranges
+#[tokio::test(threaded_scheduler)]
+pub async fn pff_file() {
+    let expected = image::DynamicImage::ImageRgb8(image::ImageBuffer::from_fn(512, 300, |x, y| {
+        image::Rgb([x as u8, y as u8, (x / 2 + y) as u8])
+    }));
+    let path = std::env::temp_dir().join(format!("dezoomify-rs-test-{}.pff", std::process::id()));
+    write_pff(&path, &expected, 256);
+    let image = DezoomifyBuilder::new(path.to_string_lossy())
+        .download_image()
+        .await
+        .expect("Reading the pff file failed");
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(image.dimensions(), (512, 300));
+    assert_eq!(image.to_rgb().into_raw(), expected.to_rgb().into_raw());
+}
+
+/// The image is still returned when some of its tiles are missing
+#[tokio::test(threaded_scheduler)]
+pub async fn builder_partial_image() {
+    let dzi_path = std::env::temp_dir().join("dezoomify-rs-builder-partial.dzi");
+    let files_dir = dzi_path.with_file_name("dezoomify-rs-builder-partial_files");
+    let _ = std::fs::remove_file(&dzi_path);
+    let _ = std::fs::remove_dir_all(&files_dir);
+    DezoomifyBuilder::new("testdata/generic/map_{{X}}_{{Y}}.jpg")
+        .retries(0, Duration::from_secs(0))
+        .save_to_file(&dzi_path)
+        .await
+        .expect("Creating the dzi failed");
+    let largest_level = std::fs::read_dir(&files_dir).unwrap()
+        .filter_map(|e| e.unwrap().file_name().to_str()?.parse::<u32>().ok())
+        .max().unwrap();
+    std::fs::remove_file(files_dir.join(largest_level.to_string()).join("0_0.jpg")).unwrap();
+
+    let builder = DezoomifyBuilder::new(dzi_path.to_str().unwrap())
+        .largest()
+        .retries(0, Duration::from_secs(0));
+    let result = builder.download_image().await;
+    assert_eq!(result.err().map(|e| e.kind()), Some("PartialDownload"));
+    let (image, err) = builder.download_partial_image().await.expect("The partial image should be returned");
+    let expected = image::open("testdata/generic/map_expected.png").unwrap();
+    assert_eq!(image.dimensions(), expected.dimensions());
+    assert_eq!(err.map(|e| e.kind()), Some("PartialDownload"));
+}
+
+/// The tiles requested to find the levels of a zoomify image without ImageProperties.xml
+/// are not reported as failed tiles
+#[tokio::test(threaded_scheduler)]
+pub async fn zoomify_probe() {
+    let out_dir = std::env::temp_dir().join("dezoomify-rs-probe.zoomify");
+    let _ = std::fs::remove_dir_all(&out_dir);
+    DezoomifyBuilder::new("testdata/generic/map_{{X}}_{{Y}}.jpg")
+        .retries(0, Duration::from_secs(0))
+        .save_to_file(&out_dir)
+        .await
+        .expect("Creating the zoomify tiles failed");
+    std::fs::remove_file(out_dir.join("ImageProperties.xml")).unwrap();
+
+    let counter = Arc::new(TileCounter::default());
+    let image = DezoomifyBuilder::new(format!("{}/#zoomify", out_dir.to_str().unwrap()))
+        .observer(Arc::clone(&counter))
+        .download_image()
+        .await
+        .expect("Probing the zoomify image failed");
+    let expected = image::open("testdata/generic/map_expected.png").unwrap();
+    assert_eq!(image.dimensions(), expected.dimensions());
+    assert_eq!(counter.failed.load(Ordering::SeqCst), 0);
+}
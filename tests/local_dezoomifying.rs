@@ -26,6 +26,121 @@ pub async fn local_generic_tiles() {
     ).await.unwrap()
 }
 
+/// Read the jpeg tiles of a pff file from disk
+#[tokio::test(threaded_scheduler)]
+pub async fn local_pff_file() {
+    test_image(
+        "testdata/pff/map.pff",
+        "testdata/generic/map_expected.png",
+    ).await.unwrap()
+}
+
+/// Re-tile an image in the deep zoom format, and read it back with the deepzoom dezoomer
+#[tokio::test(threaded_scheduler)]
+pub async fn dzi_round_trip() {
+    let dzi_path = std::env::temp_dir().join("dezoomify-rs-round-trip.dzi");
+    let _ = std::fs::remove_file(&dzi_path);
+    let _ = std::fs::remove_dir_all(dzi_path.with_file_name("dezoomify-rs-round-trip_files"));
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some("testdata/generic/map_{{X}}_{{Y}}.jpg".into());
+    args.retries = 0;
+    args.logging = "error".into();
+    args.tile_size = 64;
+    args.tile_overlap = 2;
+    args.outfile = Some(dzi_path.clone());
+    dezoomify(&args).await.expect("Creating the dzi failed");
+    test_image(
+        dzi_path.to_str().unwrap(),
+        "testdata/generic/map_expected.png",
+    ).await.unwrap()
+}
+
+/// Re-tile an image in the zoomify format, and read it back with the zoomify dezoomer
+#[tokio::test(threaded_scheduler)]
+pub async fn zoomify_round_trip() {
+    let out_dir = std::env::temp_dir().join("dezoomify-rs-round-trip.zoomify");
+    let _ = std::fs::remove_dir_all(&out_dir);
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some("testdata/generic/map_{{X}}_{{Y}}.jpg".into());
+    args.retries = 0;
+    args.logging = "error".into();
+    args.outfile = Some(out_dir.clone());
+    dezoomify(&args).await.expect("Creating the zoomify tiles failed");
+    test_image(
+        out_dir.join("ImageProperties.xml").to_str().unwrap(),
+        "testdata/generic/map_expected.png",
+    ).await.unwrap()
+}
+
+/// When a tile is missing, the image is still created, and the error tells where
+#[tokio::test(threaded_scheduler)]
+pub async fn local_partial_download() {
+    let dzi_path = std::env::temp_dir().join("dezoomify-rs-partial.dzi");
+    let files_dir = dzi_path.with_file_name("dezoomify-rs-partial_files");
+    let _ = std::fs::remove_file(&dzi_path);
+    let _ = std::fs::remove_dir_all(&files_dir);
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some("testdata/generic/map_{{X}}_{{Y}}.jpg".into());
+    args.retries = 0;
+    args.logging = "error".into();
+    args.outfile = Some(dzi_path.clone());
+    dezoomify(&args).await.expect("Creating the dzi failed");
+    let largest_level = std::fs::read_dir(&files_dir).unwrap()
+        .filter_map(|e| e.unwrap().file_name().to_str()?.parse::<u32>().ok())
+        .max().unwrap();
+    std::fs::remove_file(files_dir.join(largest_level.to_string()).join("0_0.jpg")).unwrap();
+
+    let tmp_file = TmpFile("testdata/generic/map_partial.png");
+    let _ = std::fs::remove_file(tmp_file.to_path_buf());
+    args.input_uri = Some(dzi_path.to_str().unwrap().into());
+    args.largest = true;
+    args.outfile = Some(tmp_file.to_path_buf());
+    match dezoomify(&args).await {
+        Err(ZoomError::PartialDownload { destination, size, .. }) => {
+            let expected = image::open("testdata/generic/map_expected.png").unwrap();
+            let destination = destination.expect("the partial image should have been saved");
+            assert_eq!(std::fs::canonicalize(&destination).unwrap(),
+                       std::fs::canonicalize(tmp_file.to_path_buf()).unwrap());
+            assert_eq!(size.map(|s| (s.x, s.y)), Some(expected.dimensions()));
+            assert_eq!(image::open(&destination).unwrap().dimensions(), expected.dimensions());
+        }
+        other => panic!("Expected a partial download, got {:?}", other),
+    }
+}
+
+/// Download only a part of an image
+#[tokio::test(threaded_scheduler)]
+pub async fn local_generic_region() {
+    let tmp_file = TmpFile("testdata/generic/map_region.png");
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some("testdata/generic/map_{{X}}_{{Y}}.jpg".into());
+    args.retries = 0;
+    args.logging = "error".into();
+    args.region = Some("100,200,300,50".parse().unwrap());
+    args.outfile = Some(tmp_file.to_path_buf());
+    dezoomify(&args).await.expect("Dezooming a region failed");
+    let actual = image::open(tmp_file.to_path_buf()).unwrap();
+    let expected = image::open("testdata/generic/map_expected.png").unwrap().crop_imm(100, 200, 300, 50);
+    assert_eq!(actual.dimensions(), (300, 50));
+    assert_eq!(actual.to_rgb().into_raw(), expected.to_rgb().into_raw());
+}
+
+/// A region in percent needs the size of the image, which the generic dezoomer does not know in advance
+#[tokio::test(threaded_scheduler)]
+pub async fn local_generic_region_needs_size() {
+    let tmp_file = TmpFile("testdata/generic/map_region_percent.png");
+    let _ = std::fs::remove_file(tmp_file.to_path_buf());
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some("testdata/generic/map_{{X}}_{{Y}}.jpg".into());
+    args.retries = 0;
+    args.logging = "error".into();
+    args.region = Some("10%,10%,50%,50%".parse().unwrap());
+    args.outfile = Some(tmp_file.to_path_buf());
+    let err = dezoomify(&args).await.expect_err("the region cannot be resolved");
+    assert_eq!(err.kind(), "RegionNeedsSize");
+    assert!(!tmp_file.to_path_buf().exists(), "no output file should be created");
+}
+
 pub async fn dezoom_image<'a>(input: &str, expected: &'a str) -> Result<TmpFile<'a>, ZoomError> {
     let mut args: Arguments = Default::default();
     args.input_uri = Some(input.into());